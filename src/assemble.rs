@@ -1,19 +1,215 @@
 use std::str;
-use std::collections::BTreeMap;
+use std::fmt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
 
 use nom::*;
 use byteorder::*;
 
 use data::*;
 use bitcode;
+use device::DeviceModel;
+use symbols::{SymbolTable, SourceLine};
+use disassemble::{mnemonic, register_name, uses_register, uses_operand};
+use object::{self, Object, Image, Relocation, RelocationKind, RelocationTarget};
 
 type Label = String;
 
+/// Code goes here unless there's a `.section` directive
+pub const DEFAULT_SECTION: &'static str = "text";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    /// The line couldn't be parsed
+    Syntax,
+    /// Not an instruction, and not a macro either
+    UnknownMnemonic(String),
+    BadRegister(String),
+    UnknownLabel(String),
+    /// A name was used where only constants can go, e.g. in `.equ`
+    NotConstant(String),
+    /// A number that doesn't fit in 32 bits
+    ConstantOutOfRange(String),
+    /// A label, constant or macro was defined twice
+    Duplicate(String),
+    /// A file couldn't be read
+    Io,
+    /// Everything assembled, but couldn't be linked, e.g. because of an unresolved import
+    Link,
+    /// Anything else that parses but doesn't make sense
+    Invalid,
+    /// Not an error, just a warning about something that's probably a mistake
+    Lint(Lint),
+}
+
+/// Warnings for code that assembles fine, but probably doesn't do what was meant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Lint {
+    /// `divmod` and `divmods` with a register other than C or D, which is only the dividend:
+    /// the results always go in C and D
+    DivMod,
+    /// A register given to an instruction that doesn't use one, e.g. `branch a [x]`
+    IgnoredRegister,
+    /// An operand given to an instruction that doesn't use one, e.g. `push a [1]`
+    IgnoredOperand,
+    /// A `trace` left in the code
+    Trace,
+    /// Code straight after a `halt`, `branch`, `ret` or `intexit`, with no label to get to it.
+    /// A `branch` straight after a `halt` is fine, because that's how to wait for an interrupt.
+    Unreachable,
+    /// A label that nothing refers to or exports
+    UnusedLabel,
+}
+
+impl Lint {
+    pub fn all() -> &'static [Lint] {
+        static ALL: [Lint; 6] = [
+            Lint::DivMod,
+            Lint::IgnoredRegister,
+            Lint::IgnoredOperand,
+            Lint::Trace,
+            Lint::Unreachable,
+            Lint::UnusedLabel,
+        ];
+
+        &ALL
+    }
+
+    /// What it's called on the command line, e.g. to allow it
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::DivMod          => "divmod",
+            Lint::IgnoredRegister => "ignored-register",
+            Lint::IgnoredOperand  => "ignored-operand",
+            Lint::Trace           => "trace",
+            Lint::Unreachable     => "unreachable",
+            Lint::UnusedLabel     => "unused-label",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::all().iter().cloned().find(|lint| lint.name() == name)
+    }
+}
+
+/// Something wrong with the code being assembled, and where it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub kind: AsmErrorKind,
+    pub message: String,
+    pub source: Option<SourceLine>,
+    /// Counting from 1
+    pub column: Option<u32>,
+    /// The line the error is on
+    pub snippet: Option<String>,
+}
+
+impl AsmError {
+    fn new<S: Into<String>>(kind: AsmErrorKind, message: S) -> AsmError {
+        AsmError {
+            kind: kind,
+            message: message.into(),
+            source: None,
+            column: None,
+            snippet: None,
+        }
+    }
+
+    /// Sets where the error is, unless that's already known
+    fn at(mut self, source: &SourceLine) -> AsmError {
+        if self.source.is_none() {
+            self.source = Some(source.clone());
+        }
+        self
+    }
+
+    /// The name the error is about, which is probably where to point the caret
+    fn subject(&self) -> Option<&str> {
+        match self.kind {
+            AsmErrorKind::UnknownMnemonic(ref name) |
+            AsmErrorKind::BadRegister(ref name) |
+            AsmErrorKind::UnknownLabel(ref name) |
+            AsmErrorKind::NotConstant(ref name) |
+            AsmErrorKind::ConstantOutOfRange(ref name) |
+            AsmErrorKind::Duplicate(ref name) => Some(name),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.source, self.column) {
+            (&Some(SourceLine { file: Some(ref file), line }), Some(column)) =>
+                write!(f, "{}:{}:{}: ", file, line, column)?,
+            (&Some(ref source), Some(column)) =>
+                write!(f, "{}, column {}: ", source, column)?,
+            (&Some(ref source), None) =>
+                write!(f, "{}: ", source)?,
+            (&None, _) => ()
+        }
+
+        match self.kind {
+            AsmErrorKind::Lint(lint) => write!(f, "warning: {} [{}]", self.message, lint.name())?,
+            _ => write!(f, "{}", self.message)?
+        }
+
+        if let Some(ref snippet) = self.snippet {
+            write!(f, "\n    {}", snippet)?;
+
+            if let Some(column) = self.column {
+                // Tabs stay tabs, so that the caret lines up
+                let indent: String = snippet.chars().take(column as usize - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+
+                write!(f, "\n    {}^", indent)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Every error found in one run of the assembler, in source order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmErrors(pub Vec<AsmError>);
+
+impl fmt::Display for AsmErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, error) in self.0.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl From<AsmError> for AsmErrors {
+    fn from(error: AsmError) -> AsmErrors {
+        AsmErrors(vec![error])
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum AsmBlock {
     Instruction(AsmInstruction),
-    Words(Vec<u32>),
+    Words(Vec<AsmWord>),
     Bytes(AsmEndianness, Vec<u8>),
+    Include(String),
+    Section(String),
+    Export(Vec<Label>),
+    Import(Vec<Label>),
+    Macro(Label, Vec<Label>, String),
+    MacroCall(Label, Vec<String>),
+    Equ(Label, Expr),
+    Org(Expr),
 }
 
 impl AsmBlock {
@@ -22,7 +218,7 @@ impl AsmBlock {
             AsmBlock::Instruction(AsmInstruction(_, _, ref op)) => {
                 match *op {
                     Some(AsmOperand::Reg(_))      => 1,
-                    Some(AsmOperand::Const(0))    => 1,
+                    Some(AsmOperand::Const(Expr::Num(0))) => 1,
                     None                          => 1, // same as Const(0)
 
                     Some(AsmOperand::Const(_))    => 2,
@@ -34,104 +230,1338 @@ impl AsmBlock {
             AsmBlock::Bytes(_, ref vec) => {
                 let len = vec.len() as u32;
                 (len / 4) + (if len % 4 > 0 { 1 } else { 0 })
+            },
+            AsmBlock::Include(_) |
+            AsmBlock::Section(_) |
+            AsmBlock::Export(_) |
+            AsmBlock::Import(_) |
+            AsmBlock::Macro(_, _, _) |
+            AsmBlock::MacroCall(_, _) |
+            AsmBlock::Equ(_, _) |
+            AsmBlock::Org(_) => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AsmEndianness {
+    Big,
+    Little,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AsmInstruction(Function, Option<Register>, Option<AsmOperand>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AsmOperand {
+    Reg(Register),
+    Const(Expr),
+    Relative(Expr),
+    Label(Label, Expr)
+}
+
+/// A word in `.words`. Labels are absolute addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AsmWord {
+    Const(Expr),
+    Label(Label, Expr),
+}
+
+/// An expression. Names refer to constants defined with `.equ`, which are replaced by their
+/// values as soon as the expression has been parsed, so they have to be defined first. Any other
+/// name is a label, which is the absolute address of the label once it's been linked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Num(u32),
+    Name(Label),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Pow,
+    Div,
+    And,
+    Or,
+    Xor,
+    Lsh,
+    Rsh,
+}
+
+impl BinaryOp {
+    fn apply(self, a: u32, b: u32) -> Result<u32, AsmError> {
+        Ok(match self {
+            BinaryOp::Add => a.wrapping_add(b),
+            BinaryOp::Sub => a.wrapping_sub(b),
+            BinaryOp::Mul => a.wrapping_mul(b),
+            BinaryOp::Pow => a.wrapping_pow(b),
+            BinaryOp::Div => a.checked_div(b)
+                .ok_or_else(|| AsmError::new(AsmErrorKind::Invalid, "division by zero"))?,
+            BinaryOp::And => a & b,
+            BinaryOp::Or  => a | b,
+            BinaryOp::Xor => a ^ b,
+            BinaryOp::Lsh => a.checked_shl(b).unwrap_or(0),
+            BinaryOp::Rsh => a.checked_shr(b).unwrap_or(0),
+        })
+    }
+}
+
+/// What an expression with labels in it works out to: `constant`, plus the address of each
+/// target times some factor. Only a constant, or one target plus a constant, can be relocated.
+#[derive(Debug, Clone)]
+struct Linear {
+    constant: u32,
+    terms: Vec<(RelocationTarget, u32)>,
+}
+
+impl Linear {
+    /// `target` says where a label is: in which section or import, and at what offset from it
+    fn from_expr<F>(expr: &Expr, target: &F) -> Result<Linear, AsmError>
+        where F: Fn(&str) -> Result<(RelocationTarget, u32), AsmError> {
+
+        let constant = |n| Linear { constant: n, terms: vec![] };
+
+        match *expr {
+            Expr::Num(n) => Ok(constant(n)),
+            Expr::Name(ref label) => {
+                let (target, offset) = target(label)?;
+
+                Ok(Linear { constant: offset, terms: vec![(target, 1)] })
+            },
+            Expr::Not(ref a) => Ok(constant(!Linear::from_expr(a, target)?.to_constant()?)),
+            Expr::Binary(op, ref a, ref b) => {
+                let a = Linear::from_expr(a, target)?;
+                let b = Linear::from_expr(b, target)?;
+
+                match op {
+                    BinaryOp::Add => Ok(a.plus(b, 1)),
+                    BinaryOp::Sub => Ok(a.plus(b, 1u32.wrapping_neg())),
+                    BinaryOp::Mul if a.terms.is_empty() => Ok(b.times(a.constant)),
+                    BinaryOp::Mul if b.terms.is_empty() => Ok(a.times(b.constant)),
+                    _ => Ok(constant(op.apply(a.to_constant()?, b.to_constant()?)?))
+                }
+            }
+        }
+    }
+
+    /// `self + other * factor`
+    fn plus(mut self, other: Linear, factor: u32) -> Linear {
+        self.constant = self.constant.wrapping_add(other.constant.wrapping_mul(factor));
+
+        for (target, k) in other.terms {
+            let k = k.wrapping_mul(factor);
+
+            match self.terms.iter().position(|&(ref t, _)| *t == target) {
+                Some(index) => self.terms[index].1 = self.terms[index].1.wrapping_add(k),
+                None => self.terms.push((target, k))
+            }
+        }
+
+        // Differences between labels in the same section are just constants
+        self.terms.retain(|&(_, k)| k != 0);
+
+        self
+    }
+
+    fn times(mut self, factor: u32) -> Linear {
+        self.constant = self.constant.wrapping_mul(factor);
+
+        for term in &mut self.terms {
+            term.1 = term.1.wrapping_mul(factor);
+        }
+
+        self.terms.retain(|&(_, k)| k != 0);
+
+        self
+    }
+
+    fn to_constant(&self) -> Result<u32, AsmError> {
+        if self.terms.is_empty() {
+            Ok(self.constant)
+        } else {
+            Err(AsmError::new(AsmErrorKind::Invalid,
+                              "labels can only be added, subtracted or multiplied by a constant"))
+        }
+    }
+
+    /// The value to put in the word at `offset` in `section`, adding a relocation if needed
+    fn to_word(&self, offset: u32, section: &mut object::Section) -> Result<u32, AsmError> {
+        match self.terms.len() {
+            0 => Ok(self.constant),
+            1 if self.terms[0].1 == 1 => {
+                section.relocations.push(Relocation {
+                    offset: offset,
+                    kind: RelocationKind::Absolute,
+                    target: self.terms[0].0.clone(),
+                    addend: self.constant as i32,
+                });
+
+                Ok(0)
+            },
+            _ => Err(AsmError::new(AsmErrorKind::Invalid,
+                                   "expression isn't a constant or a single address"))
+        }
+    }
+}
+
+impl Expr {
+    fn negate(self) -> Expr {
+        match self {
+            Expr::Num(n) => Expr::Num(n.wrapping_neg()),
+            e => Expr::Binary(BinaryOp::Sub, Box::new(Expr::Num(0)), Box::new(e))
+        }
+    }
+
+    fn eval(&self, constants: &BTreeMap<Label, Constant>) -> Result<u32, AsmError> {
+        match *self {
+            Expr::Num(n) => Ok(n),
+            Expr::Name(ref name) => constants.get(name).map(|c| c.value)
+                .ok_or_else(|| AsmError::new(AsmErrorKind::NotConstant(name.clone()),
+                                             format!("{} isn't a constant", name))),
+            Expr::Not(ref a) => Ok(!a.eval(constants)?),
+            Expr::Binary(op, ref a, ref b) => op.apply(a.eval(constants)?, b.eval(constants)?),
+        }
+    }
+
+    /// Replaces constants with their values, and works out whatever doesn't involve labels
+    fn substitute(&self, constants: &BTreeMap<Label, Constant>) -> Result<Expr, AsmError> {
+        Ok(match *self {
+            Expr::Num(n) => Expr::Num(n),
+            Expr::Name(ref name) => match constants.get(name) {
+                Some(constant) => Expr::Num(constant.value),
+                None => Expr::Name(name.clone())
+            },
+            Expr::Not(ref a) => match a.substitute(constants)? {
+                Expr::Num(n) => Expr::Num(!n),
+                a => Expr::Not(Box::new(a))
+            },
+            Expr::Binary(op, ref a, ref b) => {
+                match (a.substitute(constants)?, b.substitute(constants)?) {
+                    (Expr::Num(a), Expr::Num(b)) => Expr::Num(op.apply(a, b)?),
+                    (a, b) => Expr::Binary(op, Box::new(a), Box::new(b))
+                }
+            }
+        })
+    }
+
+    /// Adds every name in the expression to `names`
+    fn names<'a>(&'a self, names: &mut BTreeSet<&'a str>) {
+        match *self {
+            Expr::Num(_) => (),
+            Expr::Name(ref name) => { names.insert(name); },
+            Expr::Not(ref a) => a.names(names),
+            Expr::Binary(_, ref a, ref b) => {
+                a.names(names);
+                b.names(names);
+            }
+        }
+    }
+
+    fn as_num(&self) -> Option<u32> {
+        match *self {
+            Expr::Num(n) => Some(n),
+            _ => None
+        }
+    }
+
+    /// The value of an expression that has already been resolved
+    fn value(&self) -> u32 {
+        match *self {
+            Expr::Num(n) => n,
+            _ => panic!("Bug check: {:?} should have been resolved by parse_source", self)
+        }
+    }
+}
+
+/// Blocks, with the source line each one starts on, under the label before them. Chunks
+/// without a label are just a continuation of the one before.
+#[derive(Debug, Clone)]
+struct Chunk {
+    label: Label,
+    source: SourceLine,
+    section: String,
+    blocks: Vec<(SourceLine, AsmBlock)>,
+}
+
+/// How deep `.include`s and macros can nest before we assume they're recursive
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+/// Defined with `.macro NAME param, param` and ended with `.endm`.
+///
+/// When a macro is used, `\param` in the body is replaced by the argument given for it, and
+/// `\@` by a number that's different every time, for labels that are local to each use.
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<Label>,
+    body: String,
+    source: SourceLine,
+    builtin: bool,
+}
+
+/// Defined with `.equ NAME expr` (or `.define`)
+#[derive(Debug, Clone)]
+struct Constant {
+    value: u32,
+    source: SourceLine,
+    builtin: bool,
+}
+
+/// Pseudo-instructions that are always available. They can be redefined.
+static BUILTIN_MACROS: &'static [u8] = br#"
+.macro jmpz reg, target
+    cmp \reg [0]
+    branche [\target]
+.endm
+
+.macro jmpnz reg, target
+    cmp \reg [0]
+    branchne [\target]
+.endm
+
+.macro pushall
+    push a
+    push b
+    push c
+    push d
+.endm
+
+.macro popall
+    pop d
+    pop c
+    pop b
+    pop a
+.endm
+"#;
+
+/// Everything that has been parsed so far
+struct Assembly {
+    chunks: Vec<Chunk>,
+    macros: BTreeMap<Label, Macro>,
+    expansions: u32,
+    constants: BTreeMap<Label, Constant>,
+    /// Files that were parsed, for showing where errors are
+    sources: Vec<(Option<String>, Vec<u8>)>,
+    errors: Vec<AsmError>,
+}
+
+impl Assembly {
+    fn new() -> Assembly {
+        let mut asm = Assembly {
+            chunks: vec![],
+            macros: BTreeMap::new(),
+            expansions: 0,
+            constants: BTreeMap::new(),
+            sources: vec![],
+            errors: vec![],
+        };
+
+        parse_source(BUILTIN_MACROS, None, 0, None, &mut asm);
+
+        assert!(asm.errors.is_empty(), "Bug check: built-in macros don't parse: {:?}", asm.errors);
+
+        asm.chunks.clear();
+        asm.sources.clear();
+
+        for mac in asm.macros.values_mut() {
+            mac.builtin = true;
+        }
+
+        // The prelude: device models, so that they don't need to be kept in sync by hand
+        for &model in DeviceModel::all() {
+            let mut constant = |name: String, value: u32| {
+                asm.constants.insert(name, Constant {
+                    value: value,
+                    source: SourceLine::new(Some("<prelude>".into()), 0),
+                    builtin: true,
+                });
+            };
+
+            constant(format!("DeviceModel.{:?}", model), model.number());
+
+            if let Some(size) = model.memory_size() {
+                constant(format!("DeviceModel.{:?}.memory_size", model), size);
+            }
+        }
+
+        asm
+    }
+
+    /// Turns everything that was parsed into an object, or returns every error found
+    fn finish(mut self, name: &str) -> Result<Object, AsmErrors> {
+        let result = chunks_to_object(&self.chunks, name);
+
+        let object = match result {
+            Ok(object) => Some(object),
+            Err(errors) => {
+                self.errors.extend(errors);
+                None
+            }
+        };
+
+        if self.errors.is_empty() {
+            return Ok(object.unwrap());
+        }
+
+        let errors = self.errors.clone();
+
+        Err(AsmErrors(self.report(errors)))
+    }
+
+    /// Locates errors or warnings, in the order they appear in the code as far as possible
+    fn report(&self, errors: Vec<AsmError>) -> Vec<AsmError> {
+        let mut errors: Vec<AsmError> = errors.into_iter().map(|e| self.locate(e)).collect();
+
+        errors.sort_by_key(|e| e.source.as_ref().map(|source| {
+            let file = self.sources.iter().position(|&(ref file, _)| *file == source.file);
+
+            (file, source.line)
+        }));
+
+        errors
+    }
+
+    /// Fills in the line of code that an error is on, and a column if it doesn't have one
+    fn locate(&self, mut error: AsmError) -> AsmError {
+        let line = {
+            let source = match error.source {
+                Some(ref source) => source,
+                None => return error
+            };
+
+            let code = match self.sources.iter().find(|&&(ref file, _)| *file == source.file) {
+                Some(&(_, ref code)) => code,
+                None => return error
+            };
+
+            match code.split(|&c| c == b'\n').nth(source.line as usize - 1) {
+                Some(line) => String::from_utf8_lossy(line).trim_end().to_owned(),
+                None => return error
+            }
+        };
+
+        if error.column.is_none() {
+            let column = error.subject().and_then(|subject| find_word(&line, subject))
+                .or_else(|| line.find(|c: char| !c.is_whitespace()));
+
+            error.column = column.map(|column| column as u32 + 1);
+        }
+
+        error.snippet = Some(line);
+        error
+    }
+
+    fn define_constant(&mut self, name: Label, expr: &Expr, source: SourceLine)
+        -> Result<(), AsmError> {
+
+        if let Some(existing) = self.constants.get(&name) {
+            if !existing.builtin {
+                return Err(AsmError::new(AsmErrorKind::Duplicate(name.clone()),
+                    format!("constant {} is already defined at {}", name, existing.source))
+                    .at(&source));
             }
         }
+
+        if let Some(chunk) = self.chunks.iter().find(|chunk| chunk.label == name) {
+            return Err(AsmError::new(AsmErrorKind::Duplicate(name.clone()),
+                format!("can't define constant {}, it's a label at {}", name, chunk.source))
+                .at(&source));
+        }
+
+        let value = expr.eval(&self.constants).map_err(|e| e.at(&source))?;
+
+        self.constants.insert(name, Constant {
+            value: value,
+            source: source,
+            builtin: false,
+        });
+
+        Ok(())
+    }
+
+    /// Replaces constants in `block` with their values. Whatever is left are labels.
+    ///
+    /// A label on its own, or plus a constant, is relative to the instruction it's in. That
+    /// works out to the same address at runtime, but doesn't need relocating.
+    fn resolve(&self, block: AsmBlock, source: &SourceLine) -> Result<AsmBlock, AsmError> {
+        let eval = |expr: Expr| -> Result<Expr, AsmError> {
+            expr.eval(&self.constants)
+                .map(Expr::Num)
+                .map_err(|e| e.at(source))
+        };
+
+        let substitute = |expr: Expr| -> Result<Expr, AsmError> {
+            expr.substitute(&self.constants).map_err(|e| e.at(source))
+        };
+
+        let label = |label: Label, offset: Expr|
+            -> Result<Result<(Label, Expr), Expr>, AsmError> {
+
+            let offset = substitute(offset)?;
+
+            Ok(match (self.constants.get(&label), offset) {
+                (None, Expr::Num(offset)) => Ok((label, Expr::Num(offset))),
+                (Some(constant), offset) => Err(substitute(
+                    Expr::Binary(BinaryOp::Add, Box::new(Expr::Num(constant.value)),
+                                 Box::new(offset)))?),
+                (None, offset) => Err(
+                    Expr::Binary(BinaryOp::Add, Box::new(Expr::Name(label)), Box::new(offset))),
+            })
+        };
+
+        Ok(match block {
+            AsmBlock::Instruction(AsmInstruction(f, r, op)) => {
+                let op = match op {
+                    Some(AsmOperand::Const(c)) => Some(AsmOperand::Const(substitute(c)?)),
+                    Some(AsmOperand::Relative(c)) => Some(AsmOperand::Relative(eval(c)?)),
+                    Some(AsmOperand::Label(l, offset)) => match label(l, offset)? {
+                        Ok((l, offset)) => Some(AsmOperand::Label(l, offset)),
+                        Err(c) => Some(AsmOperand::Const(c))
+                    },
+                    op => op
+                };
+
+                AsmBlock::Instruction(AsmInstruction(f, r, op))
+            },
+            AsmBlock::Words(words) => {
+                let mut resolved = vec![];
+
+                for word in words {
+                    resolved.push(match word {
+                        AsmWord::Const(c) => AsmWord::Const(substitute(c)?),
+                        AsmWord::Label(l, offset) => match label(l, offset)? {
+                            Ok((l, offset)) => AsmWord::Label(l, offset),
+                            Err(c) => AsmWord::Const(c)
+                        }
+                    });
+                }
+
+                AsmBlock::Words(resolved)
+            },
+            AsmBlock::Org(addr) => AsmBlock::Org(eval(addr)?),
+            block => block
+        })
+    }
+
+    fn define(&mut self, name: Label, params: Vec<Label>, body: String, source: SourceLine)
+        -> Result<(), AsmError> {
+
+        if let Some(existing) = self.macros.get(&name) {
+            if !existing.builtin {
+                return Err(AsmError::new(AsmErrorKind::Duplicate(name.clone()),
+                    format!("macro {} is already defined at {}", name, existing.source))
+                    .at(&source));
+            }
+        }
+
+        self.macros.insert(name, Macro {
+            params: params,
+            body: body,
+            source: source,
+            builtin: false,
+        });
+
+        Ok(())
+    }
+
+    /// The source code that a use of a macro turns into
+    fn expand(&mut self, name: &str, args: &[String], source: &SourceLine)
+        -> Result<String, AsmError> {
+
+        let mac = self.macros.get(name).ok_or_else(|| {
+            AsmError::new(AsmErrorKind::UnknownMnemonic(name.into()),
+                          format!("unknown instruction or macro: {}", name)).at(source)
+        })?;
+
+        if args.len() != mac.params.len() {
+            return Err(AsmError::new(AsmErrorKind::Invalid,
+                format!("macro {} takes {} argument(s), but was given {}",
+                        name, mac.params.len(), args.len())).at(source));
+        }
+
+        self.expansions += 1;
+
+        let mut text = String::new();
+        let mut chars = mac.body.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                text.push(c);
+                continue;
+            }
+
+            if chars.peek() == Some(&'@') {
+                chars.next();
+                text.push_str(&self.expansions.to_string());
+                continue;
+            }
+
+            let mut param = String::new();
+
+            while let Some(&c) = chars.peek() {
+                if c == '_' || c == '.' || c.is_alphanumeric() {
+                    param.push(c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            // Anything that isn't a parameter is left alone, e.g. escapes in strings
+            match mac.params.iter().position(|p| *p == param) {
+                Some(index) => text.push_str(&args[index]),
+                None => {
+                    text.push('\\');
+                    text.push_str(&param);
+                }
+            }
+        }
+
+        Ok(text)
+    }
+}
+
+pub fn assemble(code: &[u8], out: &mut Vec<u32>) -> Result<u32, AsmErrors> {
+    assemble_with_symbols(code, out).map(|(len, _)| len)
+}
+
+/// Like `assemble`, but also returns the offset of every label and a line table.
+///
+/// `.include` paths are relative to the current directory.
+pub fn assemble_with_symbols(code: &[u8], out: &mut Vec<u32>)
+    -> Result<(u32, SymbolTable), AsmErrors> {
+
+    let object = assemble_object(code)?;
+
+    link_flat(&object, out)
+}
+
+/// Assembles an image to be loaded at `base`, which is what labels used as addresses (e.g. in
+/// `.words`) resolve against. An `.org` overrides it, so the image's own base is the one to use.
+pub fn assemble_at(code: &[u8], base: u32) -> Result<Image, AsmErrors> {
+    link_at(assemble_object(code)?, base)
+}
+
+/// Assembles several files into one image, in order, as if they had all been included by one
+/// file. Labels are shared between all of them.
+pub fn assemble_files<P: AsRef<Path>>(paths: &[P], out: &mut Vec<u32>)
+    -> Result<(u32, SymbolTable), AsmErrors> {
+
+    let object = assemble_object_files(paths)?;
+
+    link_flat(&object, out)
+}
+
+/// Like `assemble_files`, but for an image to be loaded at `base`. See `assemble_at`.
+pub fn assemble_files_at<P: AsRef<Path>>(paths: &[P], base: u32) -> Result<Image, AsmErrors> {
+    link_at(assemble_object_files(paths)?, base)
+}
+
+/// Assembles to a relocatable object instead of an image.
+pub fn assemble_object(code: &[u8]) -> Result<Object, AsmErrors> {
+    let mut asm = Assembly::new();
+
+    parse_source(code, None, 0, None, &mut asm);
+
+    asm.finish("")
+}
+
+/// Like `assemble_files`, but to a relocatable object.
+pub fn assemble_object_files<P: AsRef<Path>>(paths: &[P]) -> Result<Object, AsmErrors> {
+    let mut asm = Assembly::new();
+
+    for path in paths {
+        match read_source(path.as_ref()) {
+            Ok(code) => parse_source(&code, Some(path.as_ref()), 0, None, &mut asm),
+            Err(e) => asm.errors.push(e)
+        }
+    }
+
+    let name = paths.first().map(|path| path.as_ref().display().to_string())
+        .unwrap_or_default();
+
+    asm.finish(&name)
+}
+
+/// Checks code for likely mistakes, without assembling it. Only the lints in `lints` are
+/// checked. If the code doesn't assemble, the errors are returned instead.
+pub fn lint(code: &[u8], lints: &[Lint]) -> Result<Vec<AsmError>, AsmErrors> {
+    let mut asm = Assembly::new();
+
+    parse_source(code, None, 0, None, &mut asm);
+
+    let warnings = asm.report(lint_chunks(&asm.chunks, lints));
+
+    asm.finish("").map(|_| warnings)
+}
+
+/// Like `lint`, for files that would be assembled together with `assemble_files`
+pub fn lint_files<P: AsRef<Path>>(paths: &[P], lints: &[Lint])
+    -> Result<Vec<AsmError>, AsmErrors> {
+
+    let mut asm = Assembly::new();
+
+    for path in paths {
+        match read_source(path.as_ref()) {
+            Ok(code) => parse_source(&code, Some(path.as_ref()), 0, None, &mut asm),
+            Err(e) => asm.errors.push(e)
+        }
+    }
+
+    let warnings = asm.report(lint_chunks(&asm.chunks, lints));
+
+    asm.finish("").map(|_| warnings)
+}
+
+fn link_at(object: Object, base: u32) -> Result<Image, AsmErrors> {
+    object::link(&[object], &BTreeMap::new(), base)
+        .map_err(|e| AsmError::new(AsmErrorKind::Link, e).into())
+}
+
+/// Links a single object at 0 (unless it has an `.org`), with its sections one after another.
+fn link_flat(object: &Object, out: &mut Vec<u32>) -> Result<(u32, SymbolTable), AsmErrors> {
+    let image = link_at(object.clone(), 0)?;
+
+    out.extend(image.words.iter().cloned());
+
+    Ok((image.words.len() as u32, image.symbols))
+}
+
+fn read_source(path: &Path) -> Result<Vec<u8>, AsmError> {
+    let mut code = vec![];
+
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut code))
+        .map_err(|e| AsmError::new(AsmErrorKind::Io, format!("{}: {}", path.display(), e)))?;
+
+    Ok(code)
+}
+
+// Like `.include`, `.section` is textual: it lasts until the next one, even across files
+fn current_section(chunks: &[Chunk]) -> String {
+    chunks.last().map(|chunk| chunk.section.clone())
+        .unwrap_or_else(|| DEFAULT_SECTION.into())
+}
+
+// Whatever comes after an include or a macro continues where it left off
+fn continue_chunk(chunks: &mut Vec<Chunk>, source: SourceLine) {
+    let section = current_section(chunks);
+
+    chunks.push(Chunk {
+        label: String::new(),
+        source: source,
+        section: section,
+        blocks: vec![],
+    });
+}
+
+/// Parses `code` and appends its chunks, along with those of anything it includes or any
+/// macros it uses.
+///
+/// Lines with errors are skipped, and the errors are collected in `asm`, so that as many as
+/// possible can be reported at once. Code from a macro is all attributed to the line that used
+/// it, given as `expansion`.
+fn parse_source(code: &[u8], file: Option<&Path>, depth: usize,
+                expansion: Option<&SourceLine>, asm: &mut Assembly) {
+
+    let file_name = file.map(|path| path.display().to_string());
+
+    if expansion.is_none() {
+        asm.sources.push((file_name.clone(), code.to_vec()));
+    }
+
+    let source_line = |remaining: usize| match expansion {
+        Some(source) => source.clone(),
+        None => SourceLine::new(file_name.clone(), line_number(code, remaining)),
+    };
+
+    let mut input = code;
+
+    loop {
+        if let IResult::Done(rest, _) = asm_multispace(input) {
+            input = rest;
+        }
+
+        if input.is_empty() {
+            break;
+        }
+
+        let source = source_line(input.len());
+
+        if let IResult::Done(rest, label) = label_def(input) {
+            input = rest;
+
+            if let Some(constant) = asm.constants.get(&label) {
+                asm.errors.push(AsmError::new(AsmErrorKind::Duplicate(label.clone()),
+                    format!("can't define label {}, it's a constant at {}",
+                            label, constant.source)).at(&source));
+                continue;
+            }
+
+            let section = current_section(&asm.chunks);
+
+            asm.chunks.push(Chunk {
+                label: label,
+                source: source,
+                section: section,
+                blocks: vec![],
+            });
+
+            continue;
+        }
+
+        match asm_block(input) {
+            IResult::Done(rest, block) => {
+                input = rest;
+
+                if let Err(e) = parse_block(block, source, file, depth, asm) {
+                    asm.errors.push(e);
+                }
+            },
+            _ => {
+                let line_len = input.iter().position(|&c| c == b'\n').unwrap_or(input.len());
+
+                let mut error = diagnose(&input[..line_len]).at(&source);
+
+                // Whatever came before it on the line, e.g. indentation or a label
+                let consumed = code.len() - input.len();
+                let line_start = code[..consumed].iter().rposition(|&c| c == b'\n')
+                    .map(|pos| pos + 1).unwrap_or(0);
+
+                error.column = error.column.map(|column| column + (consumed - line_start) as u32);
+
+                // The column would be in the expanded code, which isn't anywhere to be seen
+                if expansion.is_some() {
+                    error.column = None;
+                    error.message.push_str(" (in a macro)");
+                }
+
+                asm.errors.push(error);
+
+                input = &input[line_len..];
+            }
+        }
+    }
+}
+
+fn parse_block(block: AsmBlock, source: SourceLine, file: Option<&Path>, depth: usize,
+               asm: &mut Assembly) -> Result<(), AsmError> {
+
+    match block {
+        AsmBlock::Include(ref include) => {
+            if depth >= MAX_INCLUDE_DEPTH {
+                return Err(AsmError::new(AsmErrorKind::Invalid,
+                                         "includes nested too deeply (recursive?)").at(&source));
+            }
+
+            let dir = file.and_then(|path| path.parent()).unwrap_or(Path::new(""));
+            let path = dir.join(include);
+
+            let included = read_source(&path).map_err(|e| {
+                AsmError::new(e.kind, format!("can't include {}", e.message)).at(&source)
+            })?;
+
+            parse_source(&included, Some(&path), depth + 1, None, asm);
+
+            continue_chunk(&mut asm.chunks, source);
+        },
+        AsmBlock::Section(ref name) => {
+            asm.chunks.push(Chunk {
+                label: String::new(),
+                source: source,
+                section: name.clone(),
+                blocks: vec![],
+            });
+        },
+        AsmBlock::Macro(name, params, body) => {
+            asm.define(name, params, body, source)?;
+        },
+        AsmBlock::Equ(name, ref expr) => {
+            asm.define_constant(name, expr, source)?;
+        },
+        AsmBlock::MacroCall(ref name, ref args) => {
+            if depth >= MAX_INCLUDE_DEPTH {
+                return Err(AsmError::new(AsmErrorKind::Invalid,
+                                         "macros nested too deeply (recursive?)").at(&source));
+            }
+
+            let text = asm.expand(name, args, &source)?;
+
+            parse_source(text.as_bytes(), file, depth + 1, Some(&source), asm);
+
+            continue_chunk(&mut asm.chunks, source);
+        },
+        block => {
+            let block = asm.resolve(block, &source)?;
+
+            if asm.chunks.is_empty() {
+                continue_chunk(&mut asm.chunks, source.clone());
+            }
+
+            asm.chunks.last_mut().unwrap().blocks.push((source, block));
+        }
+    }
+
+    Ok(())
+}
+
+/// Works out what's wrong with a line that couldn't be parsed
+fn diagnose(line: &[u8]) -> AsmError {
+    let is_label_char = |c: u8| c == b'_' || c == b'.' || (c as char).is_alphanumeric();
+
+    let word_at = |start: usize| -> &[u8] {
+        let len = line[start..].iter().take_while(|&&c| is_label_char(c)).count();
+        &line[start..start + len]
+    };
+
+    let skip_space = |start: usize| -> usize {
+        start + line[start..].iter().take_while(|&&c| c == b' ' || c == b'\t').count()
+    };
+
+    let error = |kind: AsmErrorKind, message: String, column: usize| -> AsmError {
+        AsmError { column: Some(column as u32 + 1), ..AsmError::new(kind, message) }
+    };
+
+    // Numbers that are too big don't parse, and could be anywhere on the line
+    let mut pos = 0;
+    let mut quote = None;
+
+    while pos < line.len() {
+        let c = line[pos];
+
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) if c == b'\\' => pos += 1,
+            Some(_) => (),
+            None if c == b'"' || c == b'\'' => quote = Some(c),
+            None if c == b';' => break,
+            None if (c as char).is_digit(10) && (pos == 0 || !is_label_char(line[pos - 1])) => {
+                let number = word_at(pos);
+
+                if out_of_range(number) {
+                    let number = String::from_utf8_lossy(number).into_owned();
+
+                    return error(AsmErrorKind::ConstantOutOfRange(number.clone()),
+                                 format!("{} doesn't fit in 32 bits", number), pos);
+                }
+
+                pos += number.len();
+                continue;
+            },
+            None => ()
+        }
+
+        pos += 1;
+    }
+
+    let start = skip_space(0);
+    let word = word_at(start);
+
+    if line.get(start) == Some(&b'.') {
+        let directive = String::from_utf8_lossy(word_at(start + 1)).into_owned();
+
+        return if DIRECTIVES.contains(&&directive[..]) {
+            error(AsmErrorKind::Syntax, format!("bad .{} directive", directive), start)
+        } else {
+            error(AsmErrorKind::Syntax, format!("unknown directive .{}", directive), start)
+        };
+    }
+
+    if word.is_empty() {
+        let c = String::from_utf8_lossy(&line[start..start + 1]).into_owned();
+
+        return error(AsmErrorKind::Syntax, format!("unexpected {:?}", c), start);
+    }
+
+    if let IResult::Done(b"", _) = function(word) {
+        let after = skip_space(start + word.len());
+        let register = word_at(after);
+
+        if !register.is_empty() {
+            if let IResult::Done(b"", _) = self::register(register) {
+                // Fine, so it must be the operand
+            } else {
+                let register = String::from_utf8_lossy(register).into_owned();
+
+                return error(AsmErrorKind::BadRegister(register.clone()),
+                             format!("bad register: {}", register), after);
+            }
+        }
+
+        let after = skip_space(after + register.len());
+
+        return if line.get(after) == Some(&b'[') {
+            error(AsmErrorKind::Syntax, "can't parse operand".into(), after + 1)
+        } else {
+            error(AsmErrorKind::Syntax, "expected an operand in [brackets]".into(), after)
+        };
     }
+
+    error(AsmErrorKind::Syntax, "can't parse this line".into(), start)
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum AsmEndianness {
-    Big,
-    Little,
+/// Where `word` is in `line`, as a whole word
+fn find_word(line: &str, word: &str) -> Option<usize> {
+    let is_label_char = |c: char| c == '_' || c == '.' || c.is_alphanumeric();
+
+    line.match_indices(word).map(|(index, _)| index).find(|&index| {
+        !line[..index].chars().next_back().map(&is_label_char).unwrap_or(false) &&
+        !line[index + word.len()..].chars().next().map(&is_label_char).unwrap_or(false)
+    })
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct AsmInstruction(Function, Option<Register>, Option<AsmOperand>);
+/// Directives that the parser knows, for telling bad ones from unknown ones
+static DIRECTIVES: &'static [&'static str] = &[
+    "len_words", "words", "len_bytes", "bytes", "include", "section", "export", "import",
+    "macro", "equ", "define", "org",
+];
+
+/// True if `number` is written correctly, but is too big for a u32
+fn out_of_range(number: &[u8]) -> bool {
+    let number = String::from_utf8_lossy(number);
+
+    let (digits, radix) = if number.starts_with("0x") {
+        (&number[2..], 16)
+    } else if number.starts_with("0b") {
+        (&number[2..], 2)
+    } else if number.starts_with('0') && number.len() > 1 {
+        (&number[1..], 8)
+    } else {
+        (&number[..], 10)
+    };
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum AsmOperand {
-    Reg(Register),
-    Const(u32),
-    Relative(i32),
-    Label(Label, i32)
+    !digits.is_empty() &&
+        digits.chars().all(|c| c.is_digit(radix)) &&
+        u32::from_str_radix(digits, radix).is_err()
 }
 
-pub fn assemble(code: &[u8], out: &mut Vec<u32>) -> Result<u32, String> {
-    match asm_blocks_file( &code[..] ) {
-        IResult::Done( b"", result ) =>
-            blocks_to_instructions(&result, out),
+/// The line number of the position `remaining` bytes from the end of `code`
+fn line_number(code: &[u8], remaining: usize) -> u32 {
+    let consumed = &code[..code.len() - remaining];
 
-        IResult::Error(verbose_errors::Err::Position(ekind, pos)) => {
-            let s = String::from_utf8_lossy(pos);
-            Err(format!("parser error near {:?}, unconsumed input: {}", ekind, s))
-        },
+    consumed.iter().filter(|&&c| c == b'\n').count() as u32 + 1
+}
+
+/// Warnings for everything in `lints` that turns up in `chunks`
+fn lint_chunks(chunks: &[Chunk], lints: &[Lint]) -> Vec<AsmError> {
+    let mut warnings = vec![];
+
+    let mut warn = |lint: Lint, message: String, source: &SourceLine| {
+        if lints.contains(&lint) {
+            warnings.push(AsmError::new(AsmErrorKind::Lint(lint), message).at(source));
+        }
+    };
+
+    let mut used: BTreeSet<&str> = BTreeSet::new();
+
+    // Where the last unconditional jump or halt was, if nothing since could be jumped to
+    let mut jumped: Option<Function> = None;
+
+    let mut section = DEFAULT_SECTION;
+
+    for chunk in chunks {
+        if !chunk.label.is_empty() || chunk.section != section {
+            jumped = None;
+        }
+
+        section = &chunk.section;
+
+        for &(ref source, ref block) in &chunk.blocks {
+            match *block {
+                AsmBlock::Instruction(AsmInstruction(f, r, ref op)) => {
+                    match jumped.take() {
+                        // Waiting for an interrupt, which carries on after the `halt` when it
+                        // returns
+                        Some(Function::Halt) if f == Function::Branch => (),
+                        Some(previous) => warn(Lint::Unreachable, format!(
+                            "unreachable code after {}", mnemonic(previous)), source),
+                        None => ()
+                    }
+
+                    let name = mnemonic(f);
+
+                    match f {
+                        Function::Halt | Function::Branch | Function::Ret | Function::IntExit =>
+                            jumped = Some(f),
+                        Function::Trace =>
+                            warn(Lint::Trace, "trace left in the code".into(), source),
+                        Function::DivMod | Function::DivModS
+                            if r != Some(Register::C) && r != Some(Register::D) =>
 
-        IResult::Error(e) => Err(format!("parser error: {:?}", e)),
-        other_e => Err(format!("parser unexpected condition: {:?}", other_e))
+                            warn(Lint::DivMod, format!(
+                                "{} only reads {}: the quotient goes in c and the remainder in d",
+                                name, register_name(r.unwrap_or(Register::A))), source),
+                        _ => ()
+                    }
+
+                    if let Some(r) = r {
+                        if !uses_register(f) {
+                            warn(Lint::IgnoredRegister, format!(
+                                "{} doesn't use a register, so {} is ignored",
+                                name, register_name(r)), source);
+                        }
+                    }
+
+                    match *op {
+                        None | Some(AsmOperand::Const(Expr::Num(0))) => (),
+                        Some(_) if !uses_operand(f) => warn(Lint::IgnoredOperand, format!(
+                            "{} doesn't use an operand, so it's ignored", name), source),
+                        _ => ()
+                    }
+
+                    match *op {
+                        Some(AsmOperand::Label(ref label, _)) => { used.insert(label); },
+                        Some(AsmOperand::Const(ref expr)) => expr.names(&mut used),
+                        _ => ()
+                    }
+                },
+                AsmBlock::Words(ref words) => {
+                    for word in words {
+                        match *word {
+                            AsmWord::Label(ref label, _) => { used.insert(label); },
+                            AsmWord::Const(ref expr) => expr.names(&mut used),
+                        }
+                    }
+                },
+                AsmBlock::Export(ref labels) => used.extend(labels.iter().map(|l| &l[..])),
+                AsmBlock::Org(_) => jumped = None,
+                _ => ()
+            }
+        }
+    }
+
+    // A label at the very start is where the code is entered, so it doesn't need to be used
+    let mut start = true;
+
+    for chunk in chunks {
+        if !start && !chunk.label.is_empty() && !used.contains(&chunk.label[..]) {
+            warn(Lint::UnusedLabel, format!("label {} is never used", chunk.label),
+                 &chunk.source);
+        }
+
+        start = start && chunk.blocks.is_empty();
     }
+
+    warnings
 }
 
-fn blocks_to_instructions(sections: &[(Label, Vec<AsmBlock>)], out: &mut Vec<u32>)
-    -> Result<u32, String> {
+/// Lays out and encodes everything. Errors don't stop it, so that it can find all of them.
+fn chunks_to_object(chunks: &[Chunk], name: &str) -> Result<Object, Vec<AsmError>> {
+    let mut object = Object { name: name.into(), ..Object::default() };
+
+    let mut errors: Vec<AsmError> = vec![];
+
+    // Which section each chunk goes in, and where. Empty chunks don't go anywhere, so that a
+    // `.section` at the top of a file doesn't leave an empty section before it.
+    let mut placement: Vec<Option<(usize, u32)>> = vec![];
+
+    let mut sizes: Vec<u32> = vec![];
+
+    let mut label_offsets: BTreeMap<&str, (usize, u32, &SourceLine)> = BTreeMap::new();
+
+    for chunk in chunks {
+        if chunk.label.is_empty() && chunk.blocks.is_empty() {
+            placement.push(None);
+            continue;
+        }
+
+        let index = match object.sections.iter().position(|s| s.name == chunk.section) {
+            Some(index) => index,
+            None => {
+                object.sections.push(object::Section::new(&chunk.section));
+                sizes.push(0);
+                sizes.len() - 1
+            }
+        };
+
+        let start = sizes[index];
+        sizes[index] += chunk.blocks.iter().map(|&(_, ref b)| b.size()).sum();
+
+        placement.push(Some((index, start)));
 
-    let mut current_ptr = 0_u32;
+        if chunk.label.is_empty() {
+            continue;
+        }
+
+        debug!("label_offset {:?}, {}+{:#x}", chunk.label, chunk.section, start);
+
+        if let Some(&(_, _, first)) = label_offsets.get(&chunk.label[..]) {
+            errors.push(AsmError::new(AsmErrorKind::Duplicate(chunk.label.clone()),
+                format!("duplicate label {} (first defined at {})", chunk.label, first))
+                .at(&chunk.source));
+            continue;
+        }
 
-    let label_offsets: BTreeMap<Label, u32> =
-        sections.iter().map(|&(ref label, ref blocks)| {
-            let start = current_ptr;
-            current_ptr += blocks.iter().map(|b| b.size()).sum();
+        label_offsets.insert(&chunk.label, (index, start, &chunk.source));
+    }
 
-            debug!("label_offset {:?}, {:#x}", label, start);
+    let mut imports: BTreeSet<&str> = BTreeSet::new();
+    let mut exports: BTreeSet<&str> = BTreeSet::new();
 
-            (label.clone(), start)
-        }).collect();
+    for chunk in chunks {
+        for &(ref source, ref block) in &chunk.blocks {
+            match *block {
+                AsmBlock::Import(ref labels) => {
+                    for label in labels {
+                        if let Some(&(_, _, defined)) = label_offsets.get(&label[..]) {
+                            errors.push(AsmError::new(AsmErrorKind::Duplicate(label.clone()),
+                                format!("can't import {}, it's defined at {}", label, defined))
+                                .at(source));
+                            continue;
+                        }
 
-    current_ptr = 0;
+                        imports.insert(label);
+                    }
+                },
+                AsmBlock::Export(ref labels) => {
+                    for label in labels {
+                        if !label_offsets.contains_key(&label[..]) {
+                            errors.push(AsmError::new(AsmErrorKind::UnknownLabel(label.clone()),
+                                format!("can't export {}, it isn't defined", label))
+                                .at(source));
+                            continue;
+                        }
 
-    let resolve = |current_ptr: u32, label: &str| -> Result<i32, String> {
-        let off = *label_offsets.get(label)
-            .ok_or_else(|| format!("Label not found: {}", label))?;
+                        exports.insert(label);
+                    }
+                },
+                _ => ()
+            }
+        }
+    }
 
-        Ok(off as i32 - current_ptr as i32)
+    // Where `label` is: in a section of this object, or imported. `addend` is added to the
+    // relocation, for when it's relative.
+    let target = |label: &str| -> Result<(RelocationTarget, u32), AsmError> {
+        if let Some(&(index, offset, _)) = label_offsets.get(label) {
+            Ok((RelocationTarget::Section(index), offset))
+        } else if imports.contains(label) {
+            Ok((RelocationTarget::Symbol(label.into()), 0))
+        } else {
+            Err(AsmError::new(AsmErrorKind::UnknownLabel(label.into()),
+                              format!("label not found: {}", label)))
+        }
     };
 
-    for &(ref cur_label, ref blocks) in sections {
+    for (chunk, &placement) in chunks.iter().zip(&placement) {
+        let (index, start) = match placement {
+            Some(placement) => placement,
+            None => continue
+        };
+
+        let section = &mut object.sections[index];
+
         // Sanity check, just to make sure we don't resolve the wrong addresses
-        {
-            let calculated_offset = label_offsets.get(cur_label).cloned().unwrap_or_else(|| {
-                panic!("Bug check: label {:?} exists in `sections`, but not `label_offsets`",
-                    cur_label);
-            });
+        assert!(section.words.len() as u32 == start,
+            "Bug check: Previously calculated offset for chunk {:?} is {}+{:#x}, but \
+             we are actually writing its contents at {:#x} for some reason.",
+            chunk.label, chunk.section, start, section.words.len());
 
-            assert!(calculated_offset == current_ptr,
-                "Bug check: Previously calculated offset for label {:?} is {:#x}, but \
-                 we are actually writing its contents at {:#x} for some reason.",
-                cur_label, calculated_offset, current_ptr);
-        }
+        for &(ref source, ref block) in &chunk.blocks {
+            let current_ptr = section.words.len() as u32;
+
+            if block.size() > 0 {
+                section.lines.push((current_ptr, source.clone()));
+            }
 
-        for block in blocks {
             match *block {
-                AsmBlock::Instruction(AsmInstruction(f, r, ref op)) => {
-                    let orig_len = out.len();
+                AsmBlock::Instruction(AsmInstruction(f, r, Some(AsmOperand::Const(ref c))))
+                    if c.as_num().is_none() => {
+
+                    // Depends on labels, so it was counted as two words even if it works out to
+                    // zero. Encode it with a placeholder that can't be zero, then fill it in.
+                    let value = Linear::from_expr(c, &target)
+                        .and_then(|linear| linear.to_word(current_ptr + 1, section))
+                        .unwrap_or_else(|e| {
+                            errors.push(e.at(source));
+                            0
+                        });
 
                     bitcode::encode_instruction(
-                        Instruction(f, r.unwrap_or(Register::A), match *op {
-                            Some(AsmOperand::Reg(r))           => Operand::Reg(r),
-                            Some(AsmOperand::Const(c))         => Operand::Const(c),
-                            Some(AsmOperand::Relative(cr))     => Operand::Relative(cr),
-                            Some(AsmOperand::Label(ref label, offset)) =>
-                                Operand::Relative(resolve(current_ptr, label)? + offset),
-                            None => Operand::Const(0)
-                        }),
-                        out
+                        Instruction(f, r.unwrap_or(Register::A), Operand::Const(1)),
+                        &mut section.words
                     );
 
-                    current_ptr += (out.len() - orig_len) as u32;
+                    *section.words.last_mut().unwrap() = value;
                 },
-                AsmBlock::Words(ref words) => {
-                    out.extend(words.iter().cloned());
+                AsmBlock::Instruction(AsmInstruction(f, r, ref op)) => {
+                    let operand = match *op {
+                        Some(AsmOperand::Reg(r))           => Operand::Reg(r),
+                        Some(AsmOperand::Const(ref c))     => Operand::Const(c.value()),
+                        Some(AsmOperand::Relative(ref cr)) => Operand::Relative(cr.value() as i32),
+                        Some(AsmOperand::Label(ref label, ref offset)) => {
+                            let offset = offset.value() as i32;
+
+                            match target(label) {
+                                Err(e) => {
+                                    errors.push(e.at(source));
+                                    Operand::Relative(0)
+                                },
+                                Ok((RelocationTarget::Section(target_index), label_offset))
+                                    if target_index == index => {
+
+                                    Operand::Relative(
+                                        label_offset as i32 - current_ptr as i32 + offset)
+                                },
+                                Ok((reloc_target, label_offset)) => {
+                                    // The operand is the second word, but it's relative to
+                                    // the first
+                                    section.relocations.push(Relocation {
+                                        offset: current_ptr + 1,
+                                        kind: RelocationKind::Relative,
+                                        target: reloc_target,
+                                        addend: label_offset as i32 + offset + 1,
+                                    });
+
+                                    Operand::Relative(0)
+                                }
+                            }
+                        },
+                        None => Operand::Const(0)
+                    };
 
-                    current_ptr += words.len() as u32;
+                    bitcode::encode_instruction(
+                        Instruction(f, r.unwrap_or(Register::A), operand),
+                        &mut section.words
+                    );
+                },
+                AsmBlock::Words(ref words) => {
+                    for word in words {
+                        match *word {
+                            AsmWord::Const(ref c) => {
+                                let offset = section.words.len() as u32;
+
+                                let value = Linear::from_expr(c, &target)
+                                    .and_then(|linear| linear.to_word(offset, section))
+                                    .unwrap_or_else(|e| {
+                                        errors.push(e.at(source));
+                                        0
+                                    });
+
+                                section.words.push(value);
+                            },
+                            AsmWord::Label(ref label, ref offset) => {
+                                let offset = offset.value() as i32;
+
+                                match target(label) {
+                                    Ok((reloc_target, label_offset)) => {
+                                        section.relocations.push(Relocation {
+                                            offset: section.words.len() as u32,
+                                            kind: RelocationKind::Absolute,
+                                            target: reloc_target,
+                                            addend: label_offset as i32 + offset,
+                                        });
+                                    },
+                                    Err(e) => errors.push(e.at(source))
+                                }
+
+                                section.words.push(0);
+                            }
+                        }
+                    }
                 },
                 AsmBlock::Bytes(endianness, ref bytes) => {
                     fn try_get(slice: &[u8], idx: usize) -> u32 {
@@ -140,50 +1570,61 @@ fn blocks_to_instructions(sections: &[(Label, Vec<AsmBlock>)], out: &mut Vec<u32
 
                     for word_bytes in bytes.chunks(4) {
                         if let AsmEndianness::Big = endianness {
-                            out.push((try_get(word_bytes, 3) <<  0) |
-                                     (try_get(word_bytes, 2) <<  8) |
-                                     (try_get(word_bytes, 1) << 16) |
-                                     (try_get(word_bytes, 0) << 24));
+                            section.words.push((try_get(word_bytes, 3) <<  0) |
+                                               (try_get(word_bytes, 2) <<  8) |
+                                               (try_get(word_bytes, 1) << 16) |
+                                               (try_get(word_bytes, 0) << 24));
                         } else {
-                            out.push((try_get(word_bytes, 0) <<  0) |
-                                     (try_get(word_bytes, 1) <<  8) |
-                                     (try_get(word_bytes, 2) << 16) |
-                                     (try_get(word_bytes, 3) << 24));
+                            section.words.push((try_get(word_bytes, 0) <<  0) |
+                                               (try_get(word_bytes, 1) <<  8) |
+                                               (try_get(word_bytes, 2) << 16) |
+                                               (try_get(word_bytes, 3) << 24));
                         }
+                    }
+                },
+                AsmBlock::Org(ref addr) => {
+                    if current_ptr != 0 {
+                        errors.push(AsmError::new(AsmErrorKind::Invalid,
+                            format!(".org has to come before anything else in section {}",
+                                    section.name)).at(source));
+                        continue;
+                    }
 
-                        current_ptr += 1;
+                    match section.origin {
+                        Some(origin) if origin != addr.value() => {
+                            errors.push(AsmError::new(AsmErrorKind::Invalid,
+                                format!("section {} already has .org {:#x}",
+                                        section.name, origin)).at(source));
+                        },
+                        _ => section.origin = Some(addr.value())
                     }
+                },
+                AsmBlock::Export(_) | AsmBlock::Import(_) => (),
+                AsmBlock::Include(_) | AsmBlock::Section(_) |
+                AsmBlock::Macro(_, _, _) | AsmBlock::MacroCall(_, _) | AsmBlock::Equ(_, _) => {
+                    panic!("Bug check: {:?} should have been handled by parse_source", block);
                 }
             }
         }
     }
 
-    Ok(current_ptr)
-}
-
-named!(asm_blocks_file<&[u8], Vec<(Label, Vec<AsmBlock>)>>,
-    do_parse!(
-        opt!(complete!(asm_multispace)) >>
-        blocks: asm_blocks >>
-        eof!() >>
-
-        ( blocks )
-    )
-);
-
-named!(asm_blocks<&[u8], Vec<(Label, Vec<AsmBlock>)>>,
-    many0!(asm_block_pair)
-);
+    for (label, &(index, offset, _)) in &label_offsets {
+        object.symbols.push(object::Symbol {
+            name: (*label).into(),
+            section: index,
+            offset: offset,
+            exported: exports.contains(label),
+        });
+    }
 
-named!(asm_block_pair<&[u8], (Label, Vec<AsmBlock>)>,
-    do_parse!(
-        not!(peek!(preceded!(asm_multispace, eof!()))) >>
-        label_opt: opt!(complete!(label_def)) >>
-        blocks: many0!(asm_block) >>
+    object.imports = imports.iter().map(|&label| label.into()).collect();
 
-        ( label_opt.unwrap_or_else(|| String::new()), blocks )
-    )
-);
+    if errors.is_empty() {
+        Ok(object)
+    } else {
+        Err(errors)
+    }
+}
 
 named!(label_initial_char<&[u8], char>,
     verify!(anychar, |c: char| c == '_' || c.is_alpha())
@@ -205,7 +1646,7 @@ named!(label<&[u8], Label>,
     )
 );
 
-named!(label_with_offset<&[u8], (Label, i32)>,
+named!(label_with_offset<&[u8], (Label, Expr)>,
     do_parse!(
         lab: label >>
         opt!(space) >>
@@ -214,8 +1655,8 @@ named!(label_with_offset<&[u8], (Label, i32)>,
         num: constant >>
 
         (lab, match op {
-            '+' => num as i32,
-            '-' => -(num as i32),
+            '+' => num,
+            '-' => num.negate(),
             _ => unreachable!()
         })
     )
@@ -234,6 +1675,7 @@ named!(asm_block<&[u8], AsmBlock>,
     do_parse!(
         block: alt_complete!(
             asm_block_directive |
+            macro_call |
             map!(asm_instruction, AsmBlock::Instruction) 
         ) >>
         opt!(complete!(space)) >>
@@ -262,14 +1704,157 @@ named!(asm_block_directive<&[u8], AsmBlock>,
             b"len_words" => call!(dir_len_words) |
             b"words"     => call!(dir_words) |
             b"len_bytes" => call!(dir_len_bytes) |
-            b"bytes"     => call!(dir_bytes)
+            b"bytes"     => call!(dir_bytes) |
+            b"include"   => call!(dir_include) |
+            b"section"   => map!(label, AsmBlock::Section) |
+            b"export"    => map!(label_list, AsmBlock::Export) |
+            b"import"    => map!(label_list, AsmBlock::Import) |
+            b"macro"     => call!(dir_macro) |
+            b"equ"       => call!(dir_equ) |
+            b"define"    => call!(dir_equ) |
+            b"org"       => map!(constant, AsmBlock::Org)
         )
     )
 );
 
+named!(dir_macro<&[u8], AsmBlock>,
+    do_parse!(
+        name: label >>
+        params: opt!(complete!(preceded!(space, label_list))) >>
+        opt!(complete!(space)) >>
+        opt!(complete!(comment)) >>
+        line_ending >>
+        body: macro_body >>
+
+        ( AsmBlock::Macro(name, params.unwrap_or_default(),
+                          String::from_utf8_lossy(body).into_owned()) )
+    )
+);
+
+/// Everything up to a line with just `.endm` on it. The `.endm` is consumed too.
+fn macro_body(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let mut pos = 0;
+
+    loop {
+        let line_end = input[pos..].iter().position(|&c| c == b'\n').map(|n| pos + n);
+        let line = &input[pos..line_end.unwrap_or(input.len())];
+
+        let indent = line.iter().take_while(|&&c| c == b' ' || c == b'\t').count();
+        let trimmed = &line[indent..];
+
+        if trimmed.starts_with(b".endm") {
+            let after = trimmed.get(5).cloned();
+
+            if after.map(|c| c == b' ' || c == b'\t' || c == b'\r' || c == b';')
+                    .unwrap_or(true) {
+                return IResult::Done(&input[pos + indent + 5..], &input[..pos]);
+            }
+        }
+
+        match line_end {
+            Some(line_end) => pos = line_end + 1,
+            None => return IResult::Error(error_position!(ErrorKind::Custom(0), input))
+        }
+    }
+}
+
+named!(macro_call<&[u8], AsmBlock>,
+    do_parse!(
+        // Anything that isn't an instruction could be a macro
+        name: map_opt!(label, |name: String| {
+            match function(name.as_bytes()) {
+                IResult::Done(b"", _) => None,
+                _ => Some(name)
+            }
+        }) >>
+        not!(peek!(one_of!(":"))) >>
+        args: opt!(complete!(preceded!(space, macro_args))) >>
+
+        ( AsmBlock::MacroCall(name, args.unwrap_or_default()) )
+    )
+);
+
+/// Comma separated arguments, up to the end of the line or a comment. Commas inside brackets,
+/// parentheses or quotes don't count.
+fn macro_args(input: &[u8]) -> IResult<&[u8], Vec<String>> {
+    let mut args = vec![];
+    let mut start = 0;
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut end = input.len();
+
+    for (i, &c) in input.iter().enumerate() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == b'\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+
+        match c {
+            b'"' | b'\'' => quote = Some(c),
+            b'[' | b'(' | b'{' => depth += 1,
+            b']' | b')' | b'}' => depth -= 1,
+            b',' if depth == 0 => {
+                args.push(&input[start..i]);
+                start = i + 1;
+            },
+            b'\r' | b'\n' | b';' => {
+                end = i;
+                break;
+            },
+            _ => ()
+        }
+    }
+
+    args.push(&input[start..end]);
+
+    let args: Vec<String> = args.into_iter()
+        .map(|arg| String::from_utf8_lossy(arg).trim().to_owned())
+        .collect();
+
+    if args.len() == 1 && args[0].is_empty() {
+        return IResult::Done(&input[end..], vec![]);
+    }
+
+    // Leave the whitespace before a comment to the caller
+    let trailing = input[..end].iter().rev().take_while(|&&c| c == b' ' || c == b'\t').count();
+
+    IResult::Done(&input[end - trailing..], args)
+}
+
+named!(dir_equ<&[u8], AsmBlock>,
+    do_parse!(
+        name: label >>
+        alt_complete!(delimited!(opt!(space), tag!(","), opt!(space)) | space) >>
+        value: constant >>
+
+        ( AsmBlock::Equ(name, value) )
+    )
+);
+
+named!(label_list<&[u8], Vec<Label>>,
+    separated_nonempty_list!(delimited!(opt!(space), one_of!(","), opt!(space)), label)
+);
+
+named!(word_value<&[u8], AsmWord>,
+    alt_complete!(
+        map!(label_with_offset, |(l, o)| AsmWord::Label(l, o)) |
+        map!(constant, |c| match c {
+            Expr::Name(l) => AsmWord::Label(l, Expr::Num(0)),
+            c => AsmWord::Const(c)
+        })
+    )
+);
+
 named!(dir_words<&[u8], AsmBlock>,
     map!(delimited!(one_of!("{"),
-                    separated_list!(one_of!(","), ws!(constant)),
+                    separated_list!(one_of!(","), ws!(word_value)),
                     one_of!("}")),
          AsmBlock::Words)
 );
@@ -283,7 +1868,7 @@ named!(dir_len_words<&[u8], AsmBlock>,
 
         let len = words.len() as u32;
 
-        words.insert(0, len);
+        words.insert(0, AsmWord::Const(Expr::Num(len)));
         AsmBlock::Words(words)
     })
 );
@@ -300,6 +1885,10 @@ named!(dir_bytes<&[u8], AsmBlock>,
     )
 );
 
+named!(dir_include<&[u8], AsmBlock>,
+    map!(string, |path: Vec<u8>| AsmBlock::Include(String::from_utf8_lossy(&path).into_owned()))
+);
+
 named!(dir_len_bytes<&[u8], AsmBlock>,
     map!(dir_bytes, |block| {
         let (endianness, bytes) = match block {
@@ -374,11 +1963,14 @@ named!(function<&[u8], Function>,
             "store"    => Store,
 
             "cmp"      => Cmp,
+            "cmps"     => CmpS,
             "branch"   => Branch,
             "branchl"  => BranchL,
             "branchg"  => BranchG,
             "branche"  => BranchE,
             "branchne" => BranchNE,
+            "branchc"  => BranchC,
+            "brancho"  => BranchO,
 
             "getsp"    => GetSp,
             "setsp"    => SetSp,
@@ -392,6 +1984,8 @@ named!(function<&[u8], Function>,
             "mul"      => Mul,
             "div"      => Div,
             "divmod"   => DivMod,
+            "divs"     => DivS,
+            "divmods"  => DivModS,
 
             "not"      => Not,
             "and"      => And,
@@ -399,6 +1993,7 @@ named!(function<&[u8], Function>,
             "xor"      => Xor,
             "lsh"      => Lsh,
             "rsh"      => Rsh,
+            "ash"      => Ash,
 
             "halt"     => Halt,
             "intsw"    => IntSw,
@@ -436,13 +2031,15 @@ named!(operand<&[u8], AsmOperand>,
     alt_complete!(
         map!(register, AsmOperand::Reg) |
         map!(relative, AsmOperand::Relative) |
-        map!(constant, AsmOperand::Const) |
         map!(label_with_offset, |(l, o)| AsmOperand::Label(l, o)) |
-        map!(label, |x| AsmOperand::Label(x, 0))
+        map!(constant, |c| match c {
+            Expr::Name(l) => AsmOperand::Label(l, Expr::Num(0)),
+            c => AsmOperand::Const(c)
+        })
     )
 );
 
-named!(relative<&[u8], i32>,
+named!(relative<&[u8], Expr>,
     do_parse!(
         one_of!("$") >>
         opt!(space) >>
@@ -451,69 +2048,61 @@ named!(relative<&[u8], i32>,
         num: constant >>
 
         (match op {
-            '+' => num as i32,
-            '-' => -(num as i32),
+            '+' => num,
+            '-' => num.negate(),
             _ => unreachable!()
         })
     )
 );
 
-named!(constant<&[u8], u32>,
+named!(constant<&[u8], Expr>,
     alt_complete!(
         constant_binary_expr | constant_not_free
     )
 );
 
-named!(constant_not_free<&[u8], u32>,
+named!(constant_not_free<&[u8], Expr>,
     alt_complete!(
-        constant_in_parens | constant_unary_expr | character | integer
+        constant_in_parens |
+        constant_unary_expr |
+        map!(character, Expr::Num) |
+        map!(integer, Expr::Num) |
+        map!(label, Expr::Name)
     )
 );
 
-named!(constant_in_parens<&[u8], u32>,
+named!(constant_in_parens<&[u8], Expr>,
     delimited!(tag!("("), ws!(constant), tag!(")"))
 );
 
-named!(constant_unary_expr<&[u8], u32>,
+named!(constant_unary_expr<&[u8], Expr>,
     do_parse!(
         tag!("~") >>
         opt!(complete!(space)) >>
         a: constant_not_free >>
-        (!a)
+        (Expr::Not(Box::new(a)))
     )
 );
 
-named!(constant_binary_expr<&[u8], u32>,
+named!(constant_binary_expr<&[u8], Expr>,
     do_parse!(
         a: constant_not_free >>
         opt!(complete!(space)) >>
         operator: alt_complete!(
-            tag!("+") |
-            tag!("-") |
-            tag!("*") |
-            tag!("**") |
-            tag!("/") |
-            tag!("&") |
-            tag!("|") |
-            tag!("^") |
-            tag!("<<") |
-            tag!(">>")
+            tag!("+")  => { |_| BinaryOp::Add } |
+            tag!("-")  => { |_| BinaryOp::Sub } |
+            tag!("**") => { |_| BinaryOp::Pow } |
+            tag!("*")  => { |_| BinaryOp::Mul } |
+            tag!("/")  => { |_| BinaryOp::Div } |
+            tag!("&")  => { |_| BinaryOp::And } |
+            tag!("|")  => { |_| BinaryOp::Or  } |
+            tag!("^")  => { |_| BinaryOp::Xor } |
+            tag!("<<") => { |_| BinaryOp::Lsh } |
+            tag!(">>") => { |_| BinaryOp::Rsh }
         ) >>
         opt!(complete!(space)) >>
         b: constant_not_free >>
-        (match operator {
-            b"+"  => a + b,
-            b"-"  => a - b,
-            b"*"  => a * b,
-            b"**" => a.pow(b),
-            b"/"  => a / b,
-            b"&"  => a & b,
-            b"|"  => a | b,
-            b"^"  => a ^ b,
-            b"<<" => a << b,
-            b">>" => a >> b,
-            _ => unreachable!()
-        })
+        (Expr::Binary(operator, Box::new(a), Box::new(b)))
     )
 );
 
@@ -628,20 +2217,19 @@ fn test_fibonacci() {
 
     println!("{:?}", String::from_utf8_lossy(code));
 
-    match asm_blocks_file( &code[..] ) {
-        IResult::Done( b"", result ) => {
-            println!("{:#?}", result);
+    let mut asm = Assembly::new();
 
-            let mut out = vec![];
+    parse_source(code, None, 0, None, &mut asm);
 
-            blocks_to_instructions(&result, &mut out).unwrap();
+    assert_eq!(asm.errors, vec![]);
 
-            println!("{:?}", out);
+    println!("{:#?}", asm.chunks);
 
-            assert_eq!(out.len(), 17 * 2 - 9);
-        },
-        e => panic!("{:?}", e)
-    }
+    let out = chunks_to_object(&asm.chunks, "").unwrap().sections.remove(0).words;
+
+    println!("{:?}", out);
+
+    assert_eq!(out.len(), 17 * 2 - 9);
 }
 
 #[test]
@@ -692,7 +2280,7 @@ fn test_operand_reg() {
 fn test_operand_const_hex() {
     assert_eq!(
         operand( &b"0x20"[..] ),
-        IResult::Done( &b""[..], AsmOperand::Const(0x20) )
+        IResult::Done( &b""[..], AsmOperand::Const(Expr::Num(0x20)) )
     );
 }
 
@@ -700,7 +2288,7 @@ fn test_operand_const_hex() {
 fn test_operand_const_bin() {
     assert_eq!(
         operand( &b"0b11011"[..] ),
-        IResult::Done( &b""[..], AsmOperand::Const(0b11011) )
+        IResult::Done( &b""[..], AsmOperand::Const(Expr::Num(0b11011)) )
     );
 }
 
@@ -708,7 +2296,7 @@ fn test_operand_const_bin() {
 fn test_operand_const_oct() {
     assert_eq!(
         operand( &b"0755"[..] ),
-        IResult::Done( &b""[..], AsmOperand::Const(0b111101101) )
+        IResult::Done( &b""[..], AsmOperand::Const(Expr::Num(0b111101101)) )
     );
 }
 
@@ -716,7 +2304,7 @@ fn test_operand_const_oct() {
 fn test_operand_const_dec() {
     assert_eq!(
         operand( &b"322"[..] ),
-        IResult::Done( &b""[..], AsmOperand::Const(322) )
+        IResult::Done( &b""[..], AsmOperand::Const(Expr::Num(322)) )
     );
 }
 
@@ -724,7 +2312,7 @@ fn test_operand_const_dec() {
 fn test_operand_const_dec_neg() {
     assert_eq!(
         operand( &b"-420"[..] ),
-        IResult::Done( &b""[..], AsmOperand::Const(-420i32 as u32) )
+        IResult::Done( &b""[..], AsmOperand::Const(Expr::Num(-420i32 as u32)) )
     );
 }
 
@@ -732,7 +2320,7 @@ fn test_operand_const_dec_neg() {
 fn test_operand_relative_pos() {
     assert_eq!(
         operand( &b"$ + 0x20"[..] ),
-        IResult::Done( &b""[..], AsmOperand::Relative(0x20) )
+        IResult::Done( &b""[..], AsmOperand::Relative(Expr::Num(0x20)) )
     );
 }
 
@@ -740,7 +2328,7 @@ fn test_operand_relative_pos() {
 fn test_operand_relative_neg() {
     assert_eq!(
         operand( &b"$ - 4"[..] ),
-        IResult::Done( &b""[..], AsmOperand::Relative(-4) )
+        IResult::Done( &b""[..], AsmOperand::Relative(Expr::Num(-4i32 as u32)) )
     );
 }
 
@@ -748,7 +2336,7 @@ fn test_operand_relative_neg() {
 fn test_operand_label() {
     assert_eq!(
         operand( &b"FooFunction"[..] ),
-        IResult::Done( &b""[..], AsmOperand::Label("FooFunction".into(), 0) )
+        IResult::Done( &b""[..], AsmOperand::Label("FooFunction".into(), Expr::Num(0)) )
     );
 }
 
@@ -770,15 +2358,338 @@ fn test_asm_block_bytes_le_size12_wordsize3() {
 }
 
 #[test]
-fn test_blocks_to_instructions_bytes_le_size12() {
+fn test_chunks_to_object_bytes_le_size12() {
     let block = AsmBlock::Bytes(AsmEndianness::Little,
                                 b"\x08\x00\x00\x00confirm ".to_vec());
 
-    let program = vec![("".into(), vec![block])];
+    let program = vec![Chunk {
+        label: "".into(),
+        source: SourceLine::new(None, 1),
+        section: DEFAULT_SECTION.into(),
+        blocks: vec![(SourceLine::new(None, 1), block)],
+    }];
+
+    let object = chunks_to_object(&program[..], "").unwrap();
+
+    assert_eq!(&object.sections[0].words[..], &[0x8, 0x666e6f63, 0x206d7269]);
+}
+
+#[test]
+fn test_assemble_with_symbols() {
+    let mut out = vec![];
+
+    let (_, symbols) = assemble_with_symbols(FIBONACCI_CODE, &mut out).unwrap();
+
+    assert_eq!(symbols.get("fibonacci"), Some(0));
+    assert_eq!(symbols.get("fibonacci.ret"), Some(23));
+    assert_eq!(symbols.get("fibonacci.bad"), Some(24));
+
+    let line = |addr| symbols.line(addr).map(|source| source.line);
+
+    assert_eq!(line(0), Some(4)); // cmp a [0]
+    assert_eq!(line(1), Some(5)); // branchl [fibonacci.bad]
+    assert_eq!(line(2), Some(5));
+    assert_eq!(line(23), Some(25)); // ret
+    assert_eq!(line(24), Some(27)); // bad
+}
+
+/// Each error from assembling `code`, with the line it's on
+#[cfg(test)]
+fn errors(code: &[u8]) -> Vec<String> {
+    assemble(code, &mut vec![]).unwrap_err().0.iter()
+        .map(|e| format!("{}: {}", e.source.as_ref().unwrap(), e.message))
+        .collect()
+}
+
+#[test]
+fn test_duplicate_label() {
+    assert_eq!(errors(b"foo:\n    nop\nfoo:\n    nop\n"),
+               ["line 3: duplicate label foo (first defined at line 1)"]);
+}
+
+#[test]
+fn test_missing_label() {
+    assert_eq!(errors(b"    nop\n    branch [nowhere]\n"),
+               ["line 2: label not found: nowhere"]);
+}
+
+#[test]
+fn test_multiple_errors() {
+    let code = b"start:\n    frob a [2]\n    branch [nowhere]\n    set e [1]\n    add a [0x123456789]\n";
+    let errors = assemble(code, &mut vec![]).unwrap_err().0;
+
+    let kinds: Vec<_> = errors.iter().map(|e| e.kind.clone()).collect();
+    assert_eq!(kinds, [AsmErrorKind::UnknownMnemonic("frob".to_string()),
+                       AsmErrorKind::UnknownLabel("nowhere".to_string()),
+                       AsmErrorKind::BadRegister("e".to_string()),
+                       AsmErrorKind::ConstantOutOfRange("0x123456789".to_string())]);
+
+    assert_eq!(errors[2].column, Some(9));
+    assert_eq!(errors[2].to_string(), "line 4, column 9: bad register: e\n        set e [1]\n            ^");
+}
+
+#[test]
+fn test_include_relative_to_file() {
+    let mut out = vec![];
+
+    let (_, symbols) = assemble_files(&["asm_examples/debug.fai"], &mut out).unwrap();
+
+    let find_device = symbols.get("FindDevice").unwrap();
+
+    assert_eq!(symbols.line(find_device),
+               Some(&SourceLine::new(Some("asm_examples/lib/find_device.fai".into()), 18)));
+}
+
+#[test]
+fn test_sections_and_absolute_words() {
+    let mut out = vec![];
+
+    let (_, symbols) = assemble_with_symbols(b"
+        .section data
+    Table:
+        .words {Start, Table + 1}
+
+        .section text
+    Start:
+        load a [Table]
+        halt
+    ", &mut out).unwrap();
+
+    // Sections are laid out in the order they first appear
+    assert_eq!(symbols.get("Table"), Some(0));
+    assert_eq!(symbols.get("Start"), Some(2));
+    assert_eq!(out, vec![2, 1, 0x0020_0003, (-2i32) as u32, 0x0040_001c]);
+}
+
+#[test]
+fn test_import_without_definition() {
+    let object = assemble_object(b".import Foo\n    call [Foo]\n").unwrap();
+
+    assert_eq!(object.imports, vec!["Foo".to_owned()]);
+    assert_eq!(object.sections[0].relocations, vec![Relocation {
+        offset: 1,
+        kind: RelocationKind::Relative,
+        target: RelocationTarget::Symbol("Foo".into()),
+        addend: 1,
+    }]);
+}
+
+#[test]
+fn test_macro() {
+    let mut out = vec![];
+
+    assemble(br"
+    .macro swap x, y ; exchange two registers
+        push \x
+        set \x [\y]
+        pop \y
+    .endm
+
+        swap a, b
+        swap c, d
+    ", &mut out).unwrap();
+
+    let mut expected = vec![];
+
+    assemble(b"
+        push a
+        set a [b]
+        pop b
+        push c
+        set c [d]
+        pop d
+    ", &mut expected).unwrap();
+
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn test_macro_local_labels() {
+    let mut out = vec![];
+
+    let (_, symbols) = assemble_with_symbols(br"
+    .macro countdown reg
+    loop\@:
+        sub \reg [1]
+        jmpnz \reg, loop\@
+    .endm
+
+    Start:
+        countdown a
+        countdown b
+    End:
+        halt
+    ", &mut out).unwrap();
+
+    assert_eq!(symbols.get("loop1"), Some(0));
+    assert_eq!(symbols.get("loop3"), Some(5)); // the jmpnz in between is expansion 2
+    assert_eq!(symbols.get("End"), Some(10));
+    assert_eq!(symbols.line(5).map(|source| source.line), Some(10));
+}
+
+#[test]
+fn test_builtin_macros() {
+    let mut out = vec![];
+    let mut expected = vec![];
+
+    assemble(b"pushall\njmpz c, $ + 8\npopall\n", &mut out).unwrap();
+    assemble(b"push a\npush b\npush c\npush d\ncmp c [0]\nbranche [$ + 8]\n\
+               pop d\npop c\npop b\npop a\n", &mut expected).unwrap();
+
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn test_macro_errors() {
+    assert_eq!(errors(b"    frobnicate a\n"),
+               ["line 1: unknown instruction or macro: frobnicate"]);
+
+    assert_eq!(errors(b"    jmpz a\n"),
+               ["line 1: macro jmpz takes 2 argument(s), but was given 1"]);
+
+    assert_eq!(errors(b".macro m\n.endm\n.macro m\n.endm\n"),
+               ["line 3: macro m is already defined at line 1"]);
+
+    // Built-ins can be replaced though
+    assert!(assemble(b".macro pushall\n    push a\n.endm\n    pushall\n", &mut vec![]).is_ok());
+}
 
+#[test]
+fn test_equ() {
     let mut out = vec![];
+    let mut expected = vec![];
+
+    assemble(b"
+    .equ BASE 0x80000
+    .define WIDTH, 40
+    .equ SIZE (WIDTH * 20) / 4
+    .equ END BASE + SIZE
+
+        set a [BASE + 180]
+        cmp a [END]
+        and a [~SIZE]
+        set b [table + WIDTH]
+    table:
+        .words {SIZE, END, table, DeviceModel.Monitor}
+    ", &mut out).unwrap();
+
+    assemble(b"
+        set a [0x80000 + 180]
+        cmp a [0x800c8]
+        and a [~200]
+        set b [table + 40]
+    table:
+        .words {200, 0x800c8, table, 0x384c0001}
+    ", &mut expected).unwrap();
+
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn test_equ_errors() {
+    assert_eq!(errors(b".equ A B * 2\n.equ B 1\n"),
+               ["line 1: B isn't a constant"]);
 
-    blocks_to_instructions(&program[..], &mut out).unwrap();
+    assert_eq!(errors(b".equ A 1\n.equ A 2\n"),
+               ["line 2: constant A is already defined at line 1"]);
 
-    assert_eq!(&out[..], &[0x8, 0x666e6f63, 0x206d7269]);
+    assert_eq!(errors(b".equ A 1\nA:\n    halt\n"),
+               ["line 2: can't define label A, it's a constant at line 1"]);
+
+    assert_eq!(errors(b".equ A 1 / 0\n"),
+               ["line 1: division by zero"]);
+}
+
+#[test]
+fn test_label_expressions() {
+    let image = assemble_at(b"
+    start:
+        set a [table.end - table]
+        set b [(table.end - table) * 2]
+        set c [table.end - table.end]
+        halt
+    table:
+        .words {start, table.end, table + 1, table.end - start}
+    table.end:
+    ", 0x11000).unwrap();
+
+    assert_eq!(image.base, 0x11000);
+    assert_eq!(image.words[1], 4);
+    assert_eq!(image.words[3], 8);
+
+    // Zero, but still two words since it couldn't be known before labels were placed
+    assert_eq!(image.words[5], 0);
+    assert_eq!(&image.words[7..], &[0x11000, 0x1100b, 0x11008, 11]);
+}
+
+#[test]
+fn test_org() {
+    let image = assemble_at(b"
+        .org 0x12000
+    handlers:
+        .words {handler_a, handler_b}
+    handler_a:
+        ret
+    handler_b:
+        halt
+    ", 0x11000).unwrap();
+
+    assert_eq!(image.base, 0x12000);
+    assert_eq!(&image.words[..2], &[0x12002, 0x12003]);
+
+    assert_eq!(errors(b"    halt\n    .org 0x12000\n"),
+               ["line 2: .org has to come before anything else in section text"]);
+}
+
+#[test]
+fn test_label_expression_errors() {
+    assert_eq!(errors(b"a:\n    .words {a * 2}\n"),
+               ["line 2: expression isn't a constant or a single address"]);
+
+    assert_eq!(errors(b"a:\n    .words {a / 2}\n"),
+               ["line 2: labels can only be added, subtracted or multiplied by a constant"]);
+
+    assert_eq!(errors(b"    set a [foo - 1]\n"),
+               ["line 1: label not found: foo"]);
+}
+
+#[test]
+fn test_lint() {
+    let code = br"
+start:
+    set a [1]
+    trace
+    divmod a [3]
+    divmod c [3]
+    divmods b [3]
+    branch b [start]
+    push a [4]
+unused:
+    ret
+    nop
+    halt
+    nop
+wait:
+    halt
+    branch [wait]
+";
+
+    let warnings: Vec<_> = lint(code, Lint::all()).unwrap().iter()
+        .map(|w| (w.kind.clone(), w.source.as_ref().unwrap().line))
+        .collect();
+
+    assert_eq!(warnings, [
+        (AsmErrorKind::Lint(Lint::Trace), 4),
+        (AsmErrorKind::Lint(Lint::DivMod), 5),
+        (AsmErrorKind::Lint(Lint::DivMod), 7),
+        (AsmErrorKind::Lint(Lint::IgnoredRegister), 8),
+        (AsmErrorKind::Lint(Lint::Unreachable), 9),
+        (AsmErrorKind::Lint(Lint::IgnoredOperand), 9),
+        (AsmErrorKind::Lint(Lint::UnusedLabel), 10),
+        (AsmErrorKind::Lint(Lint::Unreachable), 12),
+        (AsmErrorKind::Lint(Lint::Unreachable), 14),
+    ]);
+
+    assert_eq!(lint(code, &[Lint::Trace]).unwrap().len(), 1);
+    assert!(lint(b"    frob\n", Lint::all()).is_err());
 }
@@ -6,6 +6,7 @@ extern crate byteorder;
 extern crate getopts;
 
 use std::env;
+use std::collections::BTreeMap;
 use std::process::exit;
 use std::fs::File;
 use std::io;
@@ -14,19 +15,32 @@ use std::io::prelude::*;
 use getopts::Options;
 use byteorder::*;
 
-use fai::assemble::assemble;
-use fai::bitcode;
+use fai::assemble::{assemble_at, assemble_files_at};
+use fai::assemble::{assemble_object, assemble_object_files};
+use fai::assemble::{lint, lint_files, Lint};
+use fai::disassemble::disassemble;
+use fai::object::Image;
+use fai::symbols::{SymbolTable, SourceLine};
+
+/// How many words to put on one line of a listing
+const WORDS_PER_ROW: usize = 3;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum OutputFormat {
     Pretty,
     PlainText,
     Binary,
+    Object,
 }
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [file.fai] [options]", program);
+    let brief = format!("Usage: {} [file.fai...] [options]\n\n\
+                         Multiple files are assembled into one image, in order, and can refer \
+                         to each other's labels.", program);
     print!("{}", opts.usage(&brief));
+
+    let names: Vec<_> = Lint::all().iter().map(|lint| lint.name()).collect();
+    println!("\nWarnings: {}", names.join(", "));
 }
 
 fn main() {
@@ -39,11 +53,28 @@ fn main() {
 
     opts.optflag("h", "help", "Show this message");
 
-    opts.optopt("f", "format", "Choices: pretty, plaintext, binary. \
+    opts.optopt("f", "format", "Choices: pretty, plaintext, binary, object. \
+                                Objects can be linked with `link`. \
                                 Default: pretty", "FORMAT");
 
     opts.optopt("o", "out", "File to write the result to, instead of stdout", "FILE");
 
+    opts.optopt("s", "symbols", "Also write labels and a line table to FILE, \
+                                 for the debugger. Objects have their own, which `link` \
+                                 can write instead", "FILE");
+
+    opts.optopt("l", "listing", "Also write a listing to FILE: each line of source with its \
+                                 address and the words it assembled to, then the labels", "FILE");
+
+    opts.optopt("", "base", "Address (in hex) that the image will be loaded at, which labels \
+                             used as addresses are relative to. An .org in the code overrides \
+                             it. Default: 11000", "ADDR");
+
+    opts.optmulti("A", "allow", "Don't warn about LINT. Can be given more than once, or as \
+                                 `all`. See below for the choices", "LINT");
+
+    opts.optflag("", "deny-warnings", "Fail if there are any warnings");
+
     let matches = opts.parse(&args[1..]).unwrap();
 
     if matches.opt_present("help") {
@@ -55,86 +86,205 @@ fn main() {
         "pretty"    => OutputFormat::Pretty,
         "plaintext" => OutputFormat::PlainText,
         "binary"    => OutputFormat::Binary,
+        "object"    => OutputFormat::Object,
         x           => panic!("Invalid value provided for --format: {}", x)
     }).unwrap_or(OutputFormat::Pretty);
 
-    let mut buffer = vec![];
+    let mut out_stream: Box<Write> = match matches.opt_str("out") {
+        Some(s) => Box::new(File::create(&s).unwrap()),
+        None    => Box::new(io::stdout())
+    };
+
+    let stdin = if matches.free.is_empty() {
+        let mut buffer = vec![];
 
-    match matches.free.len() {
-        0 => {
-            io::stdin().read_to_end(&mut buffer).unwrap();
-        },
-        1 => {
-            let mut f = File::open(&matches.free[0]).unwrap();
-            f.read_to_end(&mut buffer).unwrap();
-        },
-        _ => {
-            print_usage(&program, opts);
-            exit(1);
+        io::stdin().read_to_end(&mut buffer).unwrap();
+
+        Some(buffer)
+    } else {
+        None
+    };
+
+    let allowed = matches.opt_strs("allow");
+
+    let lints: Vec<Lint> = if allowed.iter().any(|name| name == "all") {
+        vec![]
+    } else {
+        for name in &allowed {
+            if Lint::from_name(name).is_none() {
+                panic!("Invalid value provided for --allow: {}", name);
+            }
+        }
+
+        Lint::all().iter().cloned()
+            .filter(|lint| !allowed.iter().any(|name| name == lint.name()))
+            .collect()
+    };
+
+    if !lints.is_empty() {
+        let result = match stdin {
+            Some(ref buffer) => lint(buffer, &lints),
+            None => lint_files(&matches.free, &lints)
+        };
+
+        match result {
+            Ok(ref warnings) if !warnings.is_empty() => {
+                for warning in warnings {
+                    writeln!(io::stderr(), "{}", warning).unwrap();
+                }
+
+                if matches.opt_present("deny-warnings") {
+                    exit(1);
+                }
+            },
+            // Errors are reported when assembling
+            _ => ()
         }
     }
 
-    let mut bitcode = vec![];
+    if format == OutputFormat::Object && matches.opt_present("listing") {
+        writeln!(io::stderr(), "Objects aren't at any address yet, so can't have a listing")
+            .unwrap();
+        exit(1);
+    }
 
-    assemble(&buffer, &mut bitcode).unwrap();
+    if format == OutputFormat::Object {
+        let result = if let Some(ref buffer) = stdin {
+            assemble_object(buffer)
+        } else {
+            assemble_object_files(&matches.free)
+        };
+
+        match result {
+            Ok(object) => object.write(&mut out_stream).unwrap(),
+            Err(e) => {
+                writeln!(io::stderr(), "{}", e).unwrap();
+                exit(1);
+            }
+        }
 
-    let mut out_stream: Box<Write> = match matches.opt_str("out") {
-        Some(s) => Box::new(File::create(&s).unwrap()),
-        None    => Box::new(io::stdout())
+        return;
+    }
+
+    let base = matches.opt_str("base")
+        .map(|s| u32::from_str_radix(&s, 16).unwrap())
+        .unwrap_or(0x11000);
+
+    let result = if let Some(ref buffer) = stdin {
+        assemble_at(buffer, base)
+    } else {
+        assemble_files_at(&matches.free, base)
+    };
+
+    let (bitcode, symbols) = match result {
+        Ok(image) => {
+            if image.base != base {
+                writeln!(io::stderr(), "Image is to be loaded at {:x}", image.base).unwrap();
+            }
+
+            if let Some(path) = matches.opt_str("listing") {
+                let stdin = stdin.as_ref().map(|buffer| &buffer[..]);
+
+                output_listing(&image, stdin, File::create(&path).unwrap()).unwrap();
+            }
+
+            (image.words, image.symbols)
+        },
+        Err(e) => {
+            writeln!(io::stderr(), "{}", e).unwrap();
+            exit(1);
+        }
     };
 
+    if let Some(path) = matches.opt_str("symbols") {
+        symbols.write(File::create(&path).unwrap()).unwrap();
+    }
+
     match format {
-        OutputFormat::Pretty    => output_pretty(&bitcode, &mut out_stream),
+        OutputFormat::Pretty    => output_pretty(&bitcode, &symbols, &mut out_stream),
         OutputFormat::PlainText => output_plain_text(&bitcode, &mut out_stream),
         OutputFormat::Binary    => output_binary(&bitcode, &mut out_stream),
+        OutputFormat::Object    => unreachable!(),
     }.unwrap()
 }
 
-fn output_pretty<W: Write>(bitcode: &[u32], mut out_stream: W) -> io::Result<()> {
-    let mut current_ptr = 0;
-
-    let mut buf = vec![];
+fn output_pretty<W: Write>(bitcode: &[u32], symbols: &SymbolTable, out_stream: W)
+                          -> io::Result<()> {
+    disassemble(bitcode, symbols, true, out_stream)
+}
 
-    while current_ptr < bitcode.len() {
-        write!(out_stream, "{:08x}    ", current_ptr)?;
+/// `stdin` is the code if it wasn't read from a file
+fn output_listing<W: Write>(image: &Image, stdin: Option<&[u8]>, mut out_stream: W)
+                           -> io::Result<()> {
+    let entries: Vec<(u32, &SourceLine)> = image.symbols.lines().collect();
 
-        buf.truncate(0);
-        buf.push(bitcode[current_ptr]);
+    // The line table only says where each line starts. Its words go up to the next one.
+    let mut line_words: BTreeMap<&SourceLine, Vec<u32>> = BTreeMap::new();
+    let mut files: Vec<&Option<String>> = vec![];
 
-        let mut inst = None;
+    for (index, &(start, line)) in entries.iter().enumerate() {
+        let end = entries.get(index + 1).map(|&(next, _)| next)
+            .unwrap_or(image.words.len() as u32);
 
-        loop {
-            match bitcode::decode_instruction(&buf) {
-                Ok(i) => {
-                    inst = Some(i);
-                    break;
-                },
-                Err(bitcode::DecodeError::NeedMore) => {
-                    current_ptr += 1;
+        line_words.entry(line).or_insert_with(Vec::new).extend(start..end);
 
-                    if current_ptr >= bitcode.len() {
-                        break;
-                    } else {
-                        buf.push(bitcode[current_ptr]);
-                    }
-                },
-            }
+        if !files.contains(&&line.file) {
+            files.push(&line.file);
         }
+    }
 
-        for word in &buf {
-            write!(out_stream, " {:08x}", *word)?;
-        }
+    let row = |offsets: &[u32]| -> String {
+        let words: Vec<String> = offsets.iter()
+            .map(|&offset| format!("{:08x}", image.words[offset as usize]))
+            .collect();
+
+        format!("{:08x}  {:<width$}", image.base.wrapping_add(offsets[0]), words.join(" "),
+                width = WORDS_PER_ROW * 9)
+    };
+
+    for file in files {
+        let code = match *file {
+            Some(ref path) => {
+                let mut code = vec![];
+
+                if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut code)) {
+                    writeln!(out_stream, "; {}: {}\n", path, e)?;
+                    continue;
+                }
+
+                code
+            },
+            None => stdin.unwrap_or(&[]).to_vec()
+        };
 
-        if let Some(inst) = inst {
-            if buf.len() < 2 {
-                write!(out_stream, " {:8}", "")?;
+        writeln!(out_stream, "; {}", file.as_ref().map(|s| &s[..]).unwrap_or("<stdin>"))?;
+
+        for (index, text) in String::from_utf8_lossy(&code).lines().enumerate() {
+            let line = SourceLine::new(file.clone(), index as u32 + 1);
+
+            let offsets = line_words.get(&line).map(|offsets| &offsets[..]).unwrap_or(&[]);
+            let mut rows = offsets.chunks(WORDS_PER_ROW);
+
+            let first = rows.next().map(&row)
+                .unwrap_or_else(|| format!("{:width$}", "", width = 10 + WORDS_PER_ROW * 9));
+
+            writeln!(out_stream, "{}", format!("{}{:5}  {}", first, line.line, text).trim_end())?;
+
+            for offsets in rows {
+                writeln!(out_stream, "{}", row(offsets).trim_end())?;
             }
-            write!(out_stream, "    {:?}", inst)?;
         }
 
         writeln!(out_stream)?;
+    }
+
+    let mut labels: Vec<(&str, u32)> = image.symbols.iter().collect();
+    labels.sort_by_key(|&(label, offset)| (offset, label));
+
+    writeln!(out_stream, "; Labels")?;
 
-        current_ptr += 1;
+    for (label, offset) in labels {
+        writeln!(out_stream, "{:08x}  {}", image.base.wrapping_add(offset), label)?;
     }
 
     Ok(())
@@ -0,0 +1,87 @@
+extern crate fai;
+
+extern crate env_logger;
+
+extern crate byteorder;
+extern crate getopts;
+
+use std::env;
+use std::process::exit;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+
+use getopts::Options;
+use byteorder::*;
+
+use fai::disassemble::disassemble;
+use fai::symbols::SymbolTable;
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} [file.bin] [options]", program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    env_logger::init().unwrap();
+
+    let mut opts = Options::new();
+
+    opts.optflag("h", "help", "Show this message");
+
+    opts.optopt("o", "out", "File to write the result to, instead of stdout", "FILE");
+
+    opts.optopt("s", "symbols", "Symbol file (from the assembler's --symbols) to take labels \
+                                 from", "FILE");
+
+    opts.optflag("l", "listing", "Comment each line with its offset and encoding");
+
+    let matches = opts.parse(&args[1..]).unwrap();
+
+    if matches.opt_present("help") {
+        print_usage(&program, opts);
+        return;
+    }
+
+    let mut buffer = vec![];
+
+    match matches.free.len() {
+        0 => {
+            io::stdin().read_to_end(&mut buffer).unwrap();
+        },
+        1 => {
+            let mut f = File::open(&matches.free[0]).unwrap();
+            f.read_to_end(&mut buffer).unwrap();
+        },
+        _ => {
+            print_usage(&program, opts);
+            exit(1);
+        }
+    }
+
+    if buffer.len() % 4 != 0 {
+        writeln!(io::stderr(), "Warning: image is not a whole number of words, \
+                                ignoring the last {} bytes", buffer.len() % 4).unwrap();
+    }
+
+    let image: Vec<u32> = buffer.chunks(4)
+        .filter(|chunk| chunk.len() == 4)
+        .map(|chunk| LittleEndian::read_u32(chunk))
+        .collect();
+
+    let symbols = matches.opt_str("symbols").map(|path| {
+        let file = File::open(path).unwrap();
+        SymbolTable::read(BufReader::new(file)).unwrap()
+    }).unwrap_or_default();
+
+    let out_stream: Box<Write> = match matches.opt_str("out") {
+        Some(s) => Box::new(File::create(&s).unwrap()),
+        None    => Box::new(io::stdout())
+    };
+
+    disassemble(&image, &symbols, matches.opt_present("listing"), out_stream).unwrap();
+}
@@ -4,24 +4,42 @@ extern crate env_logger;
 
 extern crate getopts;
 extern crate byteorder;
+extern crate termion;
 
 use std::env;
+use std::io;
+use std::io::BufReader;
 use std::io::prelude::*;
 use std::fs::File;
 use std::process::exit;
 use std::time::Duration;
 use std::str::FromStr;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::mpsc::channel;
+use std::thread;
 
 use getopts::Options;
 use byteorder::*;
+use termion::raw::IntoRawMode;
 
 use fai::data::State;
 use fai::machine::Machine;
 use fai::event_pool::EventPool;
 use fai::ram::Ram;
-use fai::stdio_console::StdioConsole;
+use fai::stdio_console::{self, StdioConsole};
+use fai::monitor::Monitor;
+use fai::keyboard::Keyboard;
+use fai::terminal_renderer;
+use fai::timer::Timer;
+use fai::disk::Disk;
+use fai::uart::Uart;
 use fai::hardware::HardwareMessage;
 use fai::device::{DeviceConfig, DeviceModel};
+use fai::debugger::Debugger;
+use fai::symbols::SymbolTable;
+use fai::snapshot;
+use fai::input_log::{InputRecorder, Recording, InputLog, InputSource};
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} <file.bin> [options]", program);
@@ -44,6 +62,9 @@ fn main() {
     opts.optopt("", "load-address", "Address (in hex) to write the program to in memory.
                                      Default: 11000", "ADDR");
 
+    opts.optopt("", "entry", "Address (in hex) to start executing at, e.g. if `link` put data \
+                              before the code. Default: the load address", "ADDR");
+
     opts.optopt("", "stack-pointer", "Address (in hex) to start the stack at.
                                       Default: 10e00", "ADDR");
 
@@ -51,6 +72,38 @@ fn main() {
                                   RAM will be mounted at 10000. \
                                   Default: 2000", "WORDS");
 
+    opts.optopt("", "disk", "Attach a disk backed by the image FILE, which is read and \
+                             written in place", "FILE");
+
+    opts.optmulti("", "uart", "Attach a UART connected to SPEC, which is stdio, file:IN,OUT, \
+                               tcp:PORT (on localhost) or pty. Can be given more than once. \
+                               They're mapped at 8e00, 8e10, ... and interrupt with \
+                               ffff0006, ffff0007, ...", "SPEC");
+
+    opts.optflag("", "monitor", "Attach a monitor and keyboard instead of the debug console. \
+                                 The monitor's text mode is drawn in the terminal, and keys go \
+                                 to the keyboard");
+
+    opts.optopt("", "capture", "Save the monitor's screen to FILE (.ppm or .png) on exit, which \
+                                without --debug is when the machine halts. The program can \
+                                save it too through the monitor's capture register, with any \
+                                {} in FILE replaced by the value written. Needs --monitor",
+                "FILE");
+
+    opts.optflag("d", "debug", "Start in the interactive debugger");
+
+    opts.optopt("", "load-state", "Resume from a snapshot saved by the debugger. \
+                                   The other options must match those it was saved with", "FILE");
+
+    opts.optopt("s", "symbols", "Symbol file (from the assembler's --symbols) to use in the debugger. \
+                                 Offsets are relative to the load address", "FILE");
+
+    opts.optopt("", "record", "Record console input to FILE, so that the session can be \
+                               replayed exactly with --replay", "FILE");
+
+    opts.optopt("", "replay", "Feed console input from a log made with --record instead of \
+                               stdin", "FILE");
+
     let matches = opts.parse(&args[1..]).unwrap();
 
     if matches.opt_present("help") {
@@ -108,29 +161,148 @@ fn main() {
     let mut default_state = State::default();
 
     default_state.sp = stack_pointer;
-    default_state.ip = load_address;
+    default_state.ip = u32_hex_option(matches.opt_str("entry"), load_address);
+
+    let debug = matches.opt_present("debug");
+
+    let symbols = matches.opt_str("symbols").map(|path| {
+        let file = File::open(path).unwrap();
+        SymbolTable::read(BufReader::new(file)).unwrap().relocate(load_address)
+    });
+
+    let machine = Rc::new(RefCell::new(Machine::new(default_state)));
+
+    let recorder = matches.opt_str("record").map(|path| {
+        InputRecorder::new(File::create(path).unwrap()).unwrap()
+    });
+
+    let replay_log = matches.opt_str("replay").map(|path| {
+        InputLog::read(BufReader::new(File::open(path).unwrap())).unwrap()
+    });
+
+    // Opened early, so that where to connect to them can be shown before the terminal is taken
+    let uarts: Vec<Uart> = matches.opt_strs("uart").iter().map(|spec| {
+        Uart::open(spec).unwrap_or_else(|e| {
+            writeln!(io::stderr(), "Couldn't open UART {}: {}", spec, e).unwrap();
+            exit(1);
+        })
+    }).collect();
 
-    let machine = Machine::new(default_state);
+    for (index, uart) in uarts.iter().enumerate() {
+        writeln!(io::stderr(), "UART at {:x}: {}", 0x8e00 + 0x10 * index, uart.host_end()).unwrap();
+    }
+
+    let disk_image = matches.opt_str("disk").map(|path| {
+        Disk::open(&path).unwrap_or_else(|e| {
+            writeln!(io::stderr(), "Couldn't open disk image {}: {}", path, e).unwrap();
+            exit(1);
+        })
+    });
 
-    let stdio_console = StdioConsole::new();
+    let monitor = matches.opt_present("monitor");
+    let capture_path = matches.opt_str("capture");
+
+    if capture_path.is_some() && !monitor {
+        writeln!(io::stderr(), "--capture needs --monitor").unwrap();
+        exit(1);
+    }
+
+    // Whichever device gets the input
+    let input_channel = if monitor { "keyboard" } else { "console" };
+
+    // The debugger owns stdin, and passes it through to the console while the machine runs
+    let (console_tx, console_rx) = channel();
+
+    let input: Option<Box<InputSource>> = if let Some(ref log) = replay_log {
+        Some(Box::new(log.replay(input_channel)))
+    } else if let Some(ref recorder) = recorder {
+        let input = if debug { console_rx } else { stdio_console::spawn_stdin_reader() };
+
+        Some(Box::new(Recording::new(input, input_channel, recorder.clone())))
+    } else if debug {
+        Some(Box::new(console_rx))
+    } else if monitor {
+        Some(Box::new(stdio_console::spawn_stdin_reader()))
+    } else {
+        // The console reads stdin itself
+        None
+    };
+
+    // Unless the console does it itself
+    let _terminal = if debug || recorder.is_some() || monitor {
+        Some(io::stdout().into_raw_mode().unwrap())
+    } else {
+        None
+    };
 
     let mut pool = EventPool::new();
 
-    let machine_id = pool.add_hardware(machine);
+    let machine_id = pool.add_hardware(machine.clone());
     let ram_id     = pool.add_hardware(ram);
-    let console_id = pool.add_hardware(stdio_console);
 
-    pool.connect(machine_id, ram_id);
-    pool.connect(machine_id, console_id);
+    let mut configs = vec![];
 
-    let configs = vec![
-        DeviceConfig {
+    let mut screen = None;
+
+    if monitor {
+        let (update_tx, update_rx) = channel();
+
+        let mut device = Monitor::new(update_tx);
+
+        if let Some(ref path) = capture_path {
+            device = device.with_capture_path(path);
+        }
+
+        let device = Rc::new(RefCell::new(device));
+        screen = Some(device.clone());
+
+        let monitor_id  = pool.add_hardware(device);
+        let keyboard_id = pool.add_hardware(Keyboard::with_source(input.unwrap()));
+
+        pool.connect(machine_id, monitor_id);
+        pool.connect(machine_id, keyboard_id);
+
+        configs.push(DeviceConfig {
+            id: monitor_id,
+            model: DeviceModel::Monitor.number(),
+            interrupt: 0xffff_0001,
+            memmap_base: 0x80000,
+            memmap_size: DeviceModel::Monitor.memory_size().unwrap()
+        });
+        configs.push(DeviceConfig {
+            id: keyboard_id,
+            model: DeviceModel::Keyboard.number(),
+            interrupt: 0xffff_0005,
+            memmap_base: 0x8a00,
+            memmap_size: DeviceModel::Keyboard.memory_size().unwrap()
+        });
+
+        terminal_renderer::spawn(update_rx);
+    } else {
+        let stdio_console = match input {
+            Some(input) => StdioConsole::with_source(input),
+            None => StdioConsole::new()
+        };
+
+        let console_id = pool.add_hardware(stdio_console);
+
+        pool.connect(machine_id, console_id);
+
+        configs.push(DeviceConfig {
             id: console_id,
             model: DeviceModel::DebugConsole.number(),
             interrupt: 0xffff_0001,
             memmap_base: 0x8c00,
             memmap_size: DeviceModel::DebugConsole.memory_size().unwrap()
-        },
+        });
+    }
+
+    let timer_id = pool.add_hardware(Timer::new());
+
+    pool.connect(machine_id, ram_id);
+    pool.connect(machine_id, timer_id);
+
+    configs.extend(vec![
         DeviceConfig {
             id: ram_id,
             model: DeviceModel::Ram.number(),
@@ -138,9 +310,85 @@ fn main() {
             memmap_base: 0x10000,
             memmap_size: ram_size
         },
-    ];
+        DeviceConfig {
+            id: timer_id,
+            model: DeviceModel::Timer.number(),
+            interrupt: 0xffff_0003,
+            memmap_base: 0x8b00,
+            memmap_size: DeviceModel::Timer.memory_size().unwrap()
+        },
+    ]);
+
+    if let Some((image, sectors)) = disk_image {
+        let disk_id = pool.add_hardware(Disk::new(image, sectors, ram_id, 0x10000));
+
+        pool.connect(machine_id, disk_id);
+        pool.connect(disk_id, ram_id);
+
+        configs.push(DeviceConfig {
+            id: disk_id,
+            model: DeviceModel::Disk.number(),
+            interrupt: 0xffff_0004,
+            memmap_base: 0x8d00,
+            memmap_size: DeviceModel::Disk.memory_size().unwrap()
+        });
+    }
+
+    for (index, uart) in uarts.into_iter().enumerate() {
+        let uart_id = pool.add_hardware(uart);
+
+        pool.connect(machine_id, uart_id);
 
-    pool.dispatch().send(HardwareMessage::InitializeMachine(machine_id, configs));
+        configs.push(DeviceConfig {
+            id: uart_id,
+            model: DeviceModel::Uart.number(),
+            interrupt: 0xffff_0006 + index as u32,
+            memmap_base: 0x8e00 + 0x10 * index as u32,
+            memmap_size: DeviceModel::Uart.memory_size().unwrap()
+        });
+    }
+
+    if let Some(path) = matches.opt_str("load-state") {
+        snapshot::restore(&mut pool, File::open(path).unwrap()).unwrap();
+    } else {
+        pool.dispatch().send(HardwareMessage::InitializeMachine(machine_id, configs));
+    }
+
+    if debug {
+        let mut debugger = Debugger::new(pool, machine, stdio_console::spawn_stdin_reader());
+
+        debugger.set_console(console_tx);
+        debugger.set_tick_duration(tick_dur);
+
+        if let Some(symbols) = symbols {
+            debugger.set_symbols(symbols);
+        }
+
+        debugger.run();
+    } else if capture_path.is_some() {
+        loop {
+            pool.tick();
+
+            let machine = machine.borrow();
 
-    pool.tick_real_clock(tick_dur);
+            if machine.state().fault.is_some() ||
+                machine.state().halt && !machine.has_pending_interrupts() && pool.is_quiet() {
+                break;
+            }
+
+            thread::sleep(tick_dur);
+        }
+    } else {
+        pool.tick_real_clock(tick_dur);
+    }
+
+    if let (Some(path), Some(screen)) = (capture_path, screen) {
+        if let Err(e) = screen.borrow().capture().save(&path) {
+            // exit() doesn't run destructors, so put the terminal back first
+            drop(_terminal);
+
+            writeln!(io::stderr(), "Couldn't save the screen to {}: {}", path, e).unwrap();
+            exit(1);
+        }
+    }
 }
@@ -0,0 +1,110 @@
+extern crate fai;
+
+extern crate env_logger;
+
+extern crate byteorder;
+extern crate getopts;
+
+use std::env;
+use std::process::exit;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::collections::BTreeMap;
+
+use getopts::Options;
+use byteorder::*;
+
+use fai::object::{Object, link};
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} [file.o...] [options]\n\n\
+                         Links objects made with `assemble --format object` into an image. \
+                         The image starts at the lowest section address, which is where it \
+                         should be loaded.", program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    env_logger::init().unwrap();
+
+    let mut opts = Options::new();
+
+    opts.optflag("h", "help", "Show this message");
+
+    opts.optopt("o", "out", "File to write the image to, instead of stdout", "FILE");
+
+    opts.optopt("s", "symbols", "Also write labels and a line table to FILE, \
+                                 for the debugger", "FILE");
+
+    opts.optmulti("", "section", "Place section NAME at ADDR (in hex), \
+                                  e.g. --section data=10000 --section text=11000", "NAME=ADDR");
+
+    opts.optopt("", "base", "Address (in hex) to place sections at if they aren't placed \
+                             with --section. Default: 11000", "ADDR");
+
+    let matches = opts.parse(&args[1..]).unwrap();
+
+    if matches.opt_present("help") || matches.free.is_empty() {
+        print_usage(&program, opts);
+        return;
+    }
+
+    let base = matches.opt_str("base")
+        .map(|s| u32::from_str_radix(&s, 16).unwrap())
+        .unwrap_or(0x11000);
+
+    let mut placements = BTreeMap::new();
+
+    for placement in matches.opt_strs("section") {
+        let mut parts = placement.splitn(2, '=');
+
+        let name = parts.next().unwrap();
+
+        let addr = parts.next().and_then(|s| u32::from_str_radix(s, 16).ok())
+            .unwrap_or_else(|| {
+                writeln!(io::stderr(), "bad --section {:?}, expected NAME=ADDR", placement)
+                    .unwrap();
+                exit(1);
+            });
+
+        placements.insert(name.to_owned(), addr);
+    }
+
+    let objects: Vec<Object> = matches.free.iter().map(|path| {
+        let file = File::open(path).unwrap();
+
+        Object::read(BufReader::new(file)).unwrap_or_else(|e| {
+            writeln!(io::stderr(), "{}: {}", path, e).unwrap();
+            exit(1);
+        })
+    }).collect();
+
+    let image = link(&objects, &placements, base).unwrap_or_else(|e| {
+        writeln!(io::stderr(), "{}", e).unwrap();
+        exit(1);
+    });
+
+    if let Some(path) = matches.opt_str("symbols") {
+        image.symbols.write(File::create(&path).unwrap()).unwrap();
+    }
+
+    let mut out_stream: Box<Write> = match matches.opt_str("out") {
+        Some(s) => Box::new(File::create(&s).unwrap()),
+        None    => Box::new(io::stdout())
+    };
+
+    let mut buffer = [0u8; 4];
+
+    for &word in &image.words {
+        LittleEndian::write_u32(&mut buffer, word);
+        out_stream.write_all(&buffer).unwrap();
+    }
+
+    writeln!(io::stderr(), "Image is {:#x} words, to be loaded at {:#x}",
+             image.words.len(), image.base).unwrap();
+}
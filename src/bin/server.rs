@@ -4,17 +4,24 @@ extern crate log;
 extern crate env_logger;
 extern crate websocket;
 extern crate byteorder;
+extern crate getopts;
 
 extern crate fai;
 
+use std::env;
+use std::io;
 use std::str;
 use std::thread;
+use std::process::exit;
 use std::sync::mpsc::{Sender, TryRecvError, channel};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::fs::File;
+use std::io::BufReader;
 use std::io::prelude::*;
+use std::path::PathBuf;
 
 use byteorder::*;
+use getopts::Options;
 
 use websocket::{Server, Message};
 use websocket::message::Type;
@@ -25,21 +32,80 @@ use fai::data::State;
 use fai::machine::Machine;
 use fai::event_pool::EventPool;
 use fai::ram::Ram;
-use fai::monitor::Monitor;
+use fai::monitor::{self, Monitor, MonitorUpdate};
 use fai::keyboard::Keyboard;
+use fai::timer::Timer;
+use fai::disk::Disk;
 use fai::hardware::HardwareMessage;
 use fai::device::{DeviceConfig, DeviceModel};
+use fai::input_log::{InputRecorder, Recording, InputLog};
 
-static PROTOCOL: &'static str = "v1.fai.devyn.me";
+// v2: the screen is sent as binary `MonitorUpdate`s instead of text "offset,word" messages
+static PROTOCOL: &'static str = "v2.fai.devyn.me";
+
+fn print_usage(program: &str, opts: Options) {
+    let brief = format!("Usage: {} [options]", program);
+    print!("{}", opts.usage(&brief));
+}
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
     env_logger::init().unwrap();
 
+    let mut opts = Options::new();
+
+    opts.optflag("h", "help", "Show this message");
+
+    opts.optopt("", "record", "Record the keyboard input of every session to a log in DIR, \
+                               which can be replayed with --replay", "DIR");
+
+    opts.optopt("", "replay", "Ignore keyboard input from clients, and instead feed every \
+                               session the input recorded in FILE", "FILE");
+
+    opts.optopt("", "frame-interval", &format!("Send the screen to clients every TICKS ticks, \
+                                                 as one binary frame. Default: {}",
+                                                monitor::DEFAULT_FRAME_INTERVAL), "TICKS");
+
+    opts.optopt("", "disk", "Attach a disk that starts out as the image FILE. Every session \
+                             gets its own copy, and writes aren't saved", "FILE");
+
+    let matches = opts.parse(&args[1..]).unwrap();
+
+    if matches.opt_present("help") {
+        print_usage(&program, opts);
+        return;
+    }
+
+    let record_dir = matches.opt_str("record").map(PathBuf::from);
+
+    if let Some(ref dir) = record_dir {
+        if !dir.is_dir() {
+            writeln!(io::stderr(), "--record: {} isn't a directory", dir.display()).unwrap();
+            exit(1);
+        }
+    }
+
+    let replay_log = matches.opt_str("replay").map(|path| {
+        InputLog::read(BufReader::new(File::open(path).unwrap())).unwrap()
+    });
+
+    let disk_path = matches.opt_str("disk").map(PathBuf::from);
+
+    let frame_interval = matches.opt_str("frame-interval")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(monitor::DEFAULT_FRAME_INTERVAL);
+
     let server = Server::bind("[::]:2391").unwrap();
 
     info!("Server listening on [::]:2391");
 
     for request in server.filter_map(Result::ok) {
+        let record_dir = record_dir.clone();
+        let replay_log = replay_log.clone();
+        let disk_path = disk_path.clone();
+
         thread::spawn(move || {
             if !request.protocols().contains(&PROTOCOL.into()) {
                 request.reject().unwrap();
@@ -52,7 +118,8 @@ fn main() {
 
             let (client_rx, client_tx) = client.split().unwrap();
 
-            handle_session(ip, client_rx, client_tx);
+            handle_session(ip, client_rx, client_tx, record_dir, replay_log, disk_path,
+                           frame_interval);
         });
     }
 }
@@ -62,7 +129,13 @@ enum ClientMsg {
     WsPing(Vec<u8>),
 }
 
-fn handle_session<R, W>(ip: String, client_rx: Reader<R>, mut client_tx: Writer<W>)
+fn handle_session<R, W>(ip: String,
+                        client_rx: Reader<R>,
+                        mut client_tx: Writer<W>,
+                        record_dir: Option<PathBuf>,
+                        replay_log: Option<InputLog>,
+                        disk_path: Option<PathBuf>,
+                        frame_interval: u64)
     where R: Read + Send + 'static, W: Write {
 
     let load_address  = 0x11000;
@@ -73,6 +146,43 @@ fn handle_session<R, W>(ip: String, client_rx: Reader<R>, mut client_tx: Writer<
 
     info!("Connection from {}", ip);
 
+    // A copy for each session, so that they can't see each other's writes
+    let disk_image = match disk_path.map(Disk::open_copy) {
+        Some(Ok(image)) => Some(image),
+        Some(Err(e)) => {
+            warn!("Couldn't read the disk image for {}: {}", ip, e);
+
+            let _ = client_tx.send_message(&Message::close_because(1011, "no disk image"));
+            return;
+        },
+        None => None
+    };
+
+    // Replayed sessions have nothing to record
+    let recorder = match record_dir {
+        Some(ref dir) if replay_log.is_none() => {
+            let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+            let path = dir.join(format!("session-{}-{}.log",
+                                        since_epoch.as_secs(),
+                                        ip.replace(|c: char| !c.is_alphanumeric(), "_")));
+
+            match File::create(&path).and_then(InputRecorder::new) {
+                Ok(recorder) => {
+                    info!("Recording input from {} to {}", ip, path.display());
+                    Some(recorder)
+                },
+                Err(e) => {
+                    warn!("Couldn't record input from {} to {}: {}", ip, path.display(), e);
+
+                    let _ = client_tx.send_message(&Message::close_because(1011, "can't record"));
+                    return;
+                }
+            }
+        },
+        _ => None
+    };
+
     let (client_msg_tx, client_msg_rx) = channel::<ClientMsg>();
 
     thread::spawn(move || {
@@ -101,11 +211,20 @@ fn handle_session<R, W>(ip: String, client_rx: Reader<R>, mut client_tx: Writer<
 
     let machine = Machine::new(default_state);
 
-    let (monitor_tx, monitor_rx) = channel::<(u32, u32)>();
-    let monitor                  = Monitor::new(monitor_tx);
+    let (monitor_tx, monitor_rx) = channel::<MonitorUpdate>();
+    let monitor                  = Monitor::new(monitor_tx).with_frame_interval(frame_interval);
 
     let (keyboard_tx, keyboard_rx) = channel::<u32>();
-    let keyboard                   = Keyboard::new(keyboard_rx);
+
+    let keyboard = if let Some(ref log) = replay_log {
+        info!("Replaying recorded input for {}", ip);
+
+        Keyboard::with_source(Box::new(log.replay("keyboard")))
+    } else if let Some(recorder) = recorder {
+        Keyboard::with_source(Box::new(Recording::new(keyboard_rx, "keyboard", recorder)))
+    } else {
+        Keyboard::new(keyboard_rx)
+    };
 
     let mut pool = EventPool::new();
 
@@ -113,12 +232,14 @@ fn handle_session<R, W>(ip: String, client_rx: Reader<R>, mut client_tx: Writer<
     let ram_id      = pool.add_hardware(ram);
     let monitor_id  = pool.add_hardware(monitor);
     let keyboard_id = pool.add_hardware(keyboard);
+    let timer_id    = pool.add_hardware(Timer::new());
 
     pool.connect(machine_id, ram_id);
     pool.connect(machine_id, monitor_id);
     pool.connect(machine_id, keyboard_id);
+    pool.connect(machine_id, timer_id);
 
-    let configs = vec![
+    let mut configs = vec![
         DeviceConfig {
             id: ram_id,
             model: DeviceModel::Ram.number(),
@@ -140,14 +261,37 @@ fn handle_session<R, W>(ip: String, client_rx: Reader<R>, mut client_tx: Writer<
             memmap_base: 0x8a00,
             memmap_size: DeviceModel::Keyboard.memory_size().unwrap()
         },
+        DeviceConfig {
+            id: timer_id,
+            model: DeviceModel::Timer.number(),
+            interrupt: 0xffff_0004,
+            memmap_base: 0x8b00,
+            memmap_size: DeviceModel::Timer.memory_size().unwrap()
+        },
     ];
 
+    if let Some((image, sectors)) = disk_image {
+        let disk_id = pool.add_hardware(Disk::new(image, sectors, ram_id, 0x10000));
+
+        pool.connect(machine_id, disk_id);
+        pool.connect(disk_id, ram_id);
+
+        configs.push(DeviceConfig {
+            id: disk_id,
+            model: DeviceModel::Disk.number(),
+            interrupt: 0xffff_0005,
+            memmap_base: 0x8d00,
+            memmap_size: DeviceModel::Disk.memory_size().unwrap()
+        });
+    }
+
     pool.dispatch().send(HardwareMessage::InitializeMachine(machine_id, configs));
 
     loop {
         match client_msg_rx.try_recv() {
             Ok(ClientMsg::KeyboardInput(word)) => {
-                keyboard_tx.send(word).unwrap();
+                // Fails if replaying, since nothing is listening
+                let _ = keyboard_tx.send(word);
             },
 
             Ok(ClientMsg::WsPing(buf)) => {
@@ -163,9 +307,8 @@ fn handle_session<R, W>(ip: String, client_rx: Reader<R>, mut client_tx: Writer<
         }
 
         match monitor_rx.try_recv() {
-            Ok((offset, word)) => {
-                client_tx.send_message(
-                    &Message::text(format!("{},{}", offset, word))).unwrap();
+            Ok(update) => {
+                client_tx.send_message(&Message::binary(update.encode())).unwrap();
             },
             Err(_) => ()
         }
@@ -76,6 +76,13 @@ lazy_static! {
         (0x0023, IntExit),
 
         (0x0024, Trace),
+
+        (0x0025, CmpS),
+        (0x0026, BranchC),
+        (0x0027, BranchO),
+        (0x0028, DivS),
+        (0x0029, DivModS),
+        (0x002A, Ash),
     ].iter().cloned().collect();
 
     pub static ref REGISTERS: BTreeMap<u32, Register> = [
@@ -0,0 +1,243 @@
+//! Captures what a `Monitor` shows as an image, e.g. for golden image tests
+//!
+//! Text mode is drawn with the bundled `font`, light on black. The indexed color modes are drawn
+//! through the palette. Images can be written as PPM or PNG, without any compression for the
+//! latter, which keeps the encoder simple.
+
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+use std::path::Path;
+
+use byteorder::*;
+
+use font;
+use monitor::VideoMode;
+
+/// The color of text in text mode, as 0xRRGGBB
+pub const TEXT_COLOR: u32 = 0xcccccc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// 0xRRGGBB, a row at a time from the top left
+    pub pixels: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Ppm,
+    Png,
+}
+
+impl ImageFormat {
+    /// From the file's extension, which has to be `.ppm` or `.png`
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<ImageFormat> {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("ppm") => Some(ImageFormat::Ppm),
+            Some("png") => Some(ImageFormat::Png),
+            _ => None
+        }
+    }
+}
+
+/// Draws the part of `vid_ram` that `mode` shows
+pub fn render(mode: VideoMode, vid_ram: &[u32], palette: &[u32]) -> Image {
+    let bytes = vid_ram[..mode.buffer_size() as usize].iter().flat_map(|&word| {
+        (0..4).map(move |i| (word >> (i * 8)) as u8)
+    });
+
+    if mode.is_text() {
+        let columns = mode.width() as usize;
+
+        let width = columns * font::WIDTH;
+        let height = mode.height() as usize * font::HEIGHT;
+
+        let mut pixels = vec![0; width * height];
+
+        for (index, byte) in bytes.enumerate() {
+            let left = index % columns * font::WIDTH;
+            let top = index / columns * font::HEIGHT;
+
+            for (y, &row) in font::glyph(byte).iter().enumerate() {
+                for x in 0..font::WIDTH {
+                    if row & (0x80 >> x) != 0 {
+                        pixels[(top + y) * width + left + x] = TEXT_COLOR;
+                    }
+                }
+            }
+        }
+
+        Image { width: width as u32, height: height as u32, pixels: pixels }
+    } else {
+        Image {
+            width: mode.width(),
+            height: mode.height(),
+            pixels: bytes.map(|byte| palette.get(byte as usize).cloned().unwrap_or(0)).collect(),
+        }
+    }
+}
+
+impl Image {
+    pub fn pixel(&self, x: u32, y: u32) -> u32 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    fn rgb(&self) -> Vec<u8> {
+        self.pixels.iter().flat_map(|&pixel| {
+            vec![(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]
+        }).collect()
+    }
+
+    /// Binary PPM (P6)
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.rgb())
+    }
+
+    /// 8-bit RGB PNG, uncompressed
+    pub fn write_png<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut header = vec![];
+        header.write_u32::<BigEndian>(self.width)?;
+        header.write_u32::<BigEndian>(self.height)?;
+        header.extend(&[8, 2, 0, 0, 0]); // bit depth, RGB, compression, filter, interlace
+
+        write_chunk(out, b"IHDR", &header)?;
+
+        // Each row starts with its filter type, which is always none
+        let rgb = self.rgb();
+        let mut raw = vec![];
+
+        for row in rgb.chunks(self.width as usize * 3) {
+            raw.push(0);
+            raw.extend(row);
+        }
+
+        write_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+        write_chunk(out, b"IEND", &[])
+    }
+
+    pub fn write<W: Write>(&self, format: ImageFormat, out: &mut W) -> io::Result<()> {
+        match format {
+            ImageFormat::Ppm => self.write_ppm(out),
+            ImageFormat::Png => self.write_png(out),
+        }
+    }
+
+    /// Picks the format from the extension. See `ImageFormat::from_path()`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let format = ImageFormat::from_path(&path).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "image file must end in .ppm or .png")
+        })?;
+
+        let mut file = File::create(path)?;
+
+        self.write(format, &mut file)?;
+        file.flush()
+    }
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_u32::<BigEndian>(data.len() as u32)?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+
+    let crc = crc32(kind.iter().chain(data));
+    out.write_u32::<BigEndian>(crc)
+}
+
+fn crc32<'a, I>(bytes: I) -> u32 where I: IntoIterator<Item=&'a u8> {
+    let mut crc = 0xffff_ffff;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+/// A zlib stream of stored (uncompressed) deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(0xffff).peekable();
+
+    if blocks.peek().is_none() {
+        out.extend(&[1, 0, 0, 0xff, 0xff]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+
+        out.push(last as u8);
+        out.write_u16::<LittleEndian>(block.len() as u16).unwrap();
+        out.write_u16::<LittleEndian>(!(block.len() as u16)).unwrap();
+        out.extend(block);
+    }
+
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    out.write_u32::<BigEndian>(b << 16 | a).unwrap();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text() {
+        let mut vid_ram = vec![0; 200];
+        vid_ram[0] = 'A' as u32;
+
+        let image = render(VideoMode::Text, &vid_ram, &[]);
+
+        assert_eq!((image.width, image.height), (320, 260));
+
+        // The crossbar of the A
+        assert_eq!(image.pixel(1, 7), TEXT_COLOR);
+        assert_eq!(image.pixel(0, 7), 0);
+        assert!(image.pixels[320 * font::HEIGHT..].iter().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn indexed() {
+        let vid_ram = vec![0x00000201; 19200];
+        let palette = vec![0, 0xff0000, 0x00ff00];
+
+        let image = render(VideoMode::Indexed320x240, &vid_ram, &palette);
+
+        assert_eq!((image.width, image.height), (320, 240));
+        assert_eq!(&image.pixels[..4], &[0xff0000, 0x00ff00, 0, 0]);
+    }
+
+    #[test]
+    fn formats() {
+        let image = Image { width: 2, height: 1, pixels: vec![0x102030, 0xffffff] };
+
+        let mut ppm = vec![];
+        image.write_ppm(&mut ppm).unwrap();
+        assert_eq!(ppm, b"P6\n2 1\n255\n\x10\x20\x30\xff\xff\xff");
+
+        let mut png = vec![];
+        image.write_png(&mut png).unwrap();
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+
+        // The well known check value
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(&zlib_stored(b"Wikipedia")[16..], &[0x11, 0xe6, 0x03, 0x98]);
+    }
+}
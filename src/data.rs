@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Instruction(pub Function, pub Register, pub Operand);
 
@@ -11,11 +13,14 @@ pub enum Function {
     Store,
 
     Cmp,
+    CmpS,
     Branch,
     BranchL,
     BranchG,
     BranchE,
     BranchNE,
+    BranchC,
+    BranchO,
 
     GetSp,
     SetSp,
@@ -29,6 +34,8 @@ pub enum Function {
     Mul,
     Div,
     DivMod,
+    DivS,
+    DivModS,
 
     Not,
     And,
@@ -36,6 +43,7 @@ pub enum Function {
     Xor,
     Lsh,
     Rsh,
+    Ash,
 
     Halt,
     IntSw,
@@ -75,6 +83,8 @@ pub struct State {
     pub inth: u32,
     pub int_outgoing: Option<u32>,
     pub halt: bool,
+    /// Why the machine halted, if it was because of an exception that had no handler
+    pub fault: Option<Exception>,
     pub flags: Flags,
 }
 
@@ -103,7 +113,7 @@ impl State {
         match operand {
             Operand::Reg(reg) => self.register(reg),
             Operand::Const(c) => c,
-            Operand::Relative(rel) => (self.ip - 2).wrapping_add(rel as u32),
+            Operand::Relative(rel) => self.ip.wrapping_sub(2).wrapping_add(rel as u32),
         }
     }
 
@@ -112,12 +122,84 @@ impl State {
     }
 }
 
+/// Raised by the CPU when a program does something it can't carry on from. If an interrupt
+/// handler is set, it's called with `code()` in A, like any other interrupt. Otherwise the machine
+/// halts, and stays halted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    BadInstruction,
+    DivideByZero,
+    /// A load, store or fetch from an address that nothing is mapped at
+    UnmappedMemory(u32),
+    /// The stack pointer went past the bottom (or the top) of the address space
+    StackOverflow,
+    /// An instruction that only the supervisor can use, in user mode
+    PrivilegedInstruction,
+    /// An access that the MPU doesn't allow, in user mode
+    ProtectionFault(u32),
+}
+
+impl Exception {
+    pub fn code(self) -> u32 {
+        match self {
+            Exception::BadInstruction        => 0xfffe_0001,
+            Exception::DivideByZero          => 0xfffe_0002,
+            Exception::UnmappedMemory(_)     => 0xfffe_0003,
+            Exception::StackOverflow         => 0xfffe_0004,
+            Exception::PrivilegedInstruction => 0xfffe_0005,
+            Exception::ProtectionFault(_)    => 0xfffe_0006,
+        }
+    }
+
+    /// The address the exception is about, if it's about memory
+    pub fn addr(self) -> Option<u32> {
+        match self {
+            Exception::UnmappedMemory(addr) |
+            Exception::ProtectionFault(addr) => Some(addr),
+            _ => None
+        }
+    }
+
+    /// The other way around from `code()`. `addr` only matters for exceptions about memory.
+    pub fn from_code(code: u32, addr: u32) -> Option<Exception> {
+        match code {
+            0xfffe_0001 => Some(Exception::BadInstruction),
+            0xfffe_0002 => Some(Exception::DivideByZero),
+            0xfffe_0003 => Some(Exception::UnmappedMemory(addr)),
+            0xfffe_0004 => Some(Exception::StackOverflow),
+            0xfffe_0005 => Some(Exception::PrivilegedInstruction),
+            0xfffe_0006 => Some(Exception::ProtectionFault(addr)),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Exception::BadInstruction        => write!(f, "bad instruction"),
+            Exception::DivideByZero          => write!(f, "divide by zero"),
+            Exception::UnmappedMemory(addr)  => write!(f, "unmapped memory at {:#010x}", addr),
+            Exception::StackOverflow         => write!(f, "stack overflow"),
+            Exception::PrivilegedInstruction => write!(f, "privileged instruction in user mode"),
+            Exception::ProtectionFault(addr) => write!(f, "protection fault at {:#010x}", addr),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct Flags {
     pub cmp_l: bool,
     pub cmp_g: bool,
     pub cmp_e: bool,
+    /// Set by `add`, `sub` and `mul` if the result didn't fit, unsigned
+    pub carry: bool,
+    /// Set by `add`, `sub` and `mul` if the result didn't fit, signed
+    pub overflow: bool,
     pub int_pause: bool,
+    /// User mode: privileged instructions aren't allowed, and memory is protected by the MPU.
+    /// Interrupts switch to supervisor mode, and `intexit` switches back.
+    pub user: bool,
 }
 
 impl From<u32> for Flags {
@@ -126,7 +208,10 @@ impl From<u32> for Flags {
             cmp_l:     word & (1 << 0) != 0,
             cmp_g:     word & (1 << 1) != 0,
             cmp_e:     word & (1 << 2) != 0,
+            carry:     word & (1 << 3) != 0,
+            overflow:  word & (1 << 4) != 0,
             int_pause: word & (1 << 9) != 0,
+            user:      word & (1 << 10) != 0,
         }
     }
 }
@@ -144,9 +229,18 @@ impl From<Flags> for u32 {
         if flags.cmp_e {
             word |= 1 << 2;
         }
+        if flags.carry {
+            word |= 1 << 3;
+        }
+        if flags.overflow {
+            word |= 1 << 4;
+        }
         if flags.int_pause {
             word |= 1 << 9;
         }
+        if flags.user {
+            word |= 1 << 10;
+        }
 
         word
     }
@@ -0,0 +1,639 @@
+//! Interactive single-step debugger for a machine running in an `EventPool`
+//!
+//! The debugger drives the pool itself, ticking it until the machine reaches an instruction
+//! boundary (a transition back into `PipelineStage::Fetch`). Memory is read and written over the
+//! bus through `Machine::request_debug_access`, so device memory can be inspected too.
+//!
+//! Input comes from a byte channel, normally stdin in raw mode. While the machine is running,
+//! input is forwarded to the guest's console, except for Ctrl-B, which breaks back into the
+//! debugger.
+
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::thread;
+use std::time::Duration;
+use std::collections::BTreeSet;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+
+use data::Exception;
+use machine::{Machine, PipelineStage, PowerState, DebugAccess};
+use event_pool::EventPool;
+use symbols::SymbolTable;
+use disassemble::format_instruction;
+use snapshot;
+
+/// Ctrl-B
+pub const BREAK_KEY: u8 = 0x02;
+
+/// How many ticks to wait for a memory access or power-on before giving up.
+const TIMEOUT_TICKS: u64 = 1_000_000;
+
+static HELP: &'static str = "\
+Commands:
+  s, step [N]              execute N instructions (default 1)
+  c, continue              run until a breakpoint, halt, or Ctrl-B
+  b, break <ADDR>          set a breakpoint
+  d, delete <ADDR>         remove a breakpoint
+  l, list                  list breakpoints
+  r, regs                  show registers, flags and pipeline stage
+  x, peek <ADDR> [N]       show N words of memory (default 1)
+  w, poke <ADDR> <VALUE>   write a word to memory
+  save <FILE>              save a snapshot of the whole machine
+  load <FILE>              restore a snapshot
+  q, quit                  exit the emulator
+  h, help                  show this message
+
+Addresses, values and counts are hex. Addresses may also be labels from the symbol file, optionally
+with an offset, e.g. PrintString+4";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Addr(u32),
+    Label(String, i32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Step(u32),
+    Continue,
+    Break(Location),
+    Delete(Location),
+    List,
+    Regs,
+    Peek(Location, u32),
+    Poke(Location, u32),
+    Save(String),
+    Load(String),
+    Quit,
+    Help,
+}
+
+fn parse_hex(s: &str) -> Result<u32, String> {
+    let digits = if s.starts_with("0x") { &s[2..] } else { s };
+
+    u32::from_str_radix(digits, 16).map_err(|_| format!("not a hex number: {}", s))
+}
+
+fn parse_location(s: &str) -> Result<Location, String> {
+    let first = s.chars().next().unwrap_or(' ');
+
+    if first == '_' || first.is_alphabetic() {
+        if let Some(pos) = s.find(|c| c == '+' || c == '-') {
+            let offset = parse_hex(&s[pos+1..])? as i32;
+
+            Ok(Location::Label(s[..pos].into(), if &s[pos..pos+1] == "-" { -offset } else { offset }))
+        } else {
+            Ok(Location::Label(s.into(), 0))
+        }
+    } else {
+        parse_hex(s).map(Location::Addr)
+    }
+}
+
+pub fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Ok(None)
+    };
+
+    let arg = |n: usize| -> Result<&str, String> {
+        args.get(n).cloned().ok_or_else(|| format!("{}: missing argument", name))
+    };
+
+    Ok(Some(match name {
+        "s" | "step"     => Command::Step(match args.get(0) {
+            Some(n) => parse_hex(n)?,
+            None    => 1
+        }),
+        "c" | "continue" => Command::Continue,
+        "b" | "break"    => Command::Break(parse_location(arg(0)?)?),
+        "d" | "delete"   => Command::Delete(parse_location(arg(0)?)?),
+        "l" | "list"     => Command::List,
+        "r" | "regs"     => Command::Regs,
+        "x" | "peek"     => Command::Peek(parse_location(arg(0)?)?, match args.get(1) {
+            Some(n) => parse_hex(n)?,
+            None    => 1
+        }),
+        "w" | "poke"     => Command::Poke(parse_location(arg(0)?)?, parse_hex(arg(1)?)?),
+        "save"           => Command::Save(arg(0)?.into()),
+        "load"           => Command::Load(arg(0)?.into()),
+        "q" | "quit"     => Command::Quit,
+        "h" | "help"     => Command::Help,
+        other            => return Err(format!("unknown command: {}", other))
+    }))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+    Stepped,
+    Breakpoint,
+    Halted,
+    UserBreak,
+    TimedOut,
+}
+
+pub struct Debugger {
+    pool: EventPool,
+    machine: Rc<RefCell<Machine>>,
+    symbols: SymbolTable,
+    breakpoints: BTreeSet<u32>,
+    input_rx: Receiver<u8>,
+    console_tx: Option<Sender<u8>>,
+    tick_dur: Duration,
+    out: Box<Write>,
+}
+
+impl Debugger {
+    /// `machine` must be the same machine that was added to `pool`.
+    pub fn new(pool: EventPool,
+               machine: Rc<RefCell<Machine>>,
+               input_rx: Receiver<u8>) -> Debugger {
+        Debugger {
+            pool: pool,
+            machine: machine,
+            symbols: SymbolTable::new(),
+            breakpoints: BTreeSet::new(),
+            input_rx: input_rx,
+            console_tx: None,
+            tick_dur: Duration::new(0, 0),
+            out: Box::new(io::stdout()),
+        }
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    /// Where to send input received while the machine is running.
+    pub fn set_console(&mut self, console_tx: Sender<u8>) {
+        self.console_tx = Some(console_tx);
+    }
+
+    /// How long to sleep between ticks while running. Stepping is not delayed.
+    pub fn set_tick_duration(&mut self, tick_dur: Duration) {
+        self.tick_dur = tick_dur;
+    }
+
+    pub fn set_output<W: Write + 'static>(&mut self, out: W) {
+        self.out = Box::new(out);
+    }
+
+    // The terminal is in raw mode, so every line needs a carriage return.
+    fn println(&mut self, s: &str) {
+        for line in s.lines() {
+            let _ = write!(self.out, "{}\r\n", line);
+        }
+        let _ = self.out.flush();
+    }
+
+    fn resolve(&self, location: &Location) -> Result<u32, String> {
+        match *location {
+            Location::Addr(addr) => Ok(addr),
+            Location::Label(ref label, offset) => {
+                self.symbols.get(label)
+                    .map(|addr| addr.wrapping_add(offset as u32))
+                    .ok_or_else(|| format!("unknown label: {}", label))
+            }
+        }
+    }
+
+    /// Runs the debugger until the user quits.
+    pub fn run(&mut self) {
+        self.println("fai debugger. Type 'help' for a list of commands.");
+
+        if !self.power_on() {
+            self.println("The machine did not power on.");
+            return;
+        }
+
+        self.show_position();
+
+        loop {
+            let line = match self.read_line("(fai) ") {
+                Some(line) => line,
+                None => return
+            };
+
+            match parse_command(&line) {
+                Ok(Some(Command::Quit)) => return,
+                Ok(Some(command)) => self.execute(command),
+                Ok(None) => (),
+                Err(message) => self.println(&message),
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::Step(n) => {
+                for _ in 0..n {
+                    match self.run_until_stop(true) {
+                        StopReason::Stepped => (),
+                        StopReason::Halted => {
+                            self.report_halt();
+                            break;
+                        },
+                        StopReason::TimedOut => {
+                            self.println("Timed out waiting for the instruction to finish.");
+                            break;
+                        },
+                        _ => break
+                    }
+                }
+                self.show_position();
+            },
+            Command::Continue => {
+                let reason = self.run_until_stop(false);
+
+                match reason {
+                    StopReason::Breakpoint => self.println("Breakpoint."),
+                    StopReason::Halted     => self.report_halt(),
+                    StopReason::UserBreak  => self.println("Interrupted."),
+                    StopReason::Stepped |
+                    StopReason::TimedOut   => ()
+                }
+                self.show_position();
+            },
+            Command::Break(ref location) => {
+                match self.resolve(location) {
+                    Ok(addr) => {
+                        self.breakpoints.insert(addr);
+                        let desc = format!("Breakpoint at {:#010x} ({})",
+                                           addr, self.symbols.describe(addr));
+                        self.println(&desc);
+                    },
+                    Err(message) => self.println(&message)
+                }
+            },
+            Command::Delete(ref location) => {
+                match self.resolve(location) {
+                    Ok(addr) => {
+                        if !self.breakpoints.remove(&addr) {
+                            self.println("No breakpoint there.");
+                        }
+                    },
+                    Err(message) => self.println(&message)
+                }
+            },
+            Command::List => {
+                let lines: Vec<String> = self.breakpoints.iter()
+                    .map(|&addr| format!("{:#010x} ({})", addr, self.symbols.describe(addr)))
+                    .collect();
+
+                if lines.is_empty() {
+                    self.println("No breakpoints.");
+                } else {
+                    self.println(&lines.join("\n"));
+                }
+            },
+            Command::Regs => self.show_registers(),
+            Command::Peek(ref location, count) => {
+                let addr = match self.resolve(location) {
+                    Ok(addr) => addr,
+                    Err(message) => { self.println(&message); return; }
+                };
+
+                for ptr in addr..addr.saturating_add(count) {
+                    match self.access(DebugAccess::Peek(ptr)) {
+                        Some(val) => {
+                            let line = format!("{:#010x} ({}): {:#010x}",
+                                               ptr, self.symbols.describe(ptr), val);
+                            self.println(&line);
+                        },
+                        None => {
+                            self.println("Timed out waiting for memory.");
+                            break;
+                        }
+                    }
+                }
+            },
+            Command::Poke(ref location, val) => {
+                let addr = match self.resolve(location) {
+                    Ok(addr) => addr,
+                    Err(message) => { self.println(&message); return; }
+                };
+
+                if self.access(DebugAccess::Poke(addr, val)).is_none() {
+                    self.println("Timed out waiting for memory.");
+                }
+            },
+            Command::Save(ref path) => {
+                let result = File::create(path).map_err(From::from)
+                    .and_then(|file| snapshot::save(&self.pool, file));
+
+                match result {
+                    Ok(()) => self.println("Saved."),
+                    Err(e) => self.println(&format!("Failed to save snapshot: {}", e))
+                }
+            },
+            Command::Load(ref path) => {
+                let result = File::open(path).map_err(From::from)
+                    .and_then(|file| snapshot::restore(&mut self.pool, file));
+
+                match result {
+                    Ok(()) => self.show_position(),
+                    // A partially restored pool isn't in any sensible state
+                    Err(e) => self.println(&format!("Failed to restore snapshot, the machine may \
+                                                     now be inconsistent: {}", e))
+                }
+            },
+            Command::Help => self.println(HELP),
+            Command::Quit => ()
+        }
+    }
+
+    fn power_on(&mut self) -> bool {
+        for _ in 0..TIMEOUT_TICKS {
+            if *self.machine.borrow().power_state() == PowerState::On {
+                return true;
+            }
+            self.pool.tick();
+        }
+        false
+    }
+
+    fn access(&mut self, access: DebugAccess) -> Option<u32> {
+        self.machine.borrow_mut().request_debug_access(access);
+
+        for _ in 0..TIMEOUT_TICKS {
+            self.pool.tick();
+
+            if let Some(val) = self.machine.borrow_mut().take_debug_result() {
+                return Some(val);
+            }
+        }
+        None
+    }
+
+    /// Ticks the pool until the machine finishes an instruction (if `step`), or reaches a
+    /// breakpoint, halts or the user breaks in.
+    fn run_until_stop(&mut self, step: bool) -> StopReason {
+        let mut was_halted = self.machine.borrow().state().halt;
+        let mut ticks = 0;
+
+        // Only an interrupt can wake the machine up, and those need the machine to be running.
+        if step && was_halted {
+            return StopReason::Halted;
+        }
+
+        loop {
+            if step && ticks >= TIMEOUT_TICKS {
+                return StopReason::TimedOut;
+            }
+            ticks += 1;
+
+            let stage_before = self.machine.borrow().pipeline_stage();
+
+            self.pool.tick();
+
+            let (stage_after, ip, halt) = {
+                let machine = self.machine.borrow();
+                (machine.pipeline_stage(), machine.state().ip, machine.state().halt)
+            };
+
+            if halt && !was_halted {
+                return StopReason::Halted;
+            }
+            was_halted = halt;
+
+            let boundary = stage_before != PipelineStage::Fetch &&
+                stage_after == PipelineStage::Fetch;
+
+            if boundary {
+                if step {
+                    return StopReason::Stepped;
+                }
+
+                // The instruction we're continuing from has already finished by now, so this
+                // can't be its breakpoint
+                if self.breakpoints.contains(&ip) {
+                    return StopReason::Breakpoint;
+                }
+            }
+
+            if !step {
+                match self.input_rx.try_recv() {
+                    Ok(BREAK_KEY) => return StopReason::UserBreak,
+                    Ok(byte) => {
+                        if let Some(ref tx) = self.console_tx {
+                            let _ = tx.send(byte);
+                        }
+                    },
+                    Err(TryRecvError::Empty) => (),
+                    Err(TryRecvError::Disconnected) => return StopReason::UserBreak,
+                }
+
+                thread::sleep(self.tick_dur);
+            }
+        }
+    }
+
+    fn show_position(&mut self) {
+        let (stage, ip) = {
+            let machine = self.machine.borrow();
+            (machine.pipeline_stage(), machine.state().ip)
+        };
+
+        let location = match self.symbols.line(ip) {
+            Some(line) => format!("{}, {}", self.symbols.describe(ip), line),
+            None       => self.symbols.describe(ip),
+        };
+
+        let line = match stage {
+            PipelineStage::Fetch =>
+                format!("{:#010x} ({}): fetch", ip, location),
+            PipelineStage::Execute(inst) =>
+                format!("{:#010x} ({}): execute {}", ip, location, format_instruction(inst)),
+            PipelineStage::Interrupt(code) =>
+                format!("{:#010x} ({}): interrupt {:#010x}", ip, location, code),
+            PipelineStage::Exception(exception) =>
+                format!("{:#010x} ({}): exception: {}", ip, location, exception),
+        };
+
+        self.println(&line);
+    }
+
+    fn report_halt(&mut self) {
+        let (fault, bus_error) = {
+            let machine = self.machine.borrow();
+            (machine.state().fault, machine.last_bus_error())
+        };
+
+        let text = match (fault, bus_error) {
+            (Some(Exception::UnmappedMemory(addr)), Some(error)) if error.addr == addr =>
+                format!("Machine halted: {}, accessed by {:#010x} ({}).",
+                        Exception::UnmappedMemory(addr), error.ip,
+                        self.symbols.describe(error.ip)),
+            (Some(exception), _) => format!("Machine halted: {}.", exception),
+            (None, _) => "Machine halted.".into(),
+        };
+
+        self.println(&text);
+    }
+
+    fn show_registers(&mut self) {
+        let (state, stage, bus_error) = {
+            let machine = self.machine.borrow();
+            (*machine.state(), machine.pipeline_stage(), machine.last_bus_error())
+        };
+
+        let flags = state.flags;
+
+        let text = format!(
+            "ip   = {:#010x} ({})\n\
+             sp   = {:#010x}\n\
+             a    = {:#010x}  b = {:#010x}  c = {:#010x}  d = {:#010x}\n\
+             inth = {:#010x}\n\
+             flags: l={} g={} e={} c={} o={} int_pause={} user={}  halt={}\n\
+             stage: {:?}",
+            state.ip, self.symbols.describe(state.ip),
+            state.sp,
+            state.a, state.b, state.c, state.d,
+            state.inth,
+            flags.cmp_l as u8, flags.cmp_g as u8, flags.cmp_e as u8,
+            flags.carry as u8, flags.overflow as u8, flags.int_pause as u8, flags.user as u8,
+            state.halt,
+            stage);
+
+        self.println(&text);
+
+        if let Some(error) = bus_error {
+            self.println(&format!("last bus error: {:#010x}, accessed by {:#010x} ({})",
+                                  error.addr, error.ip, self.symbols.describe(error.ip)));
+        }
+    }
+
+    /// Simple line editing for a raw mode terminal.
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        let _ = write!(self.out, "{}", prompt);
+        let _ = self.out.flush();
+
+        let mut line = String::new();
+
+        loop {
+            let byte = match self.input_rx.recv() {
+                Ok(byte) => byte,
+                Err(_) => return None
+            };
+
+            match byte {
+                b'\r' | b'\n' => {
+                    let _ = write!(self.out, "\r\n");
+                    let _ = self.out.flush();
+                    return Some(line);
+                },
+                // Ctrl-C, Ctrl-D
+                0x03 | 0x04 => {
+                    let _ = write!(self.out, "\r\n");
+                    return None;
+                },
+                // Backspace, delete
+                0x08 | 0x7f => {
+                    if line.pop().is_some() {
+                        let _ = write!(self.out, "\x08 \x08");
+                    }
+                },
+                byte if byte >= 0x20 && byte < 0x7f => {
+                    line.push(byte as char);
+                    let _ = self.out.write_all(&[byte]);
+                },
+                _ => ()
+            }
+
+            let _ = self.out.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use assemble::assemble;
+    use data::State;
+    use device::{DeviceConfig, DeviceModel};
+    use ram::Ram;
+
+    #[test]
+    fn parse_step() {
+        assert_eq!(parse_command("s"), Ok(Some(Command::Step(1))));
+        assert_eq!(parse_command("step 10"), Ok(Some(Command::Step(0x10))));
+    }
+
+    #[test]
+    fn parse_break_label_offset() {
+        assert_eq!(parse_command("b PrintString+1c"),
+                   Ok(Some(Command::Break(Location::Label("PrintString".into(), 0x1c)))));
+    }
+
+    #[test]
+    fn parse_peek_addr_count() {
+        assert_eq!(parse_command("x 0x11000 10"),
+                   Ok(Some(Command::Peek(Location::Addr(0x11000), 0x10))));
+    }
+
+    #[test]
+    fn parse_save_needs_path() {
+        assert!(parse_command("save").is_err());
+        assert_eq!(parse_command("save foo.state"), Ok(Some(Command::Save("foo.state".into()))));
+    }
+
+    #[test]
+    fn breakpoint_on_next_instruction() {
+        let mut program = vec![];
+        assemble(b"set a [1]\nset b [2]\nset c [3]\nhalt\n", &mut program).unwrap();
+
+        let mut ram = Ram::new(0x1000);
+        ram.words_mut()[..program.len()].copy_from_slice(&program);
+
+        let machine = Rc::new(RefCell::new(Machine::new(State {
+            ip: 0x10000,
+            sp: 0x11000,
+            ..State::default()
+        })));
+
+        let mut pool = EventPool::new();
+
+        let machine_id = pool.add_hardware(machine.clone());
+        let ram_id = pool.add_hardware(ram);
+
+        pool.connect(machine_id, ram_id);
+
+        pool.initialize_machine(machine_id, &[DeviceConfig {
+            id: ram_id,
+            model: DeviceModel::Ram.number(),
+            interrupt: 0xffff_0000,
+            memmap_base: 0x10000,
+            memmap_size: 0x1000,
+        }]);
+
+        let (_input_tx, input_rx) = channel();
+        let mut debugger = Debugger::new(pool, machine.clone(), input_rx);
+
+        assert_eq!(debugger.run_until_stop(true), StopReason::Stepped);
+
+        // Each `set` is two words long, so this is the very next instruction
+        let ip = machine.borrow().state().ip;
+        debugger.breakpoints.insert(ip + 2);
+
+        assert_eq!(debugger.run_until_stop(false), StopReason::Breakpoint);
+        assert_eq!(machine.borrow().state().ip, ip + 2);
+        assert_eq!(machine.borrow().state().c, 0);
+    }
+
+    #[test]
+    fn parse_empty() {
+        assert_eq!(parse_command("   "), Ok(None));
+    }
+
+    #[test]
+    fn parse_unknown() {
+        assert!(parse_command("frobnicate").is_err());
+    }
+}
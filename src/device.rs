@@ -15,10 +15,27 @@ pub enum DeviceModel {
     Ram = 0x01011010,
     Monitor = 0x384c0001,
     Keyboard = 0x384c000e,
+    Timer = 0x384c0007,
+    Disk = 0x384c0004,
+    Uart = 0x384c0003,
     DebugConsole = 0xdeadbeef,
 }
 
 impl DeviceModel {
+    pub fn all() -> &'static [DeviceModel] {
+        static ALL: [DeviceModel; 7] = [
+            DeviceModel::Ram,
+            DeviceModel::Monitor,
+            DeviceModel::Keyboard,
+            DeviceModel::Timer,
+            DeviceModel::Disk,
+            DeviceModel::Uart,
+            DeviceModel::DebugConsole,
+        ];
+
+        &ALL
+    }
+
     pub fn number(self) -> u32 {
         self as u32
     }
@@ -30,6 +47,12 @@ impl DeviceModel {
 
             DeviceModel::Keyboard => 0x1,
 
+            DeviceModel::Timer => 0x4,
+
+            DeviceModel::Disk => 0x5,
+
+            DeviceModel::Uart => 0x3,
+
             DeviceModel::DebugConsole => 0x3,
 
             _ => { return None; }
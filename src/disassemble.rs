@@ -0,0 +1,410 @@
+//! Disassembler
+//!
+//! Turns bitcode back into assembler syntax that assembles to exactly the same words. Relative
+//! operands that point at the start of an instruction get a label, taken from a symbol table if
+//! there is one, or made up otherwise. Words that don't decode to an instruction that would be
+//! encoded the same way (data, mostly) come out as `.words`.
+
+use std::io;
+use std::io::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+use data::*;
+use data::Function::*;
+use bitcode;
+use symbols::SymbolTable;
+
+/// How many words to put on one `.words` line
+const WORDS_PER_LINE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Item {
+    Instruction(Instruction, usize),
+    Word(u32),
+}
+
+impl Item {
+    fn len(&self) -> usize {
+        match *self {
+            Item::Instruction(_, len) => len,
+            Item::Word(_)             => 1,
+        }
+    }
+}
+
+pub fn mnemonic(function: Function) -> &'static str {
+    match function {
+        Bad      => "bad",
+
+        Nop      => "nop",
+        Set      => "set",
+        Load     => "load",
+        Store    => "store",
+
+        Cmp      => "cmp",
+        CmpS     => "cmps",
+        Branch   => "branch",
+        BranchL  => "branchl",
+        BranchG  => "branchg",
+        BranchE  => "branche",
+        BranchNE => "branchne",
+        BranchC  => "branchc",
+        BranchO  => "brancho",
+
+        GetSp    => "getsp",
+        SetSp    => "setsp",
+        Push     => "push",
+        Pop      => "pop",
+        Call     => "call",
+        Ret      => "ret",
+
+        Add      => "add",
+        Sub      => "sub",
+        Mul      => "mul",
+        Div      => "div",
+        DivMod   => "divmod",
+        DivS     => "divs",
+        DivModS  => "divmods",
+
+        Not      => "not",
+        And      => "and",
+        Or       => "or",
+        Xor      => "xor",
+        Lsh      => "lsh",
+        Rsh      => "rsh",
+        Ash      => "ash",
+
+        Halt     => "halt",
+        IntSw    => "intsw",
+        IntHw    => "inthw",
+        IntPause => "intpause",
+        IntCont  => "intcont",
+        IntHGet  => "inthget",
+        IntHSet  => "inthset",
+        IntExit  => "intexit",
+
+        Trace    => "trace",
+    }
+}
+
+/// False if the register field of the instruction is ignored
+pub fn uses_register(function: Function) -> bool {
+    match function {
+        Bad | Nop | Branch | BranchL | BranchG | BranchE | BranchNE | BranchC | BranchO |
+        SetSp | Call | Ret | Halt | IntSw | IntHw | IntPause | IntCont | IntHSet | IntExit |
+        Trace => false,
+        _ => true
+    }
+}
+
+/// False if the operand of the instruction is ignored (it's always zero in that case)
+pub fn uses_operand(function: Function) -> bool {
+    match function {
+        Bad | Nop | GetSp | Push | Pop | Ret | Not | Halt | IntPause | IntCont | IntHGet |
+        IntExit => false,
+        _ => true
+    }
+}
+
+pub fn register_name(register: Register) -> &'static str {
+    match register {
+        Register::A => "a",
+        Register::B => "b",
+        Register::C => "c",
+        Register::D => "d",
+    }
+}
+
+fn relative(offset: i32) -> String {
+    // i32::MIN can't be negated, but `$ + 0x80000000` wraps around to it anyway
+    if offset < 0 && offset != i32::min_value() {
+        format!("$ - {:#x}", -offset)
+    } else {
+        format!("$ + {:#x}", offset)
+    }
+}
+
+/// Formats an instruction in assembler syntax, with relative operands written as `$ + n`.
+pub fn format_instruction(inst: Instruction) -> String {
+    format_with_labels(inst, |offset| relative(offset))
+}
+
+fn format_with_labels<F>(inst: Instruction, relative_operand: F) -> String
+    where F: FnOnce(i32) -> String {
+
+    let Instruction(function, register, operand) = inst;
+
+    let mut s = mnemonic(function).to_owned();
+
+    if uses_register(function) || register != Register::A {
+        s.push(' ');
+        s.push_str(register_name(register));
+    }
+
+    let operand = match operand {
+        Operand::Const(0) if !uses_operand(function) => None,
+        Operand::Const(c)      => Some(format!("{:#x}", c)),
+        Operand::Reg(r)        => Some(register_name(r).to_owned()),
+        Operand::Relative(off) => Some(relative_operand(off)),
+    };
+
+    if let Some(operand) = operand {
+        s.push_str(" [");
+        s.push_str(&operand);
+        s.push(']');
+    }
+
+    s
+}
+
+/// Decodes the instruction at the start of `words`, but only if encoding it again would give
+/// back the same words.
+fn decode_exact(words: &[u32]) -> Option<Item> {
+    let len = if words[0] & (1 << 22) == 0 { 2 } else { 1 };
+
+    if words.len() < len {
+        return None;
+    }
+
+    let inst = match bitcode::decode_instruction(&words[..len]) {
+        Ok(inst) => inst,
+        Err(_) => return None
+    };
+
+    let mut encoded = vec![];
+
+    bitcode::encode_instruction(inst, &mut encoded);
+
+    if encoded[..] == words[..len] {
+        Some(Item::Instruction(inst, len))
+    } else {
+        None
+    }
+}
+
+fn decode_image(image: &[u32]) -> Vec<(usize, Item)> {
+    let mut items = vec![];
+    let mut pos = 0;
+
+    while pos < image.len() {
+        let item = decode_exact(&image[pos..]).unwrap_or(Item::Word(image[pos]));
+
+        items.push((pos, item));
+
+        pos += item.len();
+    }
+
+    items
+}
+
+/// Writes `image` out as assembler source. Labels from `symbols` are used where they fall on an
+/// instruction boundary. With `listing`, each line also gets a comment with its offset and the
+/// words it assembles to.
+pub fn disassemble<W: Write>(image: &[u32], symbols: &SymbolTable, listing: bool, mut out: W)
+    -> io::Result<()> {
+
+    let items = decode_image(image);
+
+    // The end of the image counts, since a label can go there too
+    let boundaries: BTreeSet<usize> = items.iter().map(|&(pos, _)| pos)
+        .chain(Some(image.len()))
+        .collect();
+
+    let mut labels: BTreeMap<usize, String> = BTreeMap::new();
+
+    for (label, addr) in symbols.iter() {
+        if boundaries.contains(&(addr as usize)) {
+            labels.entry(addr as usize).or_insert_with(|| label.to_owned());
+        }
+    }
+
+    // Generated names get a suffix if the program already has a symbol by that name
+    let unique_name = |target: i64| {
+        let name = format!("loc_{:04x}", target);
+
+        (0..).map(|n| if n == 0 { name.clone() } else { format!("{}_{}", name, n) })
+            .find(|name| symbols.get(name).is_none())
+            .unwrap()
+    };
+
+    for &(pos, item) in &items {
+        if let Item::Instruction(Instruction(_, _, Operand::Relative(off)), _) = item {
+            let target = pos as i64 + off as i64;
+
+            if target >= 0 && boundaries.contains(&(target as usize)) {
+                labels.entry(target as usize).or_insert_with(|| unique_name(target));
+            }
+        }
+    }
+
+    let mut index = 0;
+
+    while index < items.len() {
+        let (pos, item) = items[index];
+
+        if let Some(label) = labels.get(&pos) {
+            writeln!(out, "{}:", label)?;
+        }
+
+        let (text, len) = match item {
+            Item::Instruction(inst, len) => {
+                let text = format_with_labels(inst, |off| {
+                    let target = pos as i64 + off as i64;
+
+                    if target >= 0 {
+                        if let Some(label) = labels.get(&(target as usize)) {
+                            return label.clone();
+                        }
+                    }
+
+                    relative(off)
+                });
+
+                index += 1;
+
+                (text, len)
+            },
+            Item::Word(_) => {
+                // Group up consecutive words, as long as no label needs to go in between
+                let mut words = vec![];
+
+                while let Some(&(word_pos, Item::Word(word))) = items.get(index) {
+                    if words.len() == WORDS_PER_LINE ||
+                        (!words.is_empty() && labels.contains_key(&word_pos)) {
+                        break;
+                    }
+
+                    words.push(format!("{:#x}", word));
+                    index += 1;
+                }
+
+                let len = words.len();
+
+                (format!(".words {{{}}}", words.join(", ")), len)
+            }
+        };
+
+        if listing {
+            let words: Vec<String> = image[pos..pos + len].iter()
+                .map(|word| format!("{:08x}", word))
+                .collect();
+
+            writeln!(out, "    {:<36} ; {:08x}: {}", text, pos, words.join(" "))?;
+        } else {
+            writeln!(out, "    {}", text)?;
+        }
+    }
+
+    if let Some(label) = labels.get(&image.len()) {
+        writeln!(out, "{}:", label)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assemble::{assemble, assemble_with_symbols};
+
+    fn disassemble_to_string(image: &[u32], symbols: &SymbolTable) -> String {
+        let mut out = vec![];
+
+        disassemble(image, symbols, false, &mut out).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    fn round_trip(image: &[u32]) -> String {
+        let source = disassemble_to_string(image, &SymbolTable::new());
+
+        let mut reassembled = vec![];
+
+        assemble(source.as_bytes(), &mut reassembled)
+            .unwrap_or_else(|e| panic!("{}\n{}", e, source));
+
+        assert_eq!(reassembled, image, "{}", source);
+
+        source
+    }
+
+    #[test]
+    fn every_function() {
+        let mut image = vec![];
+
+        for &function in bitcode::FUNCTIONS.values() {
+            bitcode::encode_instruction(
+                Instruction(function, Register::B, Operand::Const(7)), &mut image);
+            bitcode::encode_instruction(
+                Instruction(function, Register::A, Operand::Const(0)), &mut image);
+        }
+
+        round_trip(&image);
+    }
+
+    #[test]
+    fn labels() {
+        let code = b"
+            set a [1]
+        loop:
+            cmp c [2]
+            branchl [done]
+            mul a [c]
+            sub c [1]
+            branch [loop]
+        done:
+            call [$ + 0x20]
+            halt
+        ";
+
+        let mut image = vec![];
+
+        assemble(code, &mut image).unwrap();
+
+        let source = round_trip(&image);
+
+        assert!(source.contains("loc_0002:\n    cmp c [0x2]\n"), "{}", source);
+        assert!(source.contains("branch [loc_0002]"), "{}", source);
+        assert!(source.contains("call [$ + 0x20]"), "{}", source);
+        assert!(source.contains("    halt\n"), "{}", source);
+    }
+
+    #[test]
+    fn symbols() {
+        let code = b"
+        start:
+            branch [start]
+        end:
+        ";
+
+        let mut image = vec![];
+
+        let (_, symbols) = assemble_with_symbols(code, &mut image).unwrap();
+
+        assert_eq!(disassemble_to_string(&image, &symbols),
+                   "start:\n    branch [start]\nend:\n");
+
+        // A symbol that looks like a generated label doesn't get reused for another address
+        let code = b"
+        loc_0002:
+            branch [$ + 2]
+            halt
+        ";
+
+        let mut image = vec![];
+
+        let (_, symbols) = assemble_with_symbols(code, &mut image).unwrap();
+
+        assert_eq!(disassemble_to_string(&image, &symbols),
+                   "loc_0002:\n    branch [loc_0002_1]\nloc_0002_1:\n    halt\n");
+    }
+
+    #[test]
+    fn data() {
+        // Unknown function, reserved bits, a non-canonical Const(0), and a truncated instruction
+        let source = round_trip(&[0x0000_ffff, 0x0080_0001, 0x0000_0001, 0x0000_0000]);
+
+        assert_eq!(source, "    .words {0xffff, 0x800001, 0x1, 0x0}\n");
+    }
+}
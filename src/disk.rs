@@ -0,0 +1,495 @@
+//! Block storage, backed by an image file on the host
+//!
+//! Sectors are transferred directly to and from RAM, one word per request, so the disk has to be
+//! connected to the RAM device as well as the machine. The memory map is a command block:
+//!
+//! ```text
+//! 0: sector number
+//! 1: address in RAM to transfer to or from
+//! 2: command: 1 = read the sector into RAM, 2 = write RAM to the sector. Writing it starts it.
+//! 3: status: 0 = idle, 1 = busy, 2 = done, 3 = error
+//! 4: number of sectors, read only
+//! ```
+//!
+//! The disk interrupts when a command has finished, whether or not it worked. Commands written
+//! while busy are ignored. In the image file, words are little endian.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::{Cursor, SeekFrom};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+use byteorder::*;
+
+use hardware::{Hardware, Id, HardwareMessage, Route};
+use event_pool::Dispatch;
+use integrated_ram::IntegratedRam;
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
+
+pub const SECTOR: usize = 0;
+pub const ADDRESS: usize = 1;
+pub const COMMAND: usize = 2;
+pub const STATUS: usize = 3;
+pub const SECTORS: usize = 4;
+
+pub const READ: u32 = 1;
+pub const WRITE: u32 = 2;
+
+pub const IDLE: u32 = 0;
+pub const BUSY: u32 = 1;
+pub const DONE: u32 = 2;
+pub const ERROR: u32 = 3;
+
+/// In words
+pub const SECTOR_SIZE: usize = 128;
+
+/// Anything that can hold a disk image, e.g. a `File`
+pub trait DiskImage: Read + Write + Seek {}
+
+impl<T> DiskImage for T where T: Read + Write + Seek {}
+
+/// A sector on its way to or from RAM
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Transfer {
+    command: u32,
+    sector: u32,
+    /// Local to the RAM device
+    addr: u32,
+    words: Vec<u32>,
+    /// The next word to transfer
+    index: usize,
+    waiting: bool,
+}
+
+pub struct Disk {
+    id: Option<Id>,
+    machine: Option<Id>,
+    image: Box<DiskImage>,
+    sectors: u32,
+    ram: Id,
+    ram_base: u32,
+
+    registers: IntegratedRam,
+    transfer: Option<Transfer>,
+
+    on: bool,
+    initialize: bool,
+    failed: bool,
+}
+
+impl Disk {
+    /// `ram` is the RAM device to transfer to and from, which the machine has mounted at
+    /// `ram_base`. Addresses in the command block are the machine's.
+    pub fn new(image: Box<DiskImage>, sectors: u32, ram: Id, ram_base: u32) -> Disk {
+        Disk {
+            id: None,
+            machine: None,
+            image: image,
+            sectors: sectors,
+            ram: ram,
+            ram_base: ram_base,
+
+            registers: IntegratedRam::new(5),
+            transfer: None,
+
+            on: false,
+            initialize: false,
+            failed: false,
+        }
+    }
+
+    /// Opens an image file for reading and writing, for `new()`. Any partial sector at the end is
+    /// ignored.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Box<DiskImage>, u32)> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let sectors = file.metadata()?.len() / (SECTOR_SIZE as u64 * 4);
+
+        Ok((Box::new(file), sectors as u32))
+    }
+
+    /// Like `open()`, but reads the whole image into memory, so that writes don't go back to the
+    /// file.
+    pub fn open_copy<P: AsRef<Path>>(path: P) -> io::Result<(Box<DiskImage>, u32)> {
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+
+        let sectors = bytes.len() / (SECTOR_SIZE * 4);
+
+        Ok((Box::new(Cursor::new(bytes)), sectors as u32))
+    }
+
+    fn route(&self) -> Route {
+        Route { from: self.id.unwrap(), to: self.machine.unwrap() }
+    }
+
+    fn ram_route(&self) -> Route {
+        Route { from: self.id.unwrap(), to: self.ram }
+    }
+
+    fn seek(&mut self, sector: u32) -> io::Result<()> {
+        let offset = sector as u64 * SECTOR_SIZE as u64 * 4;
+        self.image.seek(SeekFrom::Start(offset)).map(|_| ())
+    }
+
+    fn read_sector(&mut self, sector: u32) -> io::Result<Vec<u32>> {
+        self.seek(sector)?;
+
+        let mut words = Vec::with_capacity(SECTOR_SIZE);
+
+        for _ in 0..SECTOR_SIZE {
+            words.push(self.image.read_u32::<LittleEndian>()?);
+        }
+
+        Ok(words)
+    }
+
+    fn write_sector(&mut self, sector: u32, words: &[u32]) -> io::Result<()> {
+        self.seek(sector)?;
+
+        for &word in words {
+            self.image.write_u32::<LittleEndian>(word)?;
+        }
+
+        self.image.flush()
+    }
+
+    fn start(&mut self) {
+        let command = self.registers.words[COMMAND];
+        let sector = self.registers.words[SECTOR];
+        let addr = self.registers.words[ADDRESS].wrapping_sub(self.ram_base);
+
+        self.registers.words[STATUS] = BUSY;
+
+        let words = match command {
+            _ if sector >= self.sectors => None,
+            READ => match self.read_sector(sector) {
+                Ok(words) => Some(words),
+                Err(e) => {
+                    warn!("Couldn't read sector {} of the disk image: {}", sector, e);
+                    None
+                }
+            },
+            WRITE => Some(vec![0; SECTOR_SIZE]),
+            _ => None
+        };
+
+        match words {
+            Some(words) => {
+                self.transfer = Some(Transfer {
+                    command: command,
+                    sector: sector,
+                    addr: addr,
+                    words: words,
+                    index: 0,
+                    waiting: false,
+                });
+            },
+            None => self.failed = true
+        }
+    }
+
+    /// Moves the transfer along by a word, or finishes it. True once it has finished.
+    fn advance(&mut self, dispatch: &mut Dispatch) -> bool {
+        use hardware::HardwareMessage::*;
+
+        let transfer = match self.transfer.take() {
+            Some(transfer) => transfer,
+            None => return false
+        };
+
+        if transfer.waiting {
+            self.transfer = Some(transfer);
+            return false;
+        }
+
+        if transfer.index < SECTOR_SIZE {
+            let route = self.ram_route();
+            let addr = transfer.addr.wrapping_add(transfer.index as u32);
+
+            if transfer.command == READ {
+                dispatch.send(MemSetRequest(route, addr, transfer.words[transfer.index]));
+            } else {
+                dispatch.send(MemGetRequest(route, addr));
+            }
+
+            self.transfer = Some(Transfer { waiting: true, ..transfer });
+            return false;
+        }
+
+        if transfer.command == WRITE {
+            if let Err(e) = self.write_sector(transfer.sector, &transfer.words) {
+                warn!("Couldn't write sector {} of the disk image: {}", transfer.sector, e);
+                self.registers.words[STATUS] = ERROR;
+                return true;
+            }
+        }
+
+        self.registers.words[STATUS] = DONE;
+        true
+    }
+}
+
+impl Hardware for Disk {
+    fn set_id(&mut self, id: Id) {
+        self.id = Some(id);
+    }
+
+    fn receive(&mut self, message: HardwareMessage) {
+        use hardware::HardwareMessage::*;
+
+        self.registers.receive(&message);
+
+        match message {
+            InitializeDevice(route) => {
+                self.initialize = true;
+                self.machine = Some(route.from);
+            },
+            MemGetResponse(route, _, val, _) if route.from == self.ram => {
+                if let Some(ref mut transfer) = self.transfer {
+                    transfer.words[transfer.index] = val;
+                    transfer.index += 1;
+                    transfer.waiting = false;
+                }
+            },
+            MemSetResponse(route, ..) if route.from == self.ram => {
+                if let Some(ref mut transfer) = self.transfer {
+                    transfer.index += 1;
+                    transfer.waiting = false;
+                }
+            },
+            MemBusError(route, _) if route.from == self.ram => {
+                if self.transfer.take().is_some() {
+                    self.failed = true;
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn save_state(&self, out: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        out.write_option_u32(self.machine);
+        out.write_bool(self.on);
+        out.write_bool(self.initialize);
+        out.write_bool(self.failed);
+        self.registers.save_state(out);
+
+        match self.transfer {
+            Some(ref transfer) => {
+                out.write_bool(true);
+                out.write_u32(transfer.command);
+                out.write_u32(transfer.sector);
+                out.write_u32(transfer.addr);
+                out.write_words(&transfer.words);
+                out.write_u32(transfer.index as u32);
+                out.write_bool(transfer.waiting);
+            },
+            None => out.write_bool(false)
+        }
+
+        Ok(())
+    }
+
+    fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.machine = input.read_option_u32()?;
+        self.on = input.read_bool()?;
+        self.initialize = input.read_bool()?;
+        self.failed = input.read_bool()?;
+        self.registers.load_state(input)?;
+
+        self.transfer = if input.read_bool()? {
+            Some(Transfer {
+                command: input.read_u32()?,
+                sector: input.read_u32()?,
+                addr: input.read_u32()?,
+                words: input.read_words()?.to_vec(),
+                index: input.read_u32()? as usize,
+                waiting: input.read_bool()?,
+            })
+        } else {
+            None
+        };
+
+        Ok(())
+    }
+
+    fn tick(&mut self, _ts: u64, mut dispatch: Dispatch) {
+        use hardware::HardwareMessage::*;
+
+        if self.initialize {
+            self.registers.reinitialize();
+            self.registers.clear();
+            self.registers.words[SECTORS] = self.sectors;
+
+            self.initialize = false;
+            self.on = true;
+            self.failed = false;
+            self.transfer = None;
+
+            dispatch.send(DeviceReady(self.route()));
+
+            return;
+        }
+
+        if !self.on { return; }
+
+        if self.registers.has_pending_request() {
+            let route = self.route();
+            let status = self.registers.words[STATUS];
+
+            let updated = self.registers.tick(route, &mut dispatch);
+
+            match updated.map(|updated| updated.unwrap() as usize) {
+                Some(COMMAND) if status != BUSY => self.start(),
+                // Read only
+                Some(STATUS) => self.registers.words[STATUS] = status,
+                Some(SECTORS) => self.registers.words[SECTORS] = self.sectors,
+                _ => ()
+            }
+        }
+
+        if self.failed {
+            self.failed = false;
+            self.registers.words[STATUS] = ERROR;
+
+            dispatch.send(IntDeviceToMachine(self.route()));
+        } else if self.advance(&mut dispatch) {
+            dispatch.send(IntDeviceToMachine(self.route()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+
+    use super::*;
+    use assemble::assemble;
+    use data::State;
+    use device::{DeviceConfig, DeviceModel};
+    use event_pool::EventPool;
+    use machine::Machine;
+    use ram::Ram;
+
+    /// Two sectors, the second counting up from 0
+    fn image() -> Box<DiskImage> {
+        let mut bytes = vec![0; SECTOR_SIZE * 4];
+
+        for i in 0..SECTOR_SIZE as u32 {
+            bytes.write_u32::<LittleEndian>(i).unwrap();
+        }
+
+        Box::new(Cursor::new(bytes))
+    }
+
+    fn run(code: &[u8]) -> (State, Rc<RefCell<Ram>>) {
+        let ram = Rc::new(RefCell::new(Ram::new(0x1000)));
+
+        let mut program = vec![];
+        assemble(code, &mut program).unwrap();
+        ram.borrow_mut().words_mut()[..program.len()].copy_from_slice(&program);
+
+        let machine = Rc::new(RefCell::new(Machine::new(State {
+            ip: 0x10000,
+            sp: 0x11000,
+            ..State::default()
+        })));
+
+        let mut pool = EventPool::new();
+
+        let machine_id = pool.add_hardware(machine.clone());
+        let ram_id = pool.add_hardware(ram.clone());
+        let disk_id = pool.add_hardware(Disk::new(image(), 2, ram_id, 0x10000));
+
+        pool.connect(machine_id, ram_id);
+        pool.connect(machine_id, disk_id);
+        pool.connect(disk_id, ram_id);
+
+        pool.initialize_machine(machine_id, &[
+            DeviceConfig {
+                id: ram_id,
+                model: DeviceModel::Ram.number(),
+                interrupt: 0xffff_0000,
+                memmap_base: 0x10000,
+                memmap_size: 0x1000,
+            },
+            DeviceConfig {
+                id: disk_id,
+                model: DeviceModel::Disk.number(),
+                interrupt: 0xffff_0001,
+                memmap_base: 0x8d00,
+                memmap_size: DeviceModel::Disk.memory_size().unwrap(),
+            },
+        ]);
+
+        for _ in 0..5000 {
+            pool.tick();
+        }
+
+        let state = *machine.borrow().state();
+        (state, ram)
+    }
+
+    #[test]
+    fn read_and_write() {
+        // Copies sector 1 to sector 0 through RAM, then reads sector 0 back somewhere else
+        let (state, ram) = run(b"
+                inthset [handler]
+                set a [1]
+                store a [0x8d00]
+                set a [0x10800]
+                store a [0x8d01]
+                set a [1]
+                store a [0x8d02]
+                halt
+
+                set a [0]
+                store a [0x8d00]
+                set a [2]
+                store a [0x8d02]
+                halt
+
+                set a [0x10900]
+                store a [0x8d01]
+                set a [1]
+                store a [0x8d02]
+                halt
+
+                load b [0x8d03]
+                load c [0x8d04]
+                halt
+            handler:
+                intexit
+        ");
+
+        assert!(state.halt);
+        assert_eq!(state.b, DONE);
+        assert_eq!(state.c, 2);
+
+        let ram = ram.borrow();
+        let expected: Vec<u32> = (0..SECTOR_SIZE as u32).collect();
+
+        assert_eq!(&ram.words()[0x800..0x880], &expected[..]);
+        assert_eq!(&ram.words()[0x900..0x980], &expected[..]);
+    }
+
+    #[test]
+    fn bad_sector() {
+        let (state, _) = run(b"
+                inthset [handler]
+                set a [2]
+                store a [0x8d00]
+                set a [1]
+                store a [0x8d02]
+                halt
+            handler:
+                load b [0x8d03]
+                halt
+        ");
+
+        assert_eq!(state.a, 0xffff_0001);
+        assert_eq!(state.b, ERROR);
+    }
+}
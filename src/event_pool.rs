@@ -4,6 +4,7 @@ use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 use hardware::{Hardware, HardwareMessage, Route, Id};
 use device::DeviceConfig;
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
 
 pub struct EventPool {
     ts: u64,
@@ -28,6 +29,11 @@ impl EventPool {
         self.ts
     }
 
+    /// True if there are no messages waiting to be delivered.
+    pub fn is_quiet(&self) -> bool {
+        self.mailboxes.values().all(|mbox| mbox.is_empty())
+    }
+
     pub fn dispatch<'a>(&'a mut self) -> Dispatch<'a> {
         Dispatch {
             ensure_from: None,
@@ -89,6 +95,89 @@ impl EventPool {
     pub fn initialize_machine(&mut self, machine_id: Id, devices: &[DeviceConfig]) {
         self.dispatch().send(HardwareMessage::InitializeMachine(machine_id, devices.to_vec()));
     }
+
+    pub fn save_state(&self, out: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        out.write_u64(self.ts);
+        out.write_u32(self.id_counter);
+
+        out.write_u32(self.routes.len() as u32);
+        for route in &self.routes {
+            out.write_route(*route);
+        }
+
+        out.write_u32(self.mailboxes.len() as u32);
+        for (id, mbox) in &self.mailboxes {
+            out.write_u32(*id);
+            out.write_u32(mbox.len() as u32);
+            for message in mbox {
+                out.write_message(message);
+            }
+        }
+
+        out.write_u32(self.hardware.len() as u32);
+        for (id, hw) in &self.hardware {
+            let mut block = SnapshotWriter::new();
+
+            hw.save_state(&mut block).map_err(|e| match e {
+                SnapshotError::Unsupported => SnapshotError::UnsupportedHardware(*id),
+                other => other
+            })?;
+
+            out.write_u32(*id);
+            out.write_block(block);
+        }
+
+        Ok(())
+    }
+
+    /// The pool must already contain the same hardware, with the same ids, as the pool that
+    /// was saved.
+    pub fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.ts = input.read_u64()?;
+
+        let id_counter = input.read_u32()?;
+
+        if id_counter != self.id_counter {
+            return Err(SnapshotError::Mismatch(
+                format!("expected {} pieces of hardware", id_counter - 1)));
+        }
+
+        self.routes.clear();
+        for _ in 0..input.read_u32()? {
+            self.routes.insert(input.read_route()?);
+        }
+
+        for mbox in self.mailboxes.values_mut() {
+            mbox.clear();
+        }
+        for _ in 0..input.read_u32()? {
+            let id = input.read_u32()?;
+
+            for _ in 0..input.read_u32()? {
+                let message = input.read_message()?;
+
+                self.mailboxes.get_mut(&id)
+                    .ok_or_else(|| SnapshotError::Mismatch(format!("no hardware {}", id)))?
+                    .push_back(message);
+            }
+        }
+
+        for _ in 0..input.read_u32()? {
+            let id = input.read_u32()?;
+            let mut block = input.read_block()?;
+
+            let hw = self.hardware.get_mut(&id)
+                .ok_or_else(|| SnapshotError::Mismatch(format!("no hardware {}", id)))?;
+
+            hw.load_state(&mut block).map_err(|e| match e {
+                SnapshotError::Unsupported => SnapshotError::UnsupportedHardware(id),
+                other => other
+            })?;
+            block.finish()?;
+        }
+
+        Ok(())
+    }
 }
 
 // Just protects hardware from doing anything other than sending messages
@@ -1,10 +1,49 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
 use event_pool::Dispatch;
 use device::DeviceConfig;
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
 
 pub trait Hardware {
     fn set_id(&mut self, id: Id);
     fn receive(&mut self, message: HardwareMessage);
     fn tick(&mut self, ts: u64, dispatch: Dispatch);
+
+    /// Writes everything needed to resume from this exact point to a snapshot. Hardware that
+    /// doesn't implement this prevents the whole pool from being saved.
+    fn save_state(&self, _out: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        Err(SnapshotError::Unsupported)
+    }
+
+    /// Restores state written by `save_state()`. The id has already been set.
+    fn load_state(&mut self, _input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        Err(SnapshotError::Unsupported)
+    }
+}
+
+/// Allows hardware to be inspected from outside of the `EventPool` it was added to, e.g. by a
+/// debugger, by keeping a clone of the `Rc`.
+impl<H> Hardware for Rc<RefCell<H>> where H: Hardware {
+    fn set_id(&mut self, id: Id) {
+        self.borrow_mut().set_id(id);
+    }
+
+    fn receive(&mut self, message: HardwareMessage) {
+        self.borrow_mut().receive(message);
+    }
+
+    fn tick(&mut self, ts: u64, dispatch: Dispatch) {
+        self.borrow_mut().tick(ts, dispatch);
+    }
+
+    fn save_state(&self, out: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        self.borrow().save_state(out)
+    }
+
+    fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.borrow_mut().load_state(input)
+    }
 }
 
 pub type Id = u32;
@@ -27,6 +66,8 @@ pub enum HardwareMessage {
     MemGetResponse(Route, LocalAddr, u32, Cacheable),
     MemSetRequest(Route, LocalAddr, u32),
     MemSetResponse(Route, LocalAddr, u32, Cacheable),
+    /// Sent instead of a response if the device has nothing at the address
+    MemBusError(Route, LocalAddr),
 }
 
 impl HardwareMessage {
@@ -41,7 +82,8 @@ impl HardwareMessage {
             MemGetRequest(route, ..) |
             MemGetResponse(route, ..) |
             MemSetRequest(route, ..) |
-            MemSetResponse(route, ..) => Some(route),
+            MemSetResponse(route, ..) |
+            MemBusError(route, ..) => Some(route),
             _ => None
         }
     }
@@ -0,0 +1,559 @@
+//! Headless harness for running programs to completion
+//!
+//! Meant for tests: assembles (or takes) a program, loads it into RAM the same way the emulator
+//! does, and runs an `EventPool` as fast as possible until the machine halts or a tick budget
+//! runs out.
+//!
+//! ```ignore
+//! let outcome = Harness::from_source(b"set a [6]\nmul a [7]\nhalt\n")?.run();
+//!
+//! assert!(outcome.halted);
+//! assert_eq!(outcome.state.a, 42);
+//! ```
+//!
+//! Devices can be faked with a `ScriptedDevice`, which puts words in its memory and raises
+//! interrupts at scripted ticks, and records what the program does to it. A real `Monitor` can
+//! be mounted too, to check what ends up on the screen.
+
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::mpsc::channel;
+
+use data::*;
+use assemble::{assemble_at, AsmErrors};
+use machine::Machine;
+use ram::Ram;
+use event_pool::{EventPool, Dispatch};
+use hardware::{Hardware, Id, HardwareMessage, Route};
+use device::{DeviceConfig, DeviceModel};
+use integrated_ram::{IntegratedRam, Updated};
+use monitor::Monitor;
+use capture::Image;
+
+pub const RAM_BASE: u32 = 0x10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptEvent {
+    /// Puts a word into the device's memory.
+    Store(u32, u32),
+    /// Sends an interrupt to the machine.
+    Interrupt,
+}
+
+/// A fake device for tests.
+///
+/// Ticks in the script count from when the machine initialized the device, not from when the
+/// pool started.
+#[derive(Debug, Clone)]
+pub struct ScriptedDevice {
+    id: Option<Id>,
+    machine: Option<Id>,
+    ram: IntegratedRam,
+    script: VecDeque<(u64, ScriptEvent)>,
+    started_at: Option<u64>,
+    initialize: bool,
+    interrupted: bool,
+
+    /// Ticks (since initialization) at which the machine interrupted this device.
+    pub interrupts: Vec<u64>,
+    /// Every word the machine stored to this device: (tick, address, value).
+    pub stores: Vec<(u64, u32, u32)>,
+}
+
+impl ScriptedDevice {
+    pub fn new(memory_size: u32) -> ScriptedDevice {
+        ScriptedDevice {
+            id: None,
+            machine: None,
+            ram: IntegratedRam::new(memory_size),
+            script: VecDeque::new(),
+            started_at: None,
+            initialize: false,
+            interrupted: false,
+            interrupts: vec![],
+            stores: vec![],
+        }
+    }
+
+    /// Adds an event to the script. Events must be added in order.
+    pub fn at(mut self, tick: u64, event: ScriptEvent) -> ScriptedDevice {
+        assert!(self.script.back().map(|&(t, _)| t <= tick).unwrap_or(true),
+                "script events must be in order");
+
+        self.script.push_back((tick, event));
+        self
+    }
+
+    /// Stores `word` at `addr`, then interrupts the machine, like a keyboard would.
+    pub fn input_at(self, tick: u64, addr: u32, word: u32) -> ScriptedDevice {
+        self.at(tick, ScriptEvent::Store(addr, word))
+            .at(tick, ScriptEvent::Interrupt)
+    }
+
+    pub fn words(&self) -> &[u32] {
+        &self.ram.words
+    }
+
+    /// True once every scripted event has happened.
+    pub fn is_finished(&self) -> bool {
+        self.script.is_empty()
+    }
+
+    fn route(&self) -> Route {
+        Route { from: self.id.unwrap(), to: self.machine.unwrap() }
+    }
+}
+
+impl Hardware for ScriptedDevice {
+    fn set_id(&mut self, id: Id) {
+        self.id = Some(id);
+    }
+
+    fn receive(&mut self, message: HardwareMessage) {
+        use hardware::HardwareMessage::*;
+
+        self.ram.receive(&message);
+
+        match message {
+            InitializeDevice(route) => {
+                self.initialize = true;
+                self.machine = Some(route.from);
+            },
+            IntMachineToDevice(_) => {
+                self.interrupted = true;
+            },
+            _ => ()
+        }
+    }
+
+    fn tick(&mut self, ts: u64, mut dispatch: Dispatch) {
+        use hardware::HardwareMessage::*;
+
+        if self.initialize {
+            self.ram.reinitialize();
+
+            self.initialize = false;
+            self.started_at = Some(ts);
+
+            dispatch.send(DeviceReady(self.route()));
+
+            return;
+        }
+
+        let started_at = match self.started_at {
+            Some(started_at) => started_at,
+            None => return
+        };
+
+        let now = ts - started_at;
+
+        if self.interrupted {
+            self.interrupts.push(now);
+            self.interrupted = false;
+        }
+
+        let route = self.route();
+
+        if let Some(Updated(addr)) = self.ram.tick(route, &mut dispatch) {
+            self.stores.push((now, addr, self.ram.words[addr as usize]));
+        }
+
+        while self.script.front().map(|&(t, _)| t <= now).unwrap_or(false) {
+            match self.script.pop_front().unwrap().1 {
+                ScriptEvent::Store(addr, word) => {
+                    if let Some(pos) = self.ram.words.get_mut(addr as usize) {
+                        *pos = word;
+                    }
+                },
+                ScriptEvent::Interrupt => {
+                    dispatch.send(IntDeviceToMachine(route));
+                },
+            }
+        }
+    }
+}
+
+/// The result of a run.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub state: State,
+    /// False if the tick budget ran out first.
+    pub halted: bool,
+    pub ticks: u64,
+    /// Contents of RAM, which starts at `RAM_BASE`.
+    pub memory: Vec<u32>,
+    /// In the order they were added to the harness.
+    pub devices: Vec<ScriptedDevice>,
+    /// What the monitor showed at the end, if there was one.
+    pub screen: Option<Image>,
+}
+
+impl Outcome {
+    /// Reads a word of RAM by its address in the machine's address space.
+    pub fn load(&self, addr: u32) -> Option<u32> {
+        addr.checked_sub(RAM_BASE)
+            .and_then(|offset| self.memory.get(offset as usize).cloned())
+    }
+}
+
+struct FakeDevice {
+    device: ScriptedDevice,
+    model: u32,
+    interrupt: u32,
+    memmap_base: u32,
+}
+
+pub struct Harness {
+    program: Vec<u32>,
+    initial_state: State,
+    load_address: u32,
+    ram_size: u32,
+    tick_budget: u64,
+    devices: Vec<FakeDevice>,
+    monitor: Option<u32>,
+}
+
+impl Harness {
+    /// Defaults match the emulator: program loaded at 0x11000, stack at 0x10e00 and 0x2000
+    /// words of RAM at `RAM_BASE`.
+    pub fn new(program: Vec<u32>) -> Harness {
+        Harness {
+            program: program,
+            initial_state: State::default(),
+            load_address: 0x11000,
+            ram_size: 0x2000,
+            tick_budget: 1_000_000,
+            devices: vec![],
+            monitor: None,
+        }.stack_pointer(0x10e00)
+    }
+
+    /// Assembles `code` for the default load address, or its `.org` if it has one.
+    pub fn from_source(code: &[u8]) -> Result<Harness, AsmErrors> {
+        let harness = Harness::new(vec![]);
+
+        let image = assemble_at(code, harness.load_address)?;
+
+        Ok(Harness { program: image.words, ..harness }.load_address(image.base))
+    }
+
+    pub fn load_address(mut self, addr: u32) -> Harness {
+        self.load_address = addr;
+        self
+    }
+
+    pub fn stack_pointer(mut self, addr: u32) -> Harness {
+        self.initial_state.sp = addr;
+        self
+    }
+
+    pub fn ram_size(mut self, words: u32) -> Harness {
+        self.ram_size = words;
+        self
+    }
+
+    pub fn tick_budget(mut self, ticks: u64) -> Harness {
+        self.tick_budget = ticks;
+        self
+    }
+
+    /// Sets the registers to start with. `ip` and `sp` are overridden by the load address and
+    /// stack pointer.
+    pub fn registers(mut self, a: u32, b: u32, c: u32, d: u32) -> Harness {
+        self.initial_state = State { a: a, b: b, c: c, d: d, ..self.initial_state };
+        self
+    }
+
+    /// Mounts a fake device. Its memory size is taken from the device itself.
+    pub fn device(mut self, model: u32, interrupt: u32, memmap_base: u32,
+                  device: ScriptedDevice) -> Harness {
+        self.devices.push(FakeDevice {
+            device: device,
+            model: model,
+            interrupt: interrupt,
+            memmap_base: memmap_base,
+        });
+        self
+    }
+
+    /// Mounts a `Monitor` at `memmap_base`. The emulator and server use 0x80000.
+    pub fn monitor(mut self, memmap_base: u32) -> Harness {
+        self.monitor = Some(memmap_base);
+        self
+    }
+
+    /// Runs until the machine has halted with nothing left that could wake it up (every scripted
+    /// device has finished, and no interrupts are in flight), or until the tick budget runs out.
+    pub fn run(self) -> Outcome {
+        assert!(self.load_address >= RAM_BASE &&
+                self.load_address - RAM_BASE + self.program.len() as u32 <= self.ram_size,
+                "program doesn't fit in RAM");
+
+        let mut ram = Ram::new(self.ram_size);
+
+        {
+            let offset = (self.load_address - RAM_BASE) as usize;
+            ram.words_mut()[offset..offset + self.program.len()].copy_from_slice(&self.program);
+        }
+
+        let ram = Rc::new(RefCell::new(ram));
+
+        let machine = Rc::new(RefCell::new(Machine::new(State {
+            ip: self.load_address,
+            ..self.initial_state
+        })));
+
+        let mut pool = EventPool::new();
+
+        let machine_id = pool.add_hardware(machine.clone());
+        let ram_id = pool.add_hardware(ram.clone());
+
+        pool.connect(machine_id, ram_id);
+
+        let mut configs = vec![
+            DeviceConfig {
+                id: ram_id,
+                model: DeviceModel::Ram.number(),
+                interrupt: 0xffff_0000,
+                memmap_base: RAM_BASE,
+                memmap_size: self.ram_size,
+            }
+        ];
+
+        let mut devices = vec![];
+
+        for fake in self.devices {
+            let memmap_size = fake.device.words().len() as u32;
+
+            let device = Rc::new(RefCell::new(fake.device));
+
+            let id = pool.add_hardware(device.clone());
+
+            pool.connect(machine_id, id);
+
+            configs.push(DeviceConfig {
+                id: id,
+                model: fake.model,
+                interrupt: fake.interrupt,
+                memmap_base: fake.memmap_base,
+                memmap_size: memmap_size,
+            });
+
+            devices.push(device);
+        }
+
+        let monitor = self.monitor.map(|memmap_base| {
+            // Nothing is drawn, so nothing needs to listen
+            let (update_tx, _) = channel();
+
+            let monitor = Rc::new(RefCell::new(Monitor::new(update_tx)));
+
+            let id = pool.add_hardware(monitor.clone());
+
+            pool.connect(machine_id, id);
+
+            configs.push(DeviceConfig {
+                id: id,
+                model: DeviceModel::Monitor.number(),
+                interrupt: 0xffff_0001,
+                memmap_base: memmap_base,
+                memmap_size: DeviceModel::Monitor.memory_size().unwrap(),
+            });
+
+            monitor
+        });
+
+        pool.initialize_machine(machine_id, &configs);
+
+        let mut halted = false;
+
+        while pool.ts() < self.tick_budget {
+            pool.tick();
+
+            let done = {
+                let machine = machine.borrow();
+
+                // A fault is for good, but otherwise an interrupt could still wake it up
+                machine.state().fault.is_some() ||
+                    machine.state().halt &&
+                    !machine.has_pending_interrupts() &&
+                    pool.is_quiet() &&
+                    devices.iter().all(|device| device.borrow().is_finished())
+            };
+
+            if done {
+                halted = true;
+                break;
+            }
+        }
+
+        let ticks = pool.ts();
+
+        drop(pool);
+
+        let state = *machine.borrow().state();
+        let memory = ram.borrow().words().to_vec();
+        let devices = devices.iter().map(|device| device.borrow().clone()).collect();
+        let screen = monitor.map(|monitor| monitor.borrow().capture());
+
+        Outcome {
+            state: state,
+            halted: halted,
+            ticks: ticks,
+            memory: memory,
+            devices: devices,
+            screen: screen,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static FACTORIAL: &'static [u8] = include_bytes!("../asm_examples/factorial.fai");
+    static FIBONACCI: &'static [u8] = include_bytes!("../asm_examples/fibonacci.fai");
+    static HELLO_WORLD: &'static [u8] = include_bytes!("../asm_examples/hello_world.fai");
+    static CLEAR_SCREEN: &'static [u8] = include_bytes!("../asm_examples/clear_screen.fai");
+
+    static HELLO_WORLD_SCREEN: &'static [u8] = include_bytes!("../testdata/hello_world.ppm");
+
+    /// Calls a routine from asm_examples, since none of them are whole programs
+    fn call_example(example: &[u8], a: u32) -> Outcome {
+        let mut code = b"call [Entry]\nhalt\nEntry:\n".to_vec();
+        code.extend(example);
+
+        Harness::from_source(&code).unwrap()
+            .registers(a, 0, 0, 0)
+            .run()
+    }
+
+    #[test]
+    fn factorial_example() {
+        let outcome = call_example(FACTORIAL, 10);
+
+        assert!(outcome.halted);
+        assert_eq!(outcome.state.a, 3628800);
+    }
+
+    #[test]
+    fn fibonacci_example() {
+        let outcome = call_example(FIBONACCI, 12);
+
+        assert!(outcome.halted);
+        assert_eq!(outcome.state.a, 144);
+    }
+
+    #[test]
+    fn hello_world_screen() {
+        let mut code = b"call [Entry]\nhalt\nEntry:\n".to_vec();
+        code.extend(HELLO_WORLD);
+
+        let outcome = Harness::from_source(&code).unwrap().monitor(0x80000).run();
+
+        assert!(outcome.halted);
+
+        let mut ppm = vec![];
+        outcome.screen.unwrap().write_ppm(&mut ppm).unwrap();
+
+        assert!(ppm == HELLO_WORLD_SCREEN, "screen doesn't match testdata/hello_world.ppm");
+    }
+
+    #[test]
+    fn clear_screen_screen() {
+        let mut code = b"
+            set a [0x41414141]
+            store a [0x80000]
+            call [Entry]
+            halt
+        Entry:
+        ".to_vec();
+        code.extend(CLEAR_SCREEN);
+
+        let outcome = Harness::from_source(&code).unwrap()
+            .monitor(0x80000)
+            .tick_budget(10_000_000)
+            .run();
+
+        assert!(outcome.halted);
+        assert!(outcome.screen.unwrap().pixels.iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn clear_screen_keeps_mode_and_palette() {
+        let mut code = b"
+            set a [0x123456]
+            store a [0x80000 + 0x13e00]
+            set a [0xffffff]
+            store a [0x80000 + 0x13e01]
+            set a [1]
+            store a [0x80000 + 0x13f00]
+            set a [0x01010101]
+            store a [0x80000]
+            call [Entry]
+            load b [0x80000 + 0x13f00]
+            load c [0x80000 + 0x13e00]
+            halt
+        Entry:
+        ".to_vec();
+        code.extend(CLEAR_SCREEN);
+
+        let outcome = Harness::from_source(&code).unwrap()
+            .monitor(0x80000)
+            .tick_budget(10_000_000)
+            .run();
+
+        assert!(outcome.halted);
+        assert_eq!(outcome.state.b, 1);
+        assert_eq!(outcome.state.c, 0x123456);
+
+        let screen = outcome.screen.unwrap();
+
+        assert_eq!(screen.width, 320);
+        assert!(screen.pixels.iter().all(|&pixel| pixel == 0x123456));
+    }
+
+    #[test]
+    fn tick_budget() {
+        let outcome = Harness::from_source(b"loop:\nbranch [loop]\n").unwrap()
+            .tick_budget(1000)
+            .run();
+
+        assert!(!outcome.halted);
+        assert_eq!(outcome.ticks, 1000);
+    }
+
+    #[test]
+    fn memory() {
+        let outcome = Harness::from_source(b"set a [0xabcd]\nstore a [0x10010]\nhalt\n").unwrap()
+            .run();
+
+        assert_eq!(outcome.load(0x10010), Some(0xabcd));
+    }
+
+    #[test]
+    fn scripted_device_wakes_machine() {
+        let device = ScriptedDevice::new(1).input_at(50, 0, 0x41);
+
+        let code = b"
+            inthset [handler]
+            halt
+            halt
+        handler:
+            load b [0x8a00]
+            store b [0x8a00] ; echo it back
+            halt
+        ";
+
+        let outcome = Harness::from_source(code).unwrap()
+            .device(DeviceModel::Keyboard.number(), 0xffff_0003, 0x8a00, device)
+            .run();
+
+        assert!(outcome.halted);
+        assert_eq!(outcome.state.a, 0xffff_0003);
+        assert_eq!(outcome.state.b, 0x41);
+        assert_eq!(outcome.devices[0].stores.len(), 1);
+        assert_eq!(outcome.devices[0].stores[0].1, 0);
+    }
+}
@@ -0,0 +1,247 @@
+//! Deterministic record/replay of external input
+//!
+//! Devices that take input from outside of the emulator (the keyboard, the console) poll an
+//! `InputSource` with the current tick. Wrapping a source in a `Recording` logs every word that
+//! comes in along with the tick it arrived on, and a `Replay` of that log feeds the words back at
+//! exactly the same ticks, so the rest of the machine sees exactly the same thing.
+//!
+//! The log is a text file, one event per line:
+//!
+//! ```text
+//! ; fai input log
+//! 1520 keyboard 0x00000064
+//! ```
+
+use std::io;
+use std::io::prelude::*;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::mpsc::{Receiver, TryRecvError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Poll {
+    Word(u32),
+    Empty,
+    Disconnected,
+}
+
+pub trait InputSource {
+    fn poll(&mut self, ts: u64) -> Poll;
+}
+
+impl InputSource for Receiver<u32> {
+    fn poll(&mut self, _ts: u64) -> Poll {
+        match self.try_recv() {
+            Ok(word)                        => Poll::Word(word),
+            Err(TryRecvError::Empty)        => Poll::Empty,
+            Err(TryRecvError::Disconnected) => Poll::Disconnected,
+        }
+    }
+}
+
+impl InputSource for Receiver<u8> {
+    fn poll(&mut self, _ts: u64) -> Poll {
+        match self.try_recv() {
+            Ok(byte)                        => Poll::Word(byte as u32),
+            Err(TryRecvError::Empty)        => Poll::Empty,
+            Err(TryRecvError::Disconnected) => Poll::Disconnected,
+        }
+    }
+}
+
+/// Writes the log. Shared between every `Recording` of a session.
+pub struct InputRecorder {
+    out: Box<Write>,
+}
+
+impl InputRecorder {
+    pub fn new<W: Write + 'static>(out: W) -> io::Result<Rc<RefCell<InputRecorder>>> {
+        let mut recorder = InputRecorder { out: Box::new(out) };
+
+        writeln!(recorder.out, "; fai input log")?;
+        recorder.out.flush()?;
+
+        Ok(Rc::new(RefCell::new(recorder)))
+    }
+
+    pub fn record(&mut self, ts: u64, channel: &str, word: u32) {
+        // Flush every time so that the log survives a crash, which is when it's most useful
+        let result = writeln!(self.out, "{} {} {:#010x}", ts, channel, word)
+            .and_then(|_| self.out.flush());
+
+        if let Err(e) = result {
+            warn!("Failed to record input: {}", e);
+        }
+    }
+}
+
+pub struct Recording<S> {
+    source: S,
+    channel: String,
+    recorder: Rc<RefCell<InputRecorder>>,
+}
+
+impl<S> Recording<S> where S: InputSource {
+    pub fn new(source: S, channel: &str, recorder: Rc<RefCell<InputRecorder>>) -> Recording<S> {
+        Recording {
+            source: source,
+            channel: channel.into(),
+            recorder: recorder,
+        }
+    }
+}
+
+impl<S> InputSource for Recording<S> where S: InputSource {
+    fn poll(&mut self, ts: u64) -> Poll {
+        let result = self.source.poll(ts);
+
+        if let Poll::Word(word) = result {
+            self.recorder.borrow_mut().record(ts, &self.channel, word);
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputEvent {
+    pub ts: u64,
+    pub channel: String,
+    pub word: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputLog {
+    pub events: Vec<InputEvent>,
+}
+
+impl InputLog {
+    pub fn read<R: BufRead>(input: R) -> io::Result<InputLog> {
+        let mut events = vec![];
+
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+
+            let event = if parts.len() == 3 {
+                let word = parts[2];
+                let word = if word.starts_with("0x") { &word[2..] } else { word };
+
+                match (parts[0].parse(), u32::from_str_radix(word, 16)) {
+                    (Ok(ts), Ok(word)) => Some(InputEvent {
+                        ts: ts,
+                        channel: parts[1].into(),
+                        word: word,
+                    }),
+                    _ => None
+                }
+            } else {
+                None
+            };
+
+            match event {
+                Some(event) => events.push(event),
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("bad input log line: {:?}", line)));
+                }
+            }
+        }
+
+        Ok(InputLog { events: events })
+    }
+
+    /// A source that produces the events recorded for `channel`.
+    pub fn replay(&self, channel: &str) -> Replay {
+        Replay {
+            channel: channel.into(),
+            events: self.events.iter()
+                .filter(|event| event.channel == channel)
+                .map(|event| (event.ts, event.word))
+                .collect()
+        }
+    }
+}
+
+pub struct Replay {
+    channel: String,
+    events: VecDeque<(u64, u32)>,
+}
+
+impl InputSource for Replay {
+    /// Never disconnects. Once the log runs out, there's just no more input.
+    fn poll(&mut self, ts: u64) -> Poll {
+        match self.events.front().cloned() {
+            Some((event_ts, word)) if event_ts <= ts => {
+                if event_ts < ts {
+                    warn!("Replay of {} diverged: input recorded at tick {} was only \
+                           accepted at tick {}", self.channel, event_ts, ts);
+                }
+
+                self.events.pop_front();
+                Poll::Word(word)
+            },
+            _ => Poll::Empty
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc::channel;
+
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record_then_replay() {
+        let buf = Rc::new(RefCell::new(vec![]));
+        let recorder = InputRecorder::new(SharedBuf(buf.clone())).unwrap();
+
+        let (tx, rx) = channel::<u32>();
+        let mut recording = Recording::new(rx, "keyboard", recorder);
+
+        assert_eq!(recording.poll(5), Poll::Empty);
+        tx.send(0x64).unwrap();
+        assert_eq!(recording.poll(6), Poll::Word(0x64));
+        tx.send(0x0d).unwrap();
+        assert_eq!(recording.poll(20), Poll::Word(0x0d));
+
+        let log = InputLog::read(&buf.borrow()[..]).unwrap();
+        let mut replay = log.replay("keyboard");
+
+        assert_eq!(replay.poll(5), Poll::Empty);
+        assert_eq!(replay.poll(6), Poll::Word(0x64));
+        assert_eq!(replay.poll(19), Poll::Empty);
+        assert_eq!(replay.poll(20), Poll::Word(0x0d));
+        assert_eq!(replay.poll(21), Poll::Empty);
+    }
+
+    #[test]
+    fn replay_filters_channel() {
+        let log = InputLog::read(&b"1 console 0x41\n2 keyboard 0x42\n"[..]).unwrap();
+
+        let mut replay = log.replay("keyboard");
+
+        assert_eq!(replay.poll(1), Poll::Empty);
+        assert_eq!(replay.poll(2), Poll::Word(0x42));
+    }
+}
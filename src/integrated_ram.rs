@@ -1,10 +1,16 @@
-use hardware::{HardwareMessage, Route, Cacheable};
+use std::collections::VecDeque;
+
+use hardware::{HardwareMessage, Id, Route, Cacheable};
 use event_pool::Dispatch;
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
 
+#[derive(Debug, Clone)]
 pub struct IntegratedRam {
     pub words: Vec<u32>,
     cacheable: Cacheable,
-    request: Option<Request>,
+    /// Oldest first, with who to respond to. Usually that's the machine, but another device
+    /// can access memory directly, e.g. `Disk`.
+    requests: VecDeque<(Id, Request)>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -27,7 +33,7 @@ impl IntegratedRam {
         IntegratedRam {
             words: vec![0; size as usize],
             cacheable: Cacheable::No,
-            request: None
+            requests: VecDeque::new()
         }
     }
 
@@ -39,7 +45,7 @@ impl IntegratedRam {
     }
 
     pub fn reinitialize(&mut self) {
-        self.request = None;
+        self.requests.clear();
     }
 
     pub fn clear(&mut self) {
@@ -49,32 +55,80 @@ impl IntegratedRam {
     }
 
     pub fn has_pending_request(&self) -> bool {
-        self.request.is_some()
+        !self.requests.is_empty()
+    }
+
+    /// Saves the contents and any requests in progress. Whether the memory is cacheable is
+    /// part of the device, not its state.
+    pub fn save_state(&self, out: &mut SnapshotWriter) {
+        out.write_words(&self.words);
+
+        out.write_u32(self.requests.len() as u32);
+
+        for &(requester, request) in &self.requests {
+            out.write_u32(requester);
+
+            match request {
+                Request::Get(addr) => {
+                    out.write_u32(1); out.write_u32(addr);
+                },
+                Request::Set(addr, val) => {
+                    out.write_u32(2); out.write_u32(addr); out.write_u32(val);
+                },
+            }
+        }
+    }
+
+    pub fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.words = input.read_words()?.to_vec();
+
+        self.requests.clear();
+
+        for _ in 0..input.read_u32()? {
+            let requester = input.read_u32()?;
+
+            let request = match input.read_u32()? {
+                1 => Request::Get(input.read_u32()?),
+                2 => Request::Set(input.read_u32()?, input.read_u32()?),
+                other =>
+                    return Err(SnapshotError::Invalid(format!("bad ram request tag {}", other)))
+            };
+
+            self.requests.push_back((requester, request));
+        }
+
+        Ok(())
     }
 
     pub fn receive(&mut self, message: &HardwareMessage) {
         use hardware::HardwareMessage::*;
 
         match *message {
-            MemGetRequest(_, addr) => {
-                self.request = Some(Request::Get(addr));
+            MemGetRequest(route, addr) => {
+                self.requests.push_back((route.from, Request::Get(addr)));
             },
-            MemSetRequest(_, addr, value) => {
-                self.request = Some(Request::Set(addr, value));
+            MemSetRequest(route, addr, value) => {
+                self.requests.push_back((route.from, Request::Set(addr, value)));
             },
             _ => ()
         }
     }
 
+    /// Handles the oldest request. `route` is the device's usual route to the machine.
     pub fn tick(&mut self, route: Route, dispatch: &mut Dispatch) -> Option<Updated> {
         use hardware::HardwareMessage::*;
 
-        if let Some(request) = self.request.take() {
+        if let Some((requester, request)) = self.requests.pop_front() {
+            let route = Route { from: route.from, to: requester };
+
             match request {
                 Request::Get(addr) => {
-                    let result = self.words.get(addr as usize).cloned().unwrap_or(0);
-
-                    dispatch.send(MemGetResponse(route, addr, result, self.cacheable));
+                    match self.words.get(addr as usize) {
+                        Some(&result) =>
+                            dispatch.send(MemGetResponse(route, addr, result, self.cacheable)),
+                        None =>
+                            dispatch.send(MemBusError(route, addr)),
+                    }
 
                     None
                 },
@@ -85,8 +139,7 @@ impl IntegratedRam {
 
                         Some(Updated(addr))
                     } else {
-                        // Fail to set
-                        dispatch.send(MemSetResponse(route, addr, 0, self.cacheable));
+                        dispatch.send(MemBusError(route, addr));
 
                         None
                     }
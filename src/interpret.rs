@@ -11,7 +11,23 @@ pub fn interpret<M>(inst: Instruction, mem: &mut M, state: State) -> Result<Stat
     let Instruction(f, reg, op) = inst;
 
     Ok(match f {
-        Bad => panic!("bad instruction"),
+        Bad => return raise(Exception::BadInstruction, mem, state),
+
+        Div | DivS | DivMod | DivModS if state.operand(op) == 0 =>
+            return raise(Exception::DivideByZero, mem, state),
+
+        Push | Call if state.sp == 0 =>
+            return raise(Exception::StackOverflow, mem, state),
+        Pop | Ret if state.sp == u32::max_value() =>
+            return raise(Exception::StackOverflow, mem, state),
+        IntExit if state.sp > u32::max_value() - 3 =>
+            return raise(Exception::StackOverflow, mem, state),
+
+        // Otherwise a user mode program could take over interrupts, or return from one into
+        // supervisor mode
+        IntHSet | IntPause | IntCont | SetSp | IntHw | IntExit if state.flags.user =>
+            return raise(Exception::PrivilegedInstruction, mem, state),
+
         Nop => state,
         Set => state.register_modify(reg, |_| state.operand(op)),
         Load => {
@@ -23,18 +39,12 @@ pub fn interpret<M>(inst: Instruction, mem: &mut M, state: State) -> Result<Stat
             state
         },
         Cmp => State {
-            flags: {
-                let mut new_flags = Flags {
-                    cmp_l: false, cmp_g: false, cmp_e: false, ..state.flags };
-
-                match state.register(reg).cmp(&state.operand(op)) {
-                    Ordering::Less    => { new_flags.cmp_l = true; },
-                    Ordering::Greater => { new_flags.cmp_g = true; },
-                    Ordering::Equal   => { new_flags.cmp_e = true; }
-                }
-
-                new_flags
-            },
+            flags: compare(state.flags, state.register(reg).cmp(&state.operand(op))),
+            ..state
+        },
+        CmpS => State {
+            flags: compare(state.flags,
+                           (state.register(reg) as i32).cmp(&(state.operand(op) as i32))),
             ..state
         },
         Branch => state.branch(op),
@@ -42,6 +52,8 @@ pub fn interpret<M>(inst: Instruction, mem: &mut M, state: State) -> Result<Stat
         BranchG => if state.flags.cmp_g { state.branch(op) } else { state },
         BranchE => if state.flags.cmp_e { state.branch(op) } else { state },
         BranchNE => if !state.flags.cmp_e { state.branch(op) } else { state },
+        BranchC => if state.flags.carry { state.branch(op) } else { state },
+        BranchO => if state.flags.overflow { state.branch(op) } else { state },
 
         GetSp => state.register_modify(reg, |_| state.sp),
         SetSp => State { sp: state.operand(op), ..state },
@@ -64,10 +76,13 @@ pub fn interpret<M>(inst: Instruction, mem: &mut M, state: State) -> Result<Stat
             State { sp: state.sp + 1, ip: val, ..state }
         },
 
-        Add => state.register_modify(reg, |x| x.wrapping_add(state.operand(op))),
-        Sub => state.register_modify(reg, |x| x.wrapping_sub(state.operand(op))),
-        Mul => state.register_modify(reg, |x| x * state.operand(op)),
+        Add => arithmetic(state, reg, op, u32::overflowing_add, i32::overflowing_add),
+        Sub => arithmetic(state, reg, op, u32::overflowing_sub, i32::overflowing_sub),
+        Mul => arithmetic(state, reg, op, u32::overflowing_mul, i32::overflowing_mul),
         Div => state.register_modify(reg, |x| x / state.operand(op)),
+        DivS => state.register_modify(reg, |x| {
+            (x as i32).wrapping_div(state.operand(op) as i32) as u32
+        }),
         DivMod => {
             let x = state.register(reg);
             let y = state.operand(op);
@@ -80,13 +95,29 @@ pub fn interpret<M>(inst: Instruction, mem: &mut M, state: State) -> Result<Stat
                 ..state
             }
         },
+        DivModS => {
+            let x = state.register(reg) as i32;
+            let y = state.operand(op) as i32;
+
+            // Like divmod, but rounds towards zero, so the remainder has the sign of x
+
+            State {
+                c: x.wrapping_div(y) as u32,
+                d: x.wrapping_rem(y) as u32,
+                ..state
+            }
+        },
 
         Not => state.register_modify(reg, |x| !x),
         And => state.register_modify(reg, |x| x & state.operand(op)),
         Or  => state.register_modify(reg, |x| x | state.operand(op)),
         Xor => state.register_modify(reg, |x| x ^ state.operand(op)),
-        Lsh => state.register_modify(reg, |x| x << state.operand(op)),
-        Rsh => state.register_modify(reg, |x| x >> state.operand(op)),
+        Lsh => state.register_modify(reg, |x| x.checked_shl(state.operand(op)).unwrap_or(0)),
+        Rsh => state.register_modify(reg, |x| x.checked_shr(state.operand(op)).unwrap_or(0)),
+        Ash => state.register_modify(reg, |x| {
+            // Shifting by 31 or more leaves only copies of the sign bit
+            ((x as i32) >> state.operand(op).min(31)) as u32
+        }),
 
         Halt     => State { halt: true, ..state },
         IntSw    => handle_interrupt(state.operand(op), mem, state)?,
@@ -116,11 +147,54 @@ pub fn interpret<M>(inst: Instruction, mem: &mut M, state: State) -> Result<Stat
     })
 }
 
+/// Sets the comparison flags, and leaves the rest alone
+fn compare(flags: Flags, ordering: Ordering) -> Flags {
+    Flags {
+        cmp_l: ordering == Ordering::Less,
+        cmp_g: ordering == Ordering::Greater,
+        cmp_e: ordering == Ordering::Equal,
+        ..flags
+    }
+}
+
+/// `add`, `sub` or `mul`: wraps around, and sets the carry flag if the unsigned result didn't
+/// fit, or the overflow flag if the signed one didn't
+fn arithmetic<U, S>(state: State, reg: Register, op: Operand, unsigned: U, signed: S) -> State
+    where U: Fn(u32, u32) -> (u32, bool), S: Fn(i32, i32) -> (i32, bool) {
+
+    let x = state.register(reg);
+    let y = state.operand(op);
+
+    let (result, carry) = unsigned(x, y);
+    let (_, overflow) = signed(x as i32, y as i32);
+
+    State {
+        flags: Flags { carry: carry, overflow: overflow, ..state.flags },
+        ..state.register_modify(reg, |_| result)
+    }
+}
+
+/// Calls the interrupt handler with the code for `exception`, or halts with it as the fault if
+/// there's no handler.
+pub fn raise<M>(exception: Exception, mem: &mut M, state: State) -> Result<State, M::Error>
+    where M: MemBackend + ?Sized {
+
+    debug!("raise() {:?} at ip = {:#010x}", exception, state.ip);
+
+    if state.inth == 0 || state.sp < 3 {
+        Ok(State { halt: true, fault: Some(exception), ..state })
+    } else {
+        handle_interrupt(exception.code(), mem, state)
+    }
+}
+
 pub fn handle_interrupt<M>(code: u32, mem: &mut M, state: State) -> Result<State, M::Error>
     where M: MemBackend + ?Sized {
 
     if state.inth == 0 {
         Ok(state)
+    } else if state.sp < 3 {
+        raise(Exception::StackOverflow, mem, state)
     } else {
         let new_sp = state.sp - 3;
 
@@ -168,17 +242,60 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn interpret_bad() {
         memless_test(
             State::default(),
             &[
                 Instruction(Bad, A, Const(0))
             ],
-            State::default()
+            State { halt: true, fault: Some(Exception::BadInstruction), ..State::default() }
         )
     }
 
+    #[test]
+    fn exceptions_without_handler() {
+        let mut mem = vec![];
+
+        let cases = &[
+            (Instruction(Div, A, Const(0)), 0x100, Exception::DivideByZero),
+            (Instruction(DivModS, A, Reg(B)), 0x100, Exception::DivideByZero),
+            (Instruction(Push, A, Const(0)), 0, Exception::StackOverflow),
+            (Instruction(Call, A, Const(0x10)), 0, Exception::StackOverflow),
+            (Instruction(Ret, A, Const(0)), 0xffff_ffff, Exception::StackOverflow),
+        ];
+
+        for &(inst, sp, exception) in cases {
+            let state0 = State { sp: sp, a: 5, ..State::default() };
+            let state1 = interpret(inst, &mut mem, state0);
+
+            assert_eq!(state1, State { halt: true, fault: Some(exception), ..state0 });
+        }
+    }
+
+    #[test]
+    fn exception_with_handler() {
+        let mut mem = vec![0; 0x10];
+
+        let state0 = State { ip: 0x20, sp: 0x10, a: 7, inth: 0x40, ..State::default() };
+        let state1 = interpret(Instruction(Div, B, Const(0)), &mut mem, state0);
+
+        assert_eq!(state1, State {
+            ip: 0x40,
+            sp: 0x0d,
+            a: Exception::DivideByZero.code(),
+            flags: Flags { int_pause: true, ..Flags::default() },
+            ..state0
+        });
+
+        assert_eq!(&mem[0x0d..], &[7, 0x20, 0]);
+
+        // Not enough stack left for the handler
+        let state2 = interpret(Instruction(Bad, A, Const(0)), &mut mem, State { sp: 2, ..state0 });
+
+        assert_eq!(state2.fault, Some(Exception::BadInstruction));
+        assert!(state2.halt);
+    }
+
     #[test]
     fn interpret_nop() {
         memless_test(
@@ -289,6 +406,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn interpret_cmps() {
+        let mut mem = vec![];
+
+        let state0 = State { a: 0xffff_ffff, b: 1, ..State::default() };
+
+        let fd = Flags::default();
+
+        let cases = &[
+            (A, Reg(B), Flags { cmp_l: true,  cmp_g: false, cmp_e: false, ..fd }),
+            (B, Reg(A), Flags { cmp_l: false, cmp_g: true,  cmp_e: false, ..fd }),
+            (A, Const(0xffff_ffff), Flags { cmp_l: false, cmp_g: false, cmp_e: true, ..fd }),
+        ];
+
+        for &(reg, op, flags) in cases {
+            let state1 = interpret(Instruction(CmpS, reg, op), &mut mem, state0);
+
+            assert_eq!(state1, State { flags: flags, ..state0 });
+        }
+    }
+
     #[test]
     fn interpret_branch() {
         let mut mem = vec![];
@@ -352,6 +490,20 @@ mod tests {
         test_branch_flag(BranchNE, |flags| !flags.cmp_e);
     }
 
+    #[test]
+    fn interpret_branchc_brancho() {
+        let mut mem = vec![];
+
+        let carry = State { flags: Flags { carry: true, ..Flags::default() }, ..State::default() };
+        let overflow =
+            State { flags: Flags { overflow: true, ..Flags::default() }, ..State::default() };
+
+        assert_eq!(interpret(Instruction(BranchC, A, Const(0x10)), &mut mem, carry).ip, 0x10);
+        assert_eq!(interpret(Instruction(BranchC, A, Const(0x10)), &mut mem, overflow).ip, 0);
+        assert_eq!(interpret(Instruction(BranchO, A, Const(0x10)), &mut mem, overflow).ip, 0x10);
+        assert_eq!(interpret(Instruction(BranchO, A, Const(0x10)), &mut mem, carry).ip, 0);
+    }
+
     #[test]
     fn interpret_getsp() {
         let state0 = State { sp: 0x30, ..State::default() };
@@ -527,6 +679,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn interpret_carry_overflow() {
+        let mut mem = vec![];
+
+        let cases = &[
+            (Add, 0xffff_ffff, 1, 0, true, false),
+            (Add, 0x7fff_ffff, 1, 0x8000_0000, false, true),
+            (Add, 0x8000_0000, 0x8000_0000, 0, true, true),
+            (Sub, 0, 1, 0xffff_ffff, true, false),
+            (Sub, 0x8000_0000, 1, 0x7fff_ffff, false, true),
+            (Sub, 5, 3, 2, false, false),
+            (Mul, 0x1_0000, 0x1_0000, 0, true, true),
+            (Mul, 0xffff_ffff, 2, 0xffff_fffe, true, false),
+            (Mul, 0x4000_0000, 2, 0x8000_0000, false, true),
+        ];
+
+        for &(f, a, b, result, carry, overflow) in cases {
+            let state0 = State { a: a, ..State::default() };
+            let state1 = interpret(Instruction(f, A, Const(b)), &mut mem, state0);
+
+            assert_eq!(state1, State {
+                a: result,
+                flags: Flags { carry: carry, overflow: overflow, ..Flags::default() },
+                ..state0
+            }, "{:?} {:#x} {:#x}", f, a, b);
+        }
+    }
+
     #[test]
     fn interpret_div() {
         let state0 = State { a: 100, b: 100, ..State::default() };
@@ -556,6 +736,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn interpret_divs_divmods() {
+        let state0 = State { a: -7i32 as u32, b: 2, ..State::default() };
+        let state1 = State { a: -3i32 as u32, c: -3i32 as u32, d: -1i32 as u32, ..state0 };
+
+        memless_test(
+            state0,
+            &[
+                Instruction(DivModS, A, Reg(B)),
+                Instruction(DivS, A, Const(-2i32 as u32)),
+                Instruction(DivS, A, Const(-1i32 as u32)),
+            ],
+            state1
+        );
+    }
+
     #[test]
     fn interpret_not() {
         let state0 = State { d: 0xff00_ff00, a: 0x0000_0000, ..State::default() };
@@ -646,6 +842,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn interpret_ash() {
+        let state0 = State { a: 0xf000_0000, b: 0x7000_0000, ..State::default() };
+        let state1 = State { a: 0xffff_ffff, b: 0x0700_0000, ..state0 };
+
+        memless_test(
+            state0,
+            &[
+                Instruction(Ash, A, Const(4)),
+                Instruction(Ash, A, Const(40)),
+                Instruction(Ash, B, Const(4)),
+            ],
+            state1
+        );
+    }
+
     #[test]
     fn interpret_halt() {
         let state0 = State { halt: false, ..State::default() };
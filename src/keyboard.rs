@@ -1,13 +1,15 @@
-use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::mpsc::Receiver;
 
 use hardware::{Hardware, Id, HardwareMessage, Route};
 use event_pool::Dispatch;
 use integrated_ram::IntegratedRam;
+use input_log::{InputSource, Poll};
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
 
 pub struct Keyboard {
     id: Option<Id>,
     machine: Option<Id>,
-    input_rx: Receiver<u32>,
+    input: Box<InputSource>,
 
     ram: IntegratedRam,
 
@@ -19,10 +21,15 @@ pub struct Keyboard {
 
 impl Keyboard {
     pub fn new(input_rx: Receiver<u32>) -> Keyboard {
+        Keyboard::with_source(Box::new(input_rx))
+    }
+
+    /// Takes input from somewhere other than a channel, e.g. an `input_log::Replay`.
+    pub fn with_source(input: Box<InputSource>) -> Keyboard {
         Keyboard {
             id: None,
             machine: None,
-            input_rx: input_rx,
+            input: input,
 
             ram: IntegratedRam::new(1),
 
@@ -60,7 +67,26 @@ impl Hardware for Keyboard {
         }
     }
 
-    fn tick(&mut self, _ts: u64, mut dispatch: Dispatch) {
+    fn save_state(&self, out: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        out.write_option_u32(self.machine);
+        out.write_bool(self.on);
+        out.write_bool(self.initialize);
+        out.write_bool(self.interrupt);
+        out.write_bool(self.acknowledged);
+        self.ram.save_state(out);
+        Ok(())
+    }
+
+    fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.machine = input.read_option_u32()?;
+        self.on = input.read_bool()?;
+        self.initialize = input.read_bool()?;
+        self.interrupt = input.read_bool()?;
+        self.acknowledged = input.read_bool()?;
+        self.ram.load_state(input)
+    }
+
+    fn tick(&mut self, ts: u64, mut dispatch: Dispatch) {
         use hardware::HardwareMessage::*;
 
         if self.initialize {
@@ -93,10 +119,10 @@ impl Hardware for Keyboard {
         }
 
         if self.acknowledged {
-            let result = self.input_rx.try_recv();
+            let result = self.input.poll(ts);
 
             match result {
-                Ok(word) => {
+                Poll::Word(word) => {
                     self.ram.words[0] = word;
                     self.acknowledged = false;
 
@@ -104,11 +130,11 @@ impl Hardware for Keyboard {
 
                     dispatch.send(IntDeviceToMachine(self.route()));
                 },
-                Err(TryRecvError::Disconnected) => {
+                Poll::Disconnected => {
                     warn!("The keyboard's input source seems to have been disconnected");
                     self.on = false;
                 },
-                Err(TryRecvError::Empty) => (),
+                Poll::Empty => (),
             }
             return;
         }
@@ -9,18 +9,33 @@ extern crate nom;
 
 extern crate byteorder;
 extern crate termion;
+extern crate libc;
 
 pub mod data;
 pub mod mem_backend;
 pub mod interpret;
 pub mod bitcode;
 pub mod machine;
+pub mod mpu;
 pub mod assemble;
+pub mod disassemble;
+pub mod object;
 pub mod hardware;
 pub mod event_pool;
 pub mod device;
 pub mod integrated_ram;
 pub mod ram;
 pub mod monitor;
+pub mod terminal_renderer;
+pub mod font;
+pub mod capture;
 pub mod keyboard;
+pub mod timer;
+pub mod disk;
 pub mod stdio_console;
+pub mod uart;
+pub mod symbols;
+pub mod debugger;
+pub mod snapshot;
+pub mod input_log;
+pub mod harness;
@@ -6,13 +6,17 @@ use mem_backend::*;
 use bitcode::*;
 use hardware::{Hardware, HardwareMessage, Id, Route};
 use device::DeviceConfig;
+use mpu::{self, Mpu};
 use event_pool::Dispatch;
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PipelineStage {
     Fetch,
     Execute(Instruction),
     Interrupt(u32),
+    /// Like an interrupt, but raised by the CPU itself. See `Exception`.
+    Exception(Exception),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -23,8 +27,29 @@ pub enum PowerState {
     On
 }
 
+/// An access to memory that nothing answered, and what caused it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusError {
+    pub addr: u32,
+    /// Where the instruction that made the access is, or where the machine was interrupted if
+    /// it was entering an interrupt handler
+    pub ip: u32,
+}
+
+/// A memory access requested by a debugger, performed over the bus between instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugAccess {
+    Peek(u32),
+    Poke(u32, u32),
+}
+
 pub type MemoryError = TransactionalMemError;
 
+/// Where the device config ROM is. Past the end of the configs, it reads as zero, which marks
+/// the end of the list.
+pub const DEVICE_CONFIG_ROM_BASE: u32 = 0x1000;
+pub const DEVICE_CONFIG_ROM_SIZE: u32 = 0x1000;
+
 pub struct Machine {
     id: Option<Id>,
     default_state: State,
@@ -36,6 +61,12 @@ pub struct Machine {
     device_config_rom: Vec<u32>,
     mem_backend: TransactionalMemBackend,
     fake_mem: Option<(u32, Vec<u32>)>,
+    debug_access: Option<DebugAccess>,
+    debug_result: Option<u32>,
+    debug_started: bool,
+    instruction_ip: u32,
+    last_bus_error: Option<BusError>,
+    mpu: Mpu,
 }
 
 impl Machine {
@@ -51,6 +82,69 @@ impl Machine {
             device_config_rom: vec![],
             mem_backend: TransactionalMemBackend::new(),
             fake_mem: None,
+            debug_access: None,
+            debug_result: None,
+            debug_started: false,
+            instruction_ip: default_state.ip,
+            last_bus_error: None,
+            mpu: Mpu::new(),
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn power_state(&self) -> &PowerState {
+        &self.power_state
+    }
+
+    pub fn pipeline_stage(&self) -> PipelineStage {
+        self.pipeline_stage
+    }
+
+    /// The most recent access to an unmapped address, whether or not the program handled it.
+    pub fn last_bus_error(&self) -> Option<BusError> {
+        self.last_bus_error
+    }
+
+    pub fn mpu(&self) -> &Mpu {
+        &self.mpu
+    }
+
+    /// Whether memory accesses are checked by the MPU. Entering a handler from user mode is
+    /// checked too, because the stack it pushes to is the user's. Debugger accesses aren't.
+    fn protected(&self) -> bool {
+        self.state.flags.user && !self.debug_started
+    }
+
+    /// Interrupts that have been received, but not handled yet.
+    pub fn has_pending_interrupts(&self) -> bool {
+        !self.interrupt_queue.is_empty()
+    }
+
+    /// Queues a memory access to be performed the next time the machine is between
+    /// instructions. The result can be collected with `take_debug_result()`.
+    pub fn request_debug_access(&mut self, access: DebugAccess) {
+        self.debug_access = Some(access);
+        self.debug_result = None;
+    }
+
+    /// The value read by a `Peek`, or written by a `Poke`, once the access has completed.
+    pub fn take_debug_result(&mut self) -> Option<u32> {
+        self.debug_result.take()
+    }
+
+    fn debug_perform(&mut self, access: DebugAccess) -> Result<u32, MemoryError> {
+        let result = match access {
+            DebugAccess::Peek(addr) => self.load(addr),
+            DebugAccess::Poke(addr, val) => self.store(addr, val).map(|_| val),
+        };
+
+        // Not the program's fault, so it doesn't raise an exception. There's just nothing there.
+        match result {
+            Err(TransactionalMemError::Unmapped(_)) => Ok(0),
+            result => result
         }
     }
 
@@ -65,6 +159,8 @@ impl Machine {
         let ip = self.state.ip;
         debug!("p_fetch() ip = {:#010x}", ip);
 
+        self.instruction_ip = ip;
+
         let word0 = self.load(ip)?;
         debug!("p_fetch() word0 = {:#010x}", word0);
 
@@ -72,7 +168,7 @@ impl Machine {
             Ok(inst) => (inst, 1),
 
             Err(DecodeError::NeedMore) => {
-                let word1 = self.load(ip + 1)?;
+                let word1 = self.load(ip.wrapping_add(1))?;
                 debug!("p_fetch() word1 = {:#010x}", word1);
 
                 let inst = decode_instruction(&[word0, word1])
@@ -82,7 +178,7 @@ impl Machine {
             }
         };
 
-        self.state.ip += count;
+        self.state.ip = ip.wrapping_add(count);
 
         self.transition(PipelineStage::Execute(inst));
 
@@ -106,6 +202,8 @@ impl Machine {
 
         debug!("p_interrupt() code = {:#010x}", code);
 
+        self.instruction_ip = old_state.ip;
+
         self.state = handle_interrupt(code, self, old_state)?;
 
         self.transition(PipelineStage::Fetch);
@@ -113,16 +211,55 @@ impl Machine {
         Ok(())
     }
 
+    fn p_exception(&mut self, exception: Exception) -> Result<(), MemoryError> {
+        let old_state = self.state;
+
+        debug!("p_exception() exception = {:?}", exception);
+
+        self.state = raise(exception, self, old_state)?;
+
+        self.transition(PipelineStage::Fetch);
+
+        Ok(())
+    }
+
     pub fn advance(&mut self) -> Result<(), MemoryError> {
-        match self.pipeline_stage {
-            PipelineStage::Fetch           => self.p_fetch(),
-            PipelineStage::Execute(inst)   => self.p_execute(inst),
-            PipelineStage::Interrupt(code) => self.p_interrupt(code),
+        let result = match self.pipeline_stage {
+            PipelineStage::Fetch                => self.p_fetch(),
+            PipelineStage::Execute(inst)        => self.p_execute(inst),
+            PipelineStage::Interrupt(code)      => self.p_interrupt(code),
+            PipelineStage::Exception(exception) => self.p_exception(exception),
+        };
+
+        match (result, self.pipeline_stage) {
+            // Couldn't even get into the handler, e.g. because the stack isn't mapped
+            (Err(TransactionalMemError::Unmapped(_)), PipelineStage::Exception(exception)) |
+            (Err(TransactionalMemError::Denied(_)), PipelineStage::Exception(exception)) => {
+                self.state = State { halt: true, fault: Some(exception), ..self.state };
+                self.transition(PipelineStage::Fetch);
+            },
+            (Err(TransactionalMemError::Unmapped(addr)), _) => {
+                debug!("advance() bus error at {:#010x}, ip = {:#010x}",
+                       addr, self.instruction_ip);
+
+                self.last_bus_error = Some(BusError { addr: addr, ip: self.instruction_ip });
+
+                self.transition(PipelineStage::Exception(Exception::UnmappedMemory(addr)));
+            },
+            (Err(TransactionalMemError::Denied(addr)), _) => {
+                debug!("advance() protection fault at {:#010x}, ip = {:#010x}",
+                       addr, self.instruction_ip);
+
+                self.transition(PipelineStage::Exception(Exception::ProtectionFault(addr)));
+            },
+            (result, _) => return result
         }
+
+        Ok(())
     }
 
     pub fn run_until_halt(&mut self) -> Result<(), MemoryError> {
-        while !self.state.halt {
+        while !self.state.halt || self.pipeline_stage != PipelineStage::Fetch {
             self.advance()?;
         }
         Ok(())
@@ -150,6 +287,10 @@ impl Machine {
 
         self.pipeline_stage = PipelineStage::Fetch;
         self.mem_backend.reset();
+        self.debug_started = false;
+        self.instruction_ip = self.state.ip;
+        self.last_bus_error = None;
+        self.mpu = Mpu::new();
 
         self.power_state = PowerState::ReadyForInit;
     }
@@ -183,14 +324,14 @@ impl Machine {
                 if let Some((id, d_addr)) = self.addr_to_device(addr) {
                     dispatch.send(MemGetRequest(self.route(id), d_addr));
                 } else {
-                    self.mem_backend.respond_get(addr, 0);
+                    self.mem_backend.respond_unmapped(addr);
                 }
             },
             TransactionalMemRequest::Set(addr, val) => {
                 if let Some((id, d_addr)) = self.addr_to_device(addr) {
                     dispatch.send(MemSetRequest(self.route(id), d_addr, val));
                 } else {
-                    self.mem_backend.respond_set(addr, 0);
+                    self.mem_backend.respond_unmapped(addr);
                 }
             }
         }
@@ -210,6 +351,11 @@ impl Machine {
                     self.mem_backend.respond_set(addr, val);
                 }
             },
+            MemBusError(route, d_addr) => {
+                if let Some(addr) = self.device_to_addr(route.from, d_addr) {
+                    self.mem_backend.respond_unmapped(addr);
+                }
+            },
             other => panic!("service_mem_response() got {:?}", other)
         }
     }
@@ -235,14 +381,22 @@ impl MemBackend for Machine {
     type Error = MemoryError;
 
     fn load(&mut self, addr: u32) -> Result<u32, MemoryError> {
-        if addr >= 0x1000 && ((addr - 0x1000) as usize) < self.device_config_rom.len() {
-            Ok(self.device_config_rom[(addr - 0x1000) as usize])
+        if self.protected() && !self.mpu.allows(addr, mpu::READ) {
+            Err(TransactionalMemError::Denied(addr))
+
+        } else if self.mpu.contains(addr) {
+            Ok(self.mpu.load(addr))
+
+        } else if addr.wrapping_sub(DEVICE_CONFIG_ROM_BASE) < DEVICE_CONFIG_ROM_SIZE {
+            let index = (addr - DEVICE_CONFIG_ROM_BASE) as usize;
+
+            Ok(self.device_config_rom.get(index).cloned().unwrap_or(0))
 
         } else if let Some((mount_point, ref fake_mem)) = self.fake_mem {
             if addr >= mount_point && ((addr - mount_point) as usize) < fake_mem.len() {
                 Ok(fake_mem[(addr - mount_point) as usize])
             } else {
-                panic!("load out of bounds of fake mem: {:#010x}", addr);
+                Err(TransactionalMemError::Unmapped(addr))
             }
 
         } else {
@@ -251,7 +405,14 @@ impl MemBackend for Machine {
     }
 
     fn store(&mut self, addr: u32, val: u32) -> Result<(), MemoryError> {
-        if addr >= 0x1000 && ((addr - 0x1000) as usize) < self.device_config_rom.len() {
+        if self.protected() && !self.mpu.allows(addr, mpu::WRITE) {
+            Err(TransactionalMemError::Denied(addr))
+
+        } else if self.mpu.contains(addr) {
+            self.mpu.store(addr, val);
+            Ok(())
+
+        } else if addr.wrapping_sub(DEVICE_CONFIG_ROM_BASE) < DEVICE_CONFIG_ROM_SIZE {
             // Read only memory. Do nothing.
             Ok(())
 
@@ -260,7 +421,7 @@ impl MemBackend for Machine {
                 fake_mem[(addr - mount_point) as usize] = val;
                 Ok(())
             } else {
-                panic!("store out of bounds of fake mem: {:#010x} <- {:#010x}", addr, val);
+                Err(TransactionalMemError::Unmapped(addr))
             }
 
         } else {
@@ -274,6 +435,142 @@ impl Hardware for Machine {
         self.id = Some(id);
     }
 
+    fn save_state(&self, out: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        out.write_state(&self.default_state);
+        out.write_state(&self.state);
+
+        match self.power_state {
+            PowerState::Off          => out.write_u32(0),
+            PowerState::ReadyForInit => out.write_u32(1),
+            PowerState::WaitingForDevices(ref devices) => {
+                out.write_u32(2);
+                out.write_words(devices);
+            },
+            PowerState::On           => out.write_u32(3),
+        }
+
+        match self.pipeline_stage {
+            PipelineStage::Fetch => out.write_u32(0),
+            PipelineStage::Execute(inst) => {
+                out.write_u32(1);
+                out.write_instruction(inst);
+            },
+            PipelineStage::Interrupt(code) => {
+                out.write_u32(2);
+                out.write_u32(code);
+            },
+            PipelineStage::Exception(exception) => {
+                out.write_u32(3);
+                out.write_exception(Some(exception));
+            },
+        }
+
+        let interrupt_queue: Vec<u32> = self.interrupt_queue.iter().cloned().collect();
+        out.write_words(&interrupt_queue);
+
+        out.write_u32(self.device_configs.len() as u32);
+        for config in &self.device_configs {
+            out.write_device_config(config);
+        }
+
+        self.mem_backend.save_state(out);
+
+        match self.fake_mem {
+            Some((mount_point, ref words)) => {
+                out.write_bool(true);
+                out.write_u32(mount_point);
+                out.write_words(words);
+            },
+            None => out.write_bool(false)
+        }
+
+        match self.debug_access {
+            None => out.write_u32(0),
+            Some(DebugAccess::Peek(addr)) => {
+                out.write_u32(1); out.write_u32(addr);
+            },
+            Some(DebugAccess::Poke(addr, val)) => {
+                out.write_u32(2); out.write_u32(addr); out.write_u32(val);
+            },
+        }
+        out.write_option_u32(self.debug_result);
+        out.write_bool(self.debug_started);
+
+        out.write_u32(self.instruction_ip);
+
+        match self.last_bus_error {
+            Some(error) => {
+                out.write_bool(true); out.write_u32(error.addr); out.write_u32(error.ip);
+            },
+            None => out.write_bool(false)
+        }
+
+        self.mpu.save_state(out);
+
+        Ok(())
+    }
+
+    fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.default_state = input.read_state()?;
+        self.state = input.read_state()?;
+
+        self.power_state = match input.read_u32()? {
+            0 => PowerState::Off,
+            1 => PowerState::ReadyForInit,
+            2 => PowerState::WaitingForDevices(input.read_words()?.to_vec()),
+            3 => PowerState::On,
+            other => return Err(SnapshotError::Invalid(format!("bad power state {}", other)))
+        };
+
+        self.pipeline_stage = match input.read_u32()? {
+            0 => PipelineStage::Fetch,
+            1 => PipelineStage::Execute(input.read_instruction()?),
+            2 => PipelineStage::Interrupt(input.read_u32()?),
+            3 => match input.read_exception()? {
+                Some(exception) => PipelineStage::Exception(exception),
+                None => return Err(SnapshotError::Invalid("missing exception".into()))
+            },
+            other => return Err(SnapshotError::Invalid(format!("bad pipeline stage {}", other)))
+        };
+
+        self.interrupt_queue = input.read_words()?.iter().cloned().collect();
+
+        self.device_configs = vec![];
+        for _ in 0..input.read_u32()? {
+            self.device_configs.push(input.read_device_config()?);
+        }
+        self.create_device_config_rom();
+
+        self.mem_backend.load_state(input)?;
+
+        self.fake_mem = if input.read_bool()? {
+            Some((input.read_u32()?, input.read_words()?.to_vec()))
+        } else {
+            None
+        };
+
+        self.debug_access = match input.read_u32()? {
+            0 => None,
+            1 => Some(DebugAccess::Peek(input.read_u32()?)),
+            2 => Some(DebugAccess::Poke(input.read_u32()?, input.read_u32()?)),
+            other => return Err(SnapshotError::Invalid(format!("bad debug access {}", other)))
+        };
+        self.debug_result = input.read_option_u32()?;
+        self.debug_started = input.read_bool()?;
+
+        self.instruction_ip = input.read_u32()?;
+
+        self.last_bus_error = if input.read_bool()? {
+            Some(BusError { addr: input.read_u32()?, ip: input.read_u32()? })
+        } else {
+            None
+        };
+
+        self.mpu.load_state(input)?;
+
+        Ok(())
+    }
+
     fn receive(&mut self, message: HardwareMessage) {
         use hardware::HardwareMessage::*;
 
@@ -296,7 +593,7 @@ impl Hardware for Machine {
                            self.interrupt_queue, self.state.flags.int_pause);
                 }
             },
-            MemGetResponse(..) | MemSetResponse(..) => {
+            MemGetResponse(..) | MemSetResponse(..) | MemBusError(..) => {
                 self.service_mem_response(message);
             },
             _ => ()
@@ -337,6 +634,41 @@ impl Hardware for Machine {
             return;
         }
 
+        if let Some(access) = self.debug_access {
+            // Only start the access between instructions, but once started, the memory backend
+            // belongs to it until it has finished.
+            if self.debug_started ||
+                self.pipeline_stage == PipelineStage::Fetch && self.mem_backend.is_empty() {
+
+                self.debug_started = true;
+
+                match self.debug_perform(access) {
+                    Ok(val) => {
+                        self.debug_access = None;
+                        self.debug_result = Some(val);
+                        self.debug_started = false;
+                        self.mem_backend.reset();
+                    },
+
+                    Err(TransactionalMemError::Need(req)) => {
+                        self.mem_backend.retry();
+
+                        self.service_mem_request(req, &mut dispatch);
+                    },
+
+                    Err(TransactionalMemError::Unmapped(_)) |
+                    Err(TransactionalMemError::Denied(_)) => unreachable!()
+                }
+
+                return;
+            }
+        }
+
+        // Only a reset gets it going again
+        if self.state.fault.is_some() {
+            return;
+        }
+
         if !self.interrupt_queue.is_empty() &&
             self.pipeline_stage == PipelineStage::Fetch &&
             !self.state.flags.int_pause {
@@ -357,50 +689,205 @@ impl Hardware for Machine {
                 if let Some(code) = self.state.int_outgoing.take() {
                     self.send_interrupt(code, &mut dispatch);
                 }
+
+                if let Some(fault) = self.state.fault {
+                    warn!("Machine halted at ip = {:#010x}: {}", self.instruction_ip, fault);
+                }
             },
 
             Err(TransactionalMemError::Need(req)) => {
                 self.mem_backend.retry();
 
                 self.service_mem_request(req, &mut dispatch);
-            }
+            },
+
+            Err(TransactionalMemError::Unmapped(_)) |
+            Err(TransactionalMemError::Denied(_)) => unreachable!()
         }
     }
 }
 
-/* TODO: fix these tests
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use std::rc::Rc;
+    use std::cell::RefCell;
 
-    use data::*;
-    use data::Function::*;
-    use data::Register::*;
-    use data::Operand::*;
-
-    static FACTORIAL: &'static [Instruction] = &[
-        Instruction(Set, A, Const(1)), // 00
-        Instruction(Cmp, C, Const(2)), // 02
-        Instruction(BranchL, A, Relative(0x08)), // 04
-        Instruction(Mul, A, Reg(C)), // 06
-        Instruction(Sub, C, Const(1)), // 08
-        Instruction(Branch, A, Relative(-0x08)), // 0A
-        Instruction(Halt, A, Const(0)), // 0C
-    ];
+    use super::*;
+    use assemble::assemble;
+    use device::DeviceModel;
+    use event_pool::EventPool;
+    use harness::Harness;
+    use ram::Ram;
+
+    static FACTORIAL: &'static [u8] = b"
+            set a [1]
+        loop:
+            cmp c [2]
+            branchl [done]
+            mul a [c]
+            sub c [1]
+            branch [loop]
+        done:
+            halt
+    ";
 
     #[test]
     fn factorial() {
-        let mut machine = Machine::new(0x80);
+        let outcome = Harness::from_source(FACTORIAL).unwrap()
+            .registers(0, 0, 10, 0)
+            .run();
 
-        machine.state.c = 10;
-        machine.state.sp = 0x20;
-        machine.state.ip = 0x40;
+        assert!(outcome.halted);
+        assert_eq!(outcome.state.a, 3628800);
+    }
+
+    #[test]
+    fn unmapped_memory_without_handler() {
+        let outcome = Harness::from_source(b"load a [0x5000]\nset b [1]\nhalt\n").unwrap().run();
+
+        assert!(outcome.halted);
+        assert_eq!(outcome.state.fault, Some(Exception::UnmappedMemory(0x5000)));
+        assert_eq!(outcome.state.b, 0);
+    }
+
+    #[test]
+    fn unmapped_memory_with_handler() {
+        let outcome = Harness::from_source(b"
+                inthset [Handler]
+                store a [0x5000]
+                halt
+            Handler:
+                set b [a]
+                halt
+        ").unwrap().run();
+
+        assert!(outcome.halted);
+        assert_eq!(outcome.state.fault, None);
+        assert_eq!(outcome.state.b, Exception::UnmappedMemory(0).code());
+    }
+
+    #[test]
+    fn bus_error_from_device() {
+        // The RAM is mapped as bigger than it really is, so it has to answer with a bus error
+        let mut ram = Ram::new(0x100);
 
-        machine.store_instructions(0x40, FACTORIAL);
+        let mut program = vec![];
+        assemble(b"nop\nload a [0x10200]\nhalt\n", &mut program).unwrap();
+        ram.words_mut()[..program.len()].copy_from_slice(&program);
 
-        machine.run_until_halt();
+        let machine = Rc::new(RefCell::new(Machine::new(State {
+            ip: 0x10000,
+            ..State::default()
+        })));
 
-        assert_eq!(machine.state.a, 3628800);
+        let mut pool = EventPool::new();
+
+        let machine_id = pool.add_hardware(machine.clone());
+        let ram_id = pool.add_hardware(ram);
+
+        pool.connect(machine_id, ram_id);
+
+        pool.initialize_machine(machine_id, &[DeviceConfig {
+            id: ram_id,
+            model: DeviceModel::Ram.number(),
+            interrupt: 0xffff_0000,
+            memmap_base: 0x10000,
+            memmap_size: 0x1000,
+        }]);
+
+        for _ in 0..1000 {
+            pool.tick();
+        }
+
+        let machine = machine.borrow();
+
+        assert!(machine.state().halt);
+        assert_eq!(machine.state().fault, Some(Exception::UnmappedMemory(0x10200)));
+        assert_eq!(machine.last_bus_error(), Some(BusError { addr: 0x10200, ip: 0x10001 }));
+    }
+
+    #[test]
+    fn device_config_rom_ends_with_zero() {
+        // Only the RAM is mounted, so its config is followed by zeroes
+        let outcome = Harness::from_source(b"load a [0x1004]\nload b [0x1fff]\nhalt\n").unwrap()
+            .registers(1, 1, 0, 0)
+            .run();
+
+        assert_eq!(outcome.state.fault, None);
+        assert_eq!((outcome.state.a, outcome.state.b), (0, 0));
+    }
+
+    // Lets the user mode program read its code and use the stack, then drops into it
+    const ENTER_USER_MODE: &'static str = "
+            set a [0x11000]
+            store a [0x0800]
+            set a [0x1000]
+            store a [0x0801]
+            set a [1]
+            store a [0x0802]
+            set a [0x10d00]
+            store a [0x0803]
+            set a [0x100]
+            store a [0x0804]
+            set a [3]
+            store a [0x0805]
+            inthset [Handler]
+            set a [0x400]
+            push a
+            set a [User]
+            push a
+            push a
+            intexit
+        Handler:
+            set b [a]
+            halt
+        User:
+    ";
+
+    #[test]
+    fn protection_fault() {
+        let source = format!("{}
+            set a [0xabcd]
+            push a
+            store a [0x10010]
+            halt
+        ", ENTER_USER_MODE);
+        let outcome = Harness::from_source(source.as_bytes()).unwrap().run();
+
+        assert_eq!(outcome.state.fault, None);
+        assert_eq!(outcome.state.b, Exception::ProtectionFault(0).code());
+        assert!(!outcome.state.flags.user);
+        assert_eq!(outcome.load(0x10dff), Some(0xabcd));
+        assert_eq!(outcome.load(0x10010), Some(0));
+    }
+
+    #[test]
+    fn user_stack_into_supervisor_memory() {
+        // Pushes off the bottom of the user's stack region, so the handler's frame would go
+        // below it
+        let source = format!("{}
+            push a
+            branch [User]
+        ", ENTER_USER_MODE);
+        let outcome = Harness::from_source(source.as_bytes()).unwrap().run();
+
+        assert_eq!(outcome.state.fault.map(|e| e.code()),
+                   Some(Exception::ProtectionFault(0).code()));
+        assert_eq!(outcome.state.b, 0);
+        assert_eq!(outcome.load(0x10cfd), Some(0));
+        assert_eq!(outcome.load(0x10cff), Some(0));
+    }
+
+    #[test]
+    fn privileged_instruction() {
+        let source = format!("{}
+            inthset [0]
+            halt
+        ", ENTER_USER_MODE);
+        let outcome = Harness::from_source(source.as_bytes()).unwrap().run();
+
+        assert_eq!(outcome.state.fault, None);
+        assert_eq!(outcome.state.b, Exception::PrivilegedInstruction.code());
+        assert!(outcome.state.inth != 0);
     }
 }
-*/
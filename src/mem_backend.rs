@@ -1,3 +1,5 @@
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
+
 pub trait MemBackend {
     type Error;
 
@@ -30,6 +32,8 @@ pub struct TransactionalMemBackend {
 pub enum TransactionalMemLog {
     Get(u32, u32),
     Set(u32, u32),
+    /// Nothing is mapped at the address
+    Unmapped(u32),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -40,7 +44,11 @@ pub enum TransactionalMemRequest {
 
 #[derive(Debug, Clone)]
 pub enum TransactionalMemError {
-    Need(TransactionalMemRequest)
+    Need(TransactionalMemRequest),
+    /// The request couldn't be completed, because nothing is mapped at the address
+    Unmapped(u32),
+    /// The MPU doesn't allow the access. See `mpu`.
+    Denied(u32),
 }
 
 impl TransactionalMemBackend {
@@ -79,9 +87,81 @@ impl TransactionalMemBackend {
         }
     }
 
+    /// Fails the pending request, because nothing is mapped at `addr`
+    pub fn respond_unmapped(&mut self, addr: u32) {
+        match self.pending {
+            Some(TransactionalMemRequest::Get(a)) |
+            Some(TransactionalMemRequest::Set(a, _)) if addr == a => {
+                self.committed.push(TransactionalMemLog::Unmapped(addr));
+                self.pending = None;
+            },
+            _ => ()
+        }
+    }
+
     pub fn pending(&self) -> Option<TransactionalMemRequest> {
         self.pending
     }
+
+    /// True if no transaction is in progress.
+    pub fn is_empty(&self) -> bool {
+        self.committed.is_empty() && self.pending.is_none()
+    }
+
+    pub fn save_state(&self, out: &mut SnapshotWriter) {
+        out.write_u32(self.counter as u32);
+
+        out.write_u32(self.committed.len() as u32);
+        for log in &self.committed {
+            match *log {
+                TransactionalMemLog::Get(addr, val) => {
+                    out.write_u32(0); out.write_u32(addr); out.write_u32(val);
+                },
+                TransactionalMemLog::Set(addr, val) => {
+                    out.write_u32(1); out.write_u32(addr); out.write_u32(val);
+                },
+                TransactionalMemLog::Unmapped(addr) => {
+                    out.write_u32(2); out.write_u32(addr);
+                },
+            }
+        }
+
+        match self.pending {
+            None => {
+                out.write_u32(0);
+            },
+            Some(TransactionalMemRequest::Get(addr)) => {
+                out.write_u32(1); out.write_u32(addr);
+            },
+            Some(TransactionalMemRequest::Set(addr, val)) => {
+                out.write_u32(2); out.write_u32(addr); out.write_u32(val);
+            },
+        }
+    }
+
+    pub fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.reset();
+
+        self.counter = input.read_u32()? as usize;
+
+        for _ in 0..input.read_u32()? {
+            self.committed.push(match input.read_u32()? {
+                0 => TransactionalMemLog::Get(input.read_u32()?, input.read_u32()?),
+                1 => TransactionalMemLog::Set(input.read_u32()?, input.read_u32()?),
+                2 => TransactionalMemLog::Unmapped(input.read_u32()?),
+                other => return Err(SnapshotError::Invalid(format!("bad mem log tag {}", other)))
+            });
+        }
+
+        self.pending = match input.read_u32()? {
+            0 => None,
+            1 => Some(TransactionalMemRequest::Get(input.read_u32()?)),
+            2 => Some(TransactionalMemRequest::Set(input.read_u32()?, input.read_u32()?)),
+            other => return Err(SnapshotError::Invalid(format!("bad mem request tag {}", other)))
+        };
+
+        Ok(())
+    }
 }
 
 impl MemBackend for TransactionalMemBackend {
@@ -93,6 +173,10 @@ impl MemBackend for TransactionalMemBackend {
                 self.counter += 1;
                 Ok(val)
             },
+            Some(&TransactionalMemLog::Unmapped(a)) if addr == a => {
+                self.counter += 1;
+                Err(TransactionalMemError::Unmapped(addr))
+            },
             None => {
                 let req = TransactionalMemRequest::Get(addr);
                 self.pending = Some(req);
@@ -111,6 +195,10 @@ impl MemBackend for TransactionalMemBackend {
                 self.counter += 1;
                 Ok(())
             },
+            Some(&TransactionalMemLog::Unmapped(a)) if addr == a => {
+                self.counter += 1;
+                Err(TransactionalMemError::Unmapped(addr))
+            },
             None => {
                 let req = TransactionalMemRequest::Set(addr, val);
                 self.pending = Some(req);
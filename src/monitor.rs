@@ -1,39 +1,356 @@
+//! Video output
+//!
+//! The memory map is:
+//!
+//! ```text
+//! 0x00000...: video RAM, interpreted according to the mode
+//! 0x13e00...: palette, 256 entries of 0xRRGGBB, for the indexed color modes
+//! 0x13f00:    mode: 0 = text, 1 = 320x240 indexed color, 2 = 640x480 indexed color
+//! 0x13f01:    capture: writing here saves the screen as an image, if the host allows it
+//! ```
+//!
+//! In text mode, the screen is 40x20 characters, four to a word. In the indexed color modes, each
+//! byte is a pixel, four to a word, and picks a color from the palette. Either way the first
+//! character or pixel of a word is its least significant byte.
+//!
+//! Video RAM past the end of what the current mode shows can still be written and read back, but
+//! isn't shown. Writing an unknown mode leaves the mode as it was.
+//!
+//! Changes aren't sent as they happen, but collected and sent once per frame. See
+//! `MonitorUpdate`.
+
+use std::collections::BTreeSet;
 use std::sync::mpsc::Sender;
+use std::path::PathBuf;
+
+use byteorder::*;
 
 use hardware::{Hardware, Id, HardwareMessage, Route};
 use event_pool::Dispatch;
 use integrated_ram::{IntegratedRam, Updated};
+use capture::{self, Image};
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
+
+pub const PALETTE: u32 = 0x13e00;
+pub const PALETTE_SIZE: u32 = 0x100;
+pub const MODE: u32 = 0x13f00;
+pub const CAPTURE: u32 = 0x13f01;
+
+/// The whole memory map, which matches `DeviceModel::Monitor.memory_size()`
+pub const SIZE: u32 = 0x14000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoMode {
+    Text,
+    Indexed320x240,
+    Indexed640x480,
+}
+
+impl VideoMode {
+    pub fn number(self) -> u32 {
+        match self {
+            VideoMode::Text           => 0,
+            VideoMode::Indexed320x240 => 1,
+            VideoMode::Indexed640x480 => 2,
+        }
+    }
+
+    pub fn from_number(number: u32) -> Option<VideoMode> {
+        match number {
+            0 => Some(VideoMode::Text),
+            1 => Some(VideoMode::Indexed320x240),
+            2 => Some(VideoMode::Indexed640x480),
+            _ => None
+        }
+    }
+
+    /// In characters for text mode, otherwise in pixels
+    pub fn width(self) -> u32 {
+        match self {
+            VideoMode::Text           => 40,
+            VideoMode::Indexed320x240 => 320,
+            VideoMode::Indexed640x480 => 640,
+        }
+    }
+
+    /// In characters for text mode, otherwise in pixels
+    pub fn height(self) -> u32 {
+        match self {
+            VideoMode::Text           => 20,
+            VideoMode::Indexed320x240 => 240,
+            VideoMode::Indexed640x480 => 480,
+        }
+    }
+
+    pub fn is_text(self) -> bool {
+        self == VideoMode::Text
+    }
+
+    /// How many words of video RAM are shown. There are always four characters or pixels to a
+    /// word.
+    pub fn buffer_size(self) -> u32 {
+        self.width() * self.height() / 4
+    }
+}
+
+/// Consecutive words of video RAM, or entries in the palette, starting at `offset`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub offset: u32,
+    pub words: Vec<u32>,
+}
+
+impl Region {
+    /// Groups sorted offsets into as few regions as possible
+    fn coalesce<I>(offsets: I, words: &[u32]) -> Vec<Region> where I: IntoIterator<Item=u32> {
+        let mut regions: Vec<Region> = vec![];
+
+        for offset in offsets {
+            let word = words[offset as usize];
+
+            if let Some(region) = regions.last_mut() {
+                if region.offset + region.words.len() as u32 == offset {
+                    region.words.push(word);
+                    continue;
+                }
+            }
+
+            regions.push(Region { offset: offset, words: vec![word] });
+        }
+
+        regions
+    }
+}
+
+/// Sent to whoever is showing the screen, at most once per frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MonitorUpdate {
+    /// Everything needed to draw the screen from scratch. Sent after initializing, after the
+    /// mode changes, and on request with `Monitor::resync()`, e.g. for a new client.
+    Resync {
+        mode: VideoMode,
+        /// As much of video RAM as the mode shows
+        words: Vec<u32>,
+        palette: Vec<u32>,
+    },
+    /// What changed since the last frame
+    Frame {
+        words: Vec<Region>,
+        palette: Vec<Region>,
+    },
+}
+
+impl MonitorUpdate {
+    /// As little endian words, for sending to clients:
+    ///
+    /// ```text
+    /// resync: 1, mode, width, height, word count, words..., palette count, palette...
+    /// frame:  2, region count, regions..., palette region count, palette regions...
+    /// region: offset, word count, words...
+    /// ```
+    pub fn encode(&self) -> Vec<u8> {
+        fn write_words(out: &mut Vec<u8>, words: &[u32]) {
+            out.write_u32::<LittleEndian>(words.len() as u32).unwrap();
+
+            for &word in words {
+                out.write_u32::<LittleEndian>(word).unwrap();
+            }
+        }
+
+        fn write_regions(out: &mut Vec<u8>, regions: &[Region]) {
+            out.write_u32::<LittleEndian>(regions.len() as u32).unwrap();
+
+            for region in regions {
+                out.write_u32::<LittleEndian>(region.offset).unwrap();
+                write_words(out, &region.words);
+            }
+        }
+
+        let mut out = vec![];
+
+        match *self {
+            MonitorUpdate::Resync { mode, ref words, ref palette } => {
+                for &word in &[1, mode.number(), mode.width(), mode.height()] {
+                    out.write_u32::<LittleEndian>(word).unwrap();
+                }
+                write_words(&mut out, words);
+                write_words(&mut out, palette);
+            },
+            MonitorUpdate::Frame { ref words, ref palette } => {
+                out.write_u32::<LittleEndian>(2).unwrap();
+                write_regions(&mut out, words);
+                write_regions(&mut out, palette);
+            },
+        }
+
+        out
+    }
+}
+
+/// How many ticks there are between frames by default
+pub const DEFAULT_FRAME_INTERVAL: u64 = 1000;
+
+/// The palette after initialization: 3 bits of red, 3 of green and 2 of blue.
+pub fn default_palette() -> Vec<u32> {
+    (0..PALETTE_SIZE).map(|index| {
+        let r = (index >> 5) & 0x7;
+        let g = (index >> 2) & 0x7;
+        let b = index & 0x3;
+
+        (r * 255 / 7) << 16 | (g * 255 / 7) << 8 | (b * 255 / 3)
+    }).collect()
+}
+
+/// How video RAM starts out: blank, in text mode, with the default palette
+fn blank_vid_ram() -> IntegratedRam {
+    let mut vid_ram = IntegratedRam::new(SIZE);
+
+    vid_ram.words[PALETTE as usize..(PALETTE + PALETTE_SIZE) as usize]
+        .copy_from_slice(&default_palette());
+
+    vid_ram
+}
 
 pub struct Monitor {
     id: Option<Id>,
     machine: Option<Id>,
 
-    update_tx: Sender<(u32, u32)>,
+    update_tx: Sender<MonitorUpdate>,
+    frame_interval: u64,
+    next_frame: u64,
+    capture_path: Option<String>,
 
     vid_ram: IntegratedRam,
 
     on: bool,
-    initialize: bool
+    initialize: bool,
+
+    // Changed since the last frame
+    dirty_words: BTreeSet<u32>,
+    dirty_palette: BTreeSet<u32>,
+
+    // After restoring a snapshot, the receiving end of update_tx has to be told about
+    // everything that's on the screen
+    resend: bool,
 }
 
 impl Monitor {
-    pub fn new(update_tx: Sender<(u32, u32)>) -> Monitor {
+    pub fn new(update_tx: Sender<MonitorUpdate>) -> Monitor {
         Monitor {
             id: None,
             machine: None,
 
             update_tx: update_tx,
+            frame_interval: DEFAULT_FRAME_INTERVAL,
+            next_frame: 0,
+            capture_path: None,
 
-            vid_ram: IntegratedRam::new(0),
+            vid_ram: blank_vid_ram(),
 
             on: false,
-            initialize: false
+            initialize: false,
+
+            dirty_words: BTreeSet::new(),
+            dirty_palette: BTreeSet::new(),
+
+            resend: false,
         }
     }
 
+    /// Sends a frame every `ticks` ticks instead of `DEFAULT_FRAME_INTERVAL`
+    pub fn with_frame_interval(mut self, ticks: u64) -> Monitor {
+        self.frame_interval = ticks;
+        self
+    }
+
+    /// Lets the program save the screen to `path` through the capture register. Any `{}` in
+    /// the path is replaced with the value written, so that it can save more than one.
+    pub fn with_capture_path(mut self, path: &str) -> Monitor {
+        self.capture_path = Some(path.into());
+        self
+    }
+
+    /// What's on the screen right now
+    pub fn capture(&self) -> Image {
+        capture::render(self.mode(), &self.vid_ram.words, self.palette())
+    }
+
+    fn capture_to_path(&self, value: u32) {
+        if let Some(ref path) = self.capture_path {
+            let path = PathBuf::from(path.replace("{}", &value.to_string()));
+
+            match self.capture().save(&path) {
+                Ok(()) => info!("Saved the screen to {}", path.display()),
+                Err(e) => warn!("Couldn't save the screen to {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Sends everything on the screen with the next frame
+    pub fn resync(&mut self) {
+        self.resend = true;
+    }
+
+    pub fn mode(&self) -> VideoMode {
+        VideoMode::from_number(self.vid_ram.words[MODE as usize]).unwrap_or(VideoMode::Text)
+    }
+
+    pub fn vid_ram(&self) -> &[u32] {
+        &self.vid_ram.words
+    }
+
+    pub fn palette(&self) -> &[u32] {
+        &self.vid_ram.words[PALETTE as usize..(PALETTE + PALETTE_SIZE) as usize]
+    }
+
     fn route(&self) -> Route {
         Route { from: self.id.unwrap(), to: self.machine.unwrap() }
     }
+
+    fn send(&self, update: MonitorUpdate) {
+        // Nobody might be watching, e.g. in a headless test
+        let _ = self.update_tx.send(update);
+    }
+
+    fn send_frame(&mut self) {
+        if self.resend {
+            let mode = self.mode();
+
+            self.send(MonitorUpdate::Resync {
+                mode: mode,
+                words: self.vid_ram.words[..mode.buffer_size() as usize].to_vec(),
+                palette: self.palette().to_vec(),
+            });
+
+            self.resend = false;
+        } else if !self.dirty_words.is_empty() || !self.dirty_palette.is_empty() {
+            self.send(MonitorUpdate::Frame {
+                words: Region::coalesce(self.dirty_words.iter().cloned(), &self.vid_ram.words),
+                palette: Region::coalesce(self.dirty_palette.iter().cloned(), self.palette()),
+            });
+        }
+
+        self.dirty_words.clear();
+        self.dirty_palette.clear();
+    }
+
+    fn updated(&mut self, addr: u32, old_mode: VideoMode) {
+        let word = self.vid_ram.words[addr as usize];
+
+        if addr == MODE {
+            match VideoMode::from_number(word) {
+                Some(mode) if mode != old_mode => self.resend = true,
+                Some(_) => (),
+                None => self.vid_ram.words[MODE as usize] = old_mode.number(),
+            }
+        } else if addr == CAPTURE {
+            self.capture_to_path(word);
+        } else if addr >= PALETTE && addr < PALETTE + PALETTE_SIZE {
+            self.dirty_palette.insert(addr - PALETTE);
+        } else if addr < old_mode.buffer_size() {
+            self.dirty_words.insert(addr);
+        }
+    }
 }
 
 impl Hardware for Monitor {
@@ -55,26 +372,191 @@ impl Hardware for Monitor {
         }
     }
 
-    fn tick(&mut self, _ts: u64, mut dispatch: Dispatch) {
+    fn save_state(&self, out: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        out.write_option_u32(self.machine);
+        out.write_bool(self.on);
+        out.write_bool(self.initialize);
+        self.vid_ram.save_state(out);
+        Ok(())
+    }
+
+    fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.machine = input.read_option_u32()?;
+        self.on = input.read_bool()?;
+        self.initialize = input.read_bool()?;
+        self.vid_ram.load_state(input)?;
+        self.resend = true;
+        self.next_frame = 0;
+        Ok(())
+    }
+
+    fn tick(&mut self, ts: u64, mut dispatch: Dispatch) {
         use hardware::HardwareMessage::*;
 
         if self.initialize {
-            self.vid_ram = IntegratedRam::new(200); // 40x20 = 200 words
+            self.vid_ram = blank_vid_ram();
 
             self.initialize = false;
             self.on = true;
+            self.resend = true;
+            self.next_frame = 0;
 
             dispatch.send(DeviceReady(self.route()));
 
             return;
         }
-        
+
         if !self.on { return; }
 
         let route = self.route();
+        let mode = self.mode();
 
         if let Some(Updated(addr)) = self.vid_ram.tick(route, &mut dispatch) {
-            self.update_tx.send((addr, self.vid_ram.words[addr as usize])).unwrap();
+            self.updated(addr, mode);
+        }
+
+        if ts >= self.next_frame {
+            self.send_frame();
+
+            self.next_frame = ts + self.frame_interval;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::sync::mpsc::channel;
+
+    use super::*;
+    use assemble::assemble;
+    use data::State;
+    use device::{DeviceConfig, DeviceModel};
+    use event_pool::EventPool;
+    use machine::Machine;
+    use ram::Ram;
+
+    fn run(code: &[u8]) -> (Vec<MonitorUpdate>, Rc<RefCell<Monitor>>) {
+        let mut ram = Ram::new(0x1000);
+
+        let mut program = vec![];
+        assemble(code, &mut program).unwrap();
+        ram.words_mut()[..program.len()].copy_from_slice(&program);
+
+        let machine = Machine::new(State { ip: 0x10000, ..State::default() });
+
+        let (update_tx, update_rx) = channel();
+        let monitor = Rc::new(RefCell::new(Monitor::new(update_tx).with_frame_interval(800)));
+
+        let mut pool = EventPool::new();
+
+        let machine_id = pool.add_hardware(machine);
+        let ram_id = pool.add_hardware(ram);
+        let monitor_id = pool.add_hardware(monitor.clone());
+
+        pool.connect(machine_id, ram_id);
+        pool.connect(machine_id, monitor_id);
+
+        pool.initialize_machine(machine_id, &[
+            DeviceConfig {
+                id: ram_id,
+                model: DeviceModel::Ram.number(),
+                interrupt: 0xffff_0000,
+                memmap_base: 0x10000,
+                memmap_size: 0x1000,
+            },
+            DeviceConfig {
+                id: monitor_id,
+                model: DeviceModel::Monitor.number(),
+                interrupt: 0xffff_0001,
+                memmap_base: 0x80000,
+                memmap_size: DeviceModel::Monitor.memory_size().unwrap(),
+            },
+        ]);
+
+        for _ in 0..1000 {
+            pool.tick();
         }
+
+        (update_rx.try_iter().collect(), monitor)
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(VideoMode::Text.buffer_size(), 200);
+        assert!(VideoMode::Indexed640x480.buffer_size() <= PALETTE);
+        assert_eq!(DeviceModel::Monitor.memory_size(), Some(SIZE));
+    }
+
+    #[test]
+    fn capture_before_initialized() {
+        let monitor = Monitor::new(channel().0);
+
+        assert_eq!(monitor.mode(), VideoMode::Text);
+        assert!(monitor.capture().pixels.iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn switch_mode() {
+        let (updates, monitor) = run(b"
+            set a [0x41424344]
+            store a [0x80000]
+            store a [0x80000 + 200] ; not shown
+            set a [1]
+            store a [0x80000 + 0x13f00]
+            set a [0x00ff00]
+            store a [0x80000 + 0x13e01]
+            set a [7]
+            store a [0x80000 + 0x13f00] ; not a mode
+            halt
+        ");
+
+        let monitor = monitor.borrow();
+
+        assert_eq!(monitor.mode(), VideoMode::Indexed320x240);
+        assert_eq!(monitor.vid_ram()[200], 0x41424344);
+        assert_eq!(monitor.palette()[1], 0x00ff00);
+
+        // Everything happens before the second frame, so it's all in one resync
+        assert_eq!(updates.len(), 2);
+
+        match updates[1] {
+            MonitorUpdate::Resync { mode, ref words, ref palette } => {
+                assert_eq!(mode, VideoMode::Indexed320x240);
+                assert_eq!(words.len(), 19200);
+                assert_eq!(words[0], 0x41424344);
+                assert_eq!(palette[1], 0x00ff00);
+            },
+            ref other => panic!("expected a resync, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn dirty_regions() {
+        let (updates, _) = run(b"
+            set a [0x80000 + 10]
+        loop:
+            store a [a]
+            add a [1]
+            cmp a [0x80000 + 20]
+            branchl [loop]
+            store a [0x80000 + 30]
+            store a [0x80000 + 0x13e00]
+            halt
+        ");
+
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[1], MonitorUpdate::Frame {
+            words: vec![
+                Region { offset: 10, words: (0x8000a..0x80014).collect() },
+                Region { offset: 30, words: vec![0x80014] },
+            ],
+            palette: vec![Region { offset: 0, words: vec![0x80014] }],
+        });
+
+        let encoded = updates[1].encode();
+        assert_eq!(encoded.len(), 4 * (1 + 1 + (2 + 10) + (2 + 1) + 1 + (2 + 1)));
+        assert_eq!(&encoded[..12], &[2, 0, 0, 0, 2, 0, 0, 0, 10, 0, 0, 0]);
     }
 }
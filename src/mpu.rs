@@ -0,0 +1,112 @@
+//! Memory protection unit
+//!
+//! In user mode (`Flags::user`), a program can only access memory inside one of the MPU's
+//! regions, and only in the ways that region allows. Fetching an instruction counts as reading.
+//! In supervisor mode everything is allowed, including setting up the regions, which are memory
+//! mapped:
+//!
+//! ```text
+//! 0x0800 + 3n + 0: base address of region n
+//! 0x0800 + 3n + 1: size of region n, in words (0 = unused)
+//! 0x0800 + 3n + 2: permissions: bit 0 = read, bit 1 = write
+//! ```
+//!
+//! There are 4 regions. The MPU's own registers can't be accessed from user mode at all.
+//!
+//! Entering an interrupt or exception handler from user mode pushes to the user's stack, so that
+//! is checked too. If the frame isn't allowed, the machine halts with a fault.
+
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
+
+pub const MPU_BASE: u32 = 0x0800;
+pub const MPU_REGIONS: usize = 4;
+
+pub const READ: u32 = 1 << 0;
+pub const WRITE: u32 = 1 << 1;
+
+const WORDS_PER_REGION: usize = 3;
+const SIZE: usize = MPU_REGIONS * WORDS_PER_REGION;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Mpu {
+    registers: [u32; SIZE],
+}
+
+impl Mpu {
+    pub fn new() -> Mpu {
+        Mpu::default()
+    }
+
+    /// True if `addr` is one of the MPU's registers
+    pub fn contains(&self, addr: u32) -> bool {
+        (addr.wrapping_sub(MPU_BASE) as usize) < SIZE
+    }
+
+    pub fn load(&self, addr: u32) -> u32 {
+        self.registers[(addr - MPU_BASE) as usize]
+    }
+
+    pub fn store(&mut self, addr: u32, val: u32) {
+        self.registers[(addr - MPU_BASE) as usize] = val;
+    }
+
+    /// Whether a user mode access to `addr` with `permission` (`READ` or `WRITE`) is allowed
+    pub fn allows(&self, addr: u32, permission: u32) -> bool {
+        !self.contains(addr) && self.registers.chunks(WORDS_PER_REGION).any(|region| {
+            addr.wrapping_sub(region[0]) < region[1] && region[2] & permission == permission
+        })
+    }
+
+    pub fn save_state(&self, out: &mut SnapshotWriter) {
+        out.write_words(&self.registers);
+    }
+
+    pub fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let words = input.read_words()?;
+
+        if words.len() != SIZE {
+            return Err(SnapshotError::Invalid(format!("bad MPU size {}", words.len())));
+        }
+
+        self.registers.copy_from_slice(words);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions() {
+        let mut mpu = Mpu::new();
+
+        assert!(!mpu.allows(0x10000, READ));
+
+        mpu.store(MPU_BASE + 0, 0x10000);
+        mpu.store(MPU_BASE + 1, 0x100);
+        mpu.store(MPU_BASE + 2, READ);
+
+        mpu.store(MPU_BASE + 3, 0x10080);
+        mpu.store(MPU_BASE + 4, 0x10);
+        mpu.store(MPU_BASE + 5, READ | WRITE);
+
+        assert!(mpu.allows(0x10000, READ));
+        assert!(mpu.allows(0x100ff, READ));
+        assert!(!mpu.allows(0x10100, READ));
+        assert!(!mpu.allows(0xffff, READ));
+
+        assert!(!mpu.allows(0x10000, WRITE));
+        assert!(mpu.allows(0x10080, WRITE));
+        assert!(!mpu.allows(0x10090, WRITE));
+
+        // Even if a region covers them
+        mpu.store(MPU_BASE + 6, 0);
+        mpu.store(MPU_BASE + 7, 0x10000);
+        mpu.store(MPU_BASE + 8, READ | WRITE);
+
+        assert!(mpu.allows(0x07ff, WRITE));
+        assert!(!mpu.allows(MPU_BASE + 1, READ));
+    }
+}
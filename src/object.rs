@@ -0,0 +1,643 @@
+//! Relocatable objects, and linking them into an image
+//!
+//! The assembler can produce an object instead of an image. An object has named sections (code
+//! goes in `text` unless told otherwise), each with its words and a list of relocations, which
+//! are the words that can't be filled in until it's known where everything will be loaded.
+//! Labels that are exported can be used by other objects, which have to import them.
+//!
+//! Relocations are resolved as follows, where S is the address of the target, A is the addend,
+//! and P is the address of the word being relocated:
+//!
+//! ```text
+//! Absolute: S + A
+//! Relative: S + A - P
+//! ```
+//!
+//! The file format is little endian. Strings are a length in bytes followed by UTF-8.
+//!
+//! ```text
+//! magic, version
+//! name
+//! section count, sections: name, has origin (byte), origin, word count, words,
+//!                          relocation count, relocations: offset, kind, target, addend
+//!                          line count, lines: offset, file, line
+//! symbol count, symbols: name, section, offset, exported
+//! import count, imports: name
+//! ```
+
+use std::io;
+use std::io::prelude::*;
+use std::collections::BTreeMap;
+
+use byteorder::*;
+
+use symbols::{SymbolTable, SourceLine};
+
+pub const MAGIC: u32 = 0x4f49_4146; // "FAIO"
+pub const VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    Absolute,
+    Relative,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelocationTarget {
+    /// The start of a section in the same object
+    Section(usize),
+    /// An imported symbol
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    /// Offset of the word to fill in, within its section
+    pub offset: u32,
+    pub kind: RelocationKind,
+    pub target: RelocationTarget,
+    pub addend: i32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    /// Where the section has to go, if it had an `.org`
+    pub origin: Option<u32>,
+    pub words: Vec<u32>,
+    pub relocations: Vec<Relocation>,
+    /// Offset within the section, and the line the code there came from
+    pub lines: Vec<(u32, SourceLine)>,
+}
+
+impl Section {
+    pub fn new(name: &str) -> Section {
+        Section { name: name.into(), ..Section::default() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: usize,
+    pub offset: u32,
+    pub exported: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Object {
+    /// Usually the source file it was assembled from. Only used for messages.
+    pub name: String,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub imports: Vec<String>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_string<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    out.write_u32::<LittleEndian>(s.len() as u32)?;
+    out.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(input: &mut R) -> io::Result<String> {
+    let len = input.read_u32::<LittleEndian>()?;
+
+    let mut bytes = vec![];
+
+    input.take(len as u64).read_to_end(&mut bytes)?;
+
+    if bytes.len() != len as usize {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated string"));
+    }
+
+    String::from_utf8(bytes).map_err(|e| invalid(format!("bad string: {}", e)))
+}
+
+impl Object {
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        out.write_u32::<LittleEndian>(MAGIC)?;
+        out.write_u32::<LittleEndian>(VERSION)?;
+
+        write_string(&mut out, &self.name)?;
+
+        out.write_u32::<LittleEndian>(self.sections.len() as u32)?;
+
+        for section in &self.sections {
+            write_string(&mut out, &section.name)?;
+
+            out.write_u8(section.origin.is_some() as u8)?;
+            out.write_u32::<LittleEndian>(section.origin.unwrap_or(0))?;
+
+            out.write_u32::<LittleEndian>(section.words.len() as u32)?;
+
+            for &word in &section.words {
+                out.write_u32::<LittleEndian>(word)?;
+            }
+
+            out.write_u32::<LittleEndian>(section.relocations.len() as u32)?;
+
+            for reloc in &section.relocations {
+                out.write_u32::<LittleEndian>(reloc.offset)?;
+
+                out.write_u8(match reloc.kind {
+                    RelocationKind::Absolute => 0,
+                    RelocationKind::Relative => 1,
+                })?;
+
+                match reloc.target {
+                    RelocationTarget::Section(index) => {
+                        out.write_u8(0)?;
+                        out.write_u32::<LittleEndian>(index as u32)?;
+                    },
+                    RelocationTarget::Symbol(ref name) => {
+                        out.write_u8(1)?;
+                        write_string(&mut out, name)?;
+                    },
+                }
+
+                out.write_i32::<LittleEndian>(reloc.addend)?;
+            }
+
+            out.write_u32::<LittleEndian>(section.lines.len() as u32)?;
+
+            for &(offset, ref source) in &section.lines {
+                out.write_u32::<LittleEndian>(offset)?;
+                write_string(&mut out, source.file.as_ref().map(|s| &s[..]).unwrap_or(""))?;
+                out.write_u32::<LittleEndian>(source.line)?;
+            }
+        }
+
+        out.write_u32::<LittleEndian>(self.symbols.len() as u32)?;
+
+        for symbol in &self.symbols {
+            write_string(&mut out, &symbol.name)?;
+            out.write_u32::<LittleEndian>(symbol.section as u32)?;
+            out.write_u32::<LittleEndian>(symbol.offset)?;
+            out.write_u8(symbol.exported as u8)?;
+        }
+
+        out.write_u32::<LittleEndian>(self.imports.len() as u32)?;
+
+        for import in &self.imports {
+            write_string(&mut out, import)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut input: R) -> io::Result<Object> {
+        if input.read_u32::<LittleEndian>()? != MAGIC {
+            return Err(invalid("not a fai object".into()));
+        }
+
+        let version = input.read_u32::<LittleEndian>()?;
+
+        if version != VERSION {
+            return Err(invalid(format!("unsupported object version {}", version)));
+        }
+
+        let mut object = Object::default();
+
+        object.name = read_string(&mut input)?;
+
+        let section_count = input.read_u32::<LittleEndian>()?;
+
+        for _ in 0..section_count {
+            let mut section = Section::new(&read_string(&mut input)?);
+
+            let has_origin = input.read_u8()? != 0;
+            let origin = input.read_u32::<LittleEndian>()?;
+
+            if has_origin {
+                section.origin = Some(origin);
+            }
+
+            let word_count = input.read_u32::<LittleEndian>()?;
+
+            for _ in 0..word_count {
+                section.words.push(input.read_u32::<LittleEndian>()?);
+            }
+
+            let reloc_count = input.read_u32::<LittleEndian>()?;
+
+            for _ in 0..reloc_count {
+                let offset = input.read_u32::<LittleEndian>()?;
+
+                let kind = match input.read_u8()? {
+                    0 => RelocationKind::Absolute,
+                    1 => RelocationKind::Relative,
+                    n => return Err(invalid(format!("bad relocation kind {}", n)))
+                };
+
+                let target = match input.read_u8()? {
+                    0 => RelocationTarget::Section(input.read_u32::<LittleEndian>()? as usize),
+                    1 => RelocationTarget::Symbol(read_string(&mut input)?),
+                    n => return Err(invalid(format!("bad relocation target {}", n)))
+                };
+
+                let addend = input.read_i32::<LittleEndian>()?;
+
+                section.relocations.push(Relocation {
+                    offset: offset,
+                    kind: kind,
+                    target: target,
+                    addend: addend,
+                });
+            }
+
+            let line_count = input.read_u32::<LittleEndian>()?;
+
+            for _ in 0..line_count {
+                let offset = input.read_u32::<LittleEndian>()?;
+                let file = read_string(&mut input)?;
+                let line = input.read_u32::<LittleEndian>()?;
+
+                let file = if file.is_empty() { None } else { Some(file) };
+
+                section.lines.push((offset, SourceLine::new(file, line)));
+            }
+
+            object.sections.push(section);
+        }
+
+        let symbol_count = input.read_u32::<LittleEndian>()?;
+
+        for _ in 0..symbol_count {
+            object.symbols.push(Symbol {
+                name: read_string(&mut input)?,
+                section: input.read_u32::<LittleEndian>()? as usize,
+                offset: input.read_u32::<LittleEndian>()?,
+                exported: input.read_u8()? != 0,
+            });
+        }
+
+        let import_count = input.read_u32::<LittleEndian>()?;
+
+        for _ in 0..import_count {
+            object.imports.push(read_string(&mut input)?);
+        }
+
+        object.check()?;
+
+        Ok(object)
+    }
+
+    /// Makes sure that everything refers to something that exists, so that linking can't go out
+    /// of bounds.
+    fn check(&self) -> io::Result<()> {
+        for section in &self.sections {
+            for reloc in &section.relocations {
+                if reloc.offset as usize >= section.words.len() {
+                    return Err(invalid(format!("relocation at {:#x} is outside of section {}",
+                                               reloc.offset, section.name)));
+                }
+
+                if let RelocationTarget::Section(index) = reloc.target {
+                    if index >= self.sections.len() {
+                        return Err(invalid(format!("relocation refers to missing section {}",
+                                                   index)));
+                    }
+                }
+            }
+        }
+
+        for symbol in &self.symbols {
+            if symbol.section >= self.sections.len() {
+                return Err(invalid(format!("symbol {} refers to missing section {}",
+                                           symbol.name, symbol.section)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The result of linking: a contiguous image to be loaded at `base`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    pub base: u32,
+    pub words: Vec<u32>,
+    /// Offsets are relative to `base`, like the assembler's symbol files.
+    pub symbols: SymbolTable,
+}
+
+/// The most words a linked image can span, from the start of its first section to the end of its
+/// last. The image is stored contiguously, so sections placed far apart would take up the gap too.
+pub const MAX_IMAGE_WORDS: u32 = 0x100_0000;
+
+/// Links objects together into one image.
+///
+/// Sections with the same name are put together, in the order the objects were given. Each goes
+/// at the address given for it in `placements`, or at its `.org`, or right after the previous one
+/// if it has neither. The first section goes at `base` unless it's placed somewhere else.
+pub fn link(objects: &[Object], placements: &BTreeMap<String, u32>, base: u32)
+    -> Result<Image, String> {
+
+    // Names of output sections, in order of first appearance, and their sizes
+    let mut output: Vec<(&str, u32)> = vec![];
+
+    // Where each output section has to go because of an `.org`
+    let mut origins: Vec<Option<u32>> = vec![];
+
+    // Where each section of each object is, within its output section
+    let mut parts: Vec<Vec<(usize, u32)>> = vec![];
+
+    for object in objects {
+        let mut object_parts = vec![];
+
+        for section in &object.sections {
+            let index = match output.iter().position(|&(name, _)| name == section.name) {
+                Some(index) => index,
+                None => {
+                    output.push((&section.name, 0));
+                    origins.push(None);
+                    output.len() - 1
+                }
+            };
+
+            if let Some(origin) = section.origin {
+                // Only the start of a section can be given an address
+                if output[index].1 != 0 || origins[index].is_some() {
+                    return Err(format!("{} has .org {:#x} for section {}, but it isn't the \
+                                        first part of that section", object.name, origin,
+                                       section.name));
+                }
+
+                origins[index] = Some(origin);
+            }
+
+            object_parts.push((index, output[index].1));
+
+            output[index].1 += section.words.len() as u32;
+        }
+
+        parts.push(object_parts);
+    }
+
+    for name in placements.keys() {
+        if !output.iter().any(|&(output_name, _)| output_name == name) {
+            return Err(format!("can't place section {}: no object has it", name));
+        }
+    }
+
+    let mut addresses: Vec<u32> = vec![];
+    let mut cursor = base;
+
+    for (&(name, size), &origin) in output.iter().zip(&origins) {
+        let addr = placements.get(name).cloned().or(origin).unwrap_or(cursor);
+
+        addresses.push(addr);
+
+        cursor = addr.checked_add(size)
+            .ok_or_else(|| format!("section {} doesn't fit below 0xffffffff", name))?;
+    }
+
+    let mut by_address: Vec<usize> = (0..output.len()).collect();
+
+    by_address.sort_by_key(|&index| addresses[index]);
+
+    for pair in by_address.windows(2) {
+        let (a, b) = (pair[0], pair[1]);
+
+        if addresses[a] + output[a].1 > addresses[b] {
+            return Err(format!("section {} ({:#x}..{:#x}) overlaps section {} at {:#x}",
+                               output[a].0, addresses[a], addresses[a] + output[a].1,
+                               output[b].0, addresses[b]));
+        }
+    }
+
+    let image_base = by_address.first().map(|&index| addresses[index]).unwrap_or(base);
+    let image_end = by_address.last()
+        .map(|&index| addresses[index] + output[index].1)
+        .unwrap_or(base);
+
+    if image_end - image_base > MAX_IMAGE_WORDS {
+        let (first, last) = (by_address[0], by_address[by_address.len() - 1]);
+
+        return Err(format!("sections {} at {:#x} and {} at {:#x} are too far apart to link into \
+                            one image (more than {:#x} words)", output[first].0,
+                           addresses[first], output[last].0, addresses[last], MAX_IMAGE_WORDS));
+    }
+
+    let section_addr = |object: usize, section: usize| -> u32 {
+        let (index, offset) = parts[object][section];
+        addresses[index] + offset
+    };
+
+    let mut exports: BTreeMap<&str, (u32, &str)> = BTreeMap::new();
+
+    for (object_index, object) in objects.iter().enumerate() {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.exported) {
+            let addr = section_addr(object_index, symbol.section) + symbol.offset;
+
+            if let Some(&(_, other)) = exports.get(&symbol.name[..]) {
+                return Err(format!("symbol {} is exported by both {} and {}",
+                                   symbol.name, other, object.name));
+            }
+
+            exports.insert(&symbol.name, (addr, &object.name));
+        }
+    }
+
+    let mut image = Image {
+        base: image_base,
+        words: vec![0; (image_end - image_base) as usize],
+        symbols: SymbolTable::new(),
+    };
+
+    for (object_index, object) in objects.iter().enumerate() {
+        for (section_index, section) in object.sections.iter().enumerate() {
+            let addr = section_addr(object_index, section_index);
+            let start = (addr - image_base) as usize;
+
+            image.words[start..start + section.words.len()].copy_from_slice(&section.words);
+
+            for reloc in &section.relocations {
+                let target = match reloc.target {
+                    RelocationTarget::Section(index) => section_addr(object_index, index),
+                    RelocationTarget::Symbol(ref name) => {
+                        exports.get(&name[..]).map(|&(addr, _)| addr).ok_or_else(|| {
+                            format!("undefined symbol {} (imported by {})", name, object.name)
+                        })?
+                    },
+                };
+
+                let value = target.wrapping_add(reloc.addend as u32);
+
+                let value = match reloc.kind {
+                    RelocationKind::Absolute => value,
+                    RelocationKind::Relative => value.wrapping_sub(addr + reloc.offset),
+                };
+
+                image.words[start + reloc.offset as usize] = value;
+            }
+
+            for &(offset, ref source) in &section.lines {
+                image.symbols.insert_line(addr + offset - image_base, source.clone());
+            }
+        }
+
+        // Local symbols first, so that exported ones win if there's a clash
+        for symbol in object.symbols.iter().filter(|symbol| !symbol.exported) {
+            let addr = section_addr(object_index, symbol.section) + symbol.offset;
+
+            image.symbols.insert(symbol.name.clone(), addr - image_base);
+        }
+    }
+
+    for (name, &(addr, _)) in &exports {
+        image.symbols.insert((*name).into(), addr - image_base);
+    }
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assemble::assemble_object;
+
+    fn object(name: &str, code: &[u8]) -> Object {
+        let mut object = assemble_object(code).unwrap();
+        object.name = name.into();
+        object
+    }
+
+    fn library() -> Object {
+        object("lib", b"
+            .export Double
+        Double:
+            add a [a]
+            ret
+        ")
+    }
+
+    fn main() -> Object {
+        object("main", b"
+            .import Double
+            load a [Value]
+            call [Double]
+            halt
+
+            .section data
+        Value:
+            .words {21, Value}
+        ")
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut object = main();
+
+        object.sections[1].origin = Some(0x10000);
+
+        let mut buf = vec![];
+
+        object.write(&mut buf).unwrap();
+
+        assert_eq!(Object::read(&buf[..]).unwrap(), object);
+    }
+
+    #[test]
+    fn link_placed() {
+        let mut placements = BTreeMap::new();
+
+        placements.insert("text".into(), 0x11000);
+        placements.insert("data".into(), 0x10000);
+
+        let image = link(&[main(), library()], &placements, 0).unwrap();
+
+        assert_eq!(image.base, 0x10000);
+        assert_eq!(image.symbols.get("Value"), Some(0));
+        assert_eq!(image.symbols.get("Double"), Some(0x1005));
+
+        // Data, and an absolute reference to itself
+        assert_eq!(&image.words[0..2], &[21, 0x10000]);
+
+        // load a [Value], where the operand is relative to the instruction
+        assert_eq!(image.words[0x1001], 0x10000u32.wrapping_sub(0x11000));
+
+        // call [Double], from 0x11002 to 0x11005
+        assert_eq!(image.words[0x1003], 3);
+    }
+
+    #[test]
+    fn link_in_order() {
+        let image = link(&[main(), library()], &BTreeMap::new(), 0x100).unwrap();
+
+        assert_eq!(image.base, 0x100);
+        assert_eq!(image.symbols.get("Double"), Some(5));
+        assert_eq!(image.symbols.get("Value"), Some(7));
+        assert_eq!(image.words[8], 0x107);
+    }
+
+    #[test]
+    fn link_origin() {
+        let data = object("data", b"
+            .section data
+            .org 0x10000
+            .export Table
+        Table:
+            .words {Table, Table.end}
+        Table.end:
+        ");
+
+        let image = link(&[library(), data.clone()], &BTreeMap::new(), 0x11000).unwrap();
+
+        assert_eq!(image.base, 0x10000);
+        assert_eq!(image.symbols.get("Double"), Some(0x1000));
+        assert_eq!(&image.words[0..2], &[0x10000, 0x10002]);
+
+        // Explicit placement wins over .org
+        let mut placements = BTreeMap::new();
+
+        placements.insert("data".into(), 0x12000);
+
+        let image = link(&[library(), data], &placements, 0x11000).unwrap();
+
+        assert_eq!(image.base, 0x11000);
+        assert_eq!(&image.words[0x1000..0x1002], &[0x12000, 0x12002]);
+    }
+
+    #[test]
+    fn undefined_symbol() {
+        let err = link(&[main()], &BTreeMap::new(), 0).unwrap_err();
+
+        assert_eq!(err, "undefined symbol Double (imported by main)");
+    }
+
+    #[test]
+    fn duplicate_export() {
+        let err = link(&[library(), library()], &BTreeMap::new(), 0).unwrap_err();
+
+        assert_eq!(err, "symbol Double is exported by both lib and lib");
+    }
+
+    #[test]
+    fn overlap() {
+        let mut placements = BTreeMap::new();
+
+        placements.insert("data".into(), 0x4);
+
+        let err = link(&[main()], &placements, 0).unwrap_err();
+
+        assert!(err.contains("overlaps"), "{}", err);
+    }
+
+    #[test]
+    fn too_far_apart() {
+        let mut placements = BTreeMap::new();
+
+        placements.insert("data".into(), 0);
+        placements.insert("text".into(), 0x8000_0000);
+
+        let err = link(&[main()], &placements, 0).unwrap_err();
+
+        assert_eq!(err, "sections data at 0x0 and text at 0x80000000 are too far apart to link \
+                         into one image (more than 0x1000000 words)");
+    }
+}
@@ -1,6 +1,7 @@
 use hardware::{Hardware, Id, Route, HardwareMessage};
 use event_pool::Dispatch;
 use integrated_ram::IntegratedRam;
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
 
 pub struct Ram {
     id: Option<Id>,
@@ -53,6 +54,21 @@ impl Hardware for Ram {
         }
     }
 
+    fn save_state(&self, out: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        out.write_option_u32(self.machine);
+        out.write_bool(self.on);
+        out.write_bool(self.initialize);
+        self.ram.save_state(out);
+        Ok(())
+    }
+
+    fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.machine = input.read_option_u32()?;
+        self.on = input.read_bool()?;
+        self.initialize = input.read_bool()?;
+        self.ram.load_state(input)
+    }
+
     fn tick(&mut self, _ts: u64, mut dispatch: Dispatch) {
         use hardware::HardwareMessage::*;
 
@@ -0,0 +1,528 @@
+//! Machine snapshots (save states)
+//!
+//! A snapshot captures an entire `EventPool`: its clock, routes, pending messages and the state
+//! of every piece of hardware in it. Restoring requires a pool with the same hardware added in
+//! the same order, since a snapshot only holds state, not the hardware itself.
+//!
+//! The file format is a sequence of little endian 32-bit words:
+//!
+//! ```text
+//! 0: magic (FAIS)
+//! 1: version
+//! 2...: pool contents
+//! ```
+//!
+//! Hardware state is stored as a length-prefixed block per device, written by
+//! `Hardware::save_state()`. Devices that don't implement it can't be snapshotted.
+
+use std::io;
+use std::io::prelude::*;
+use std::fmt;
+use std::error::Error;
+
+use byteorder::*;
+
+use data::*;
+use bitcode;
+use hardware::{HardwareMessage, Route, Id, Cacheable};
+use device::DeviceConfig;
+use event_pool::EventPool;
+
+pub const MAGIC: u32 = 0x5349_4146; // "FAIS", little endian
+pub const VERSION: u32 = 6;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    Invalid(String),
+    /// The hardware can't be saved or restored.
+    Unsupported,
+    /// As above, for a specific piece of hardware in a pool.
+    UnsupportedHardware(Id),
+    /// The snapshot doesn't match the hardware in the pool.
+    Mismatch(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::Io(ref e) => write!(f, "I/O error: {}", e),
+            SnapshotError::BadMagic => write!(f, "not a fai snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Invalid(ref s) => write!(f, "invalid snapshot: {}", s),
+            SnapshotError::Unsupported => write!(f, "hardware does not support snapshots"),
+            SnapshotError::UnsupportedHardware(id) =>
+                write!(f, "hardware {} does not support snapshots", id),
+            SnapshotError::Mismatch(ref s) => write!(f, "snapshot does not match hardware: {}", s),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn description(&self) -> &str {
+        "snapshot error"
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> SnapshotError {
+        SnapshotError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotWriter {
+    words: Vec<u32>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> SnapshotWriter {
+        SnapshotWriter::default()
+    }
+
+    pub fn into_words(self) -> Vec<u32> {
+        self.words
+    }
+
+    pub fn write_u32(&mut self, word: u32) {
+        self.words.push(word);
+    }
+
+    pub fn write_u64(&mut self, n: u64) {
+        self.write_u32(n as u32);
+        self.write_u32((n >> 32) as u32);
+    }
+
+    pub fn write_bool(&mut self, b: bool) {
+        self.write_u32(b as u32);
+    }
+
+    pub fn write_option_u32(&mut self, opt: Option<u32>) {
+        match opt {
+            Some(word) => { self.write_bool(true); self.write_u32(word); },
+            None       => { self.write_bool(false); }
+        }
+    }
+
+    /// Length-prefixed
+    pub fn write_words(&mut self, words: &[u32]) {
+        self.write_u32(words.len() as u32);
+        self.words.extend(words.iter().cloned());
+    }
+
+    pub fn write_state(&mut self, state: &State) {
+        self.write_u32(state.ip);
+        self.write_u32(state.sp);
+        self.write_u32(state.a);
+        self.write_u32(state.b);
+        self.write_u32(state.c);
+        self.write_u32(state.d);
+        self.write_u32(state.inth);
+        self.write_option_u32(state.int_outgoing);
+        self.write_bool(state.halt);
+
+        self.write_exception(state.fault);
+        self.write_u32(state.flags.into());
+    }
+
+    /// The exception's code (or 0 for none), then the address it's about, if any
+    pub fn write_exception(&mut self, exception: Option<Exception>) {
+        match exception {
+            Some(exception) => {
+                self.write_u32(exception.code());
+                self.write_u32(exception.addr().unwrap_or(0));
+            },
+            None => {
+                self.write_u32(0); self.write_u32(0);
+            },
+        }
+    }
+
+    pub fn write_instruction(&mut self, inst: Instruction) {
+        let mut words = vec![];
+        bitcode::encode_instruction(inst, &mut words);
+        self.write_words(&words);
+    }
+
+    pub fn write_route(&mut self, route: Route) {
+        self.write_u32(route.to);
+        self.write_u32(route.from);
+    }
+
+    pub fn write_device_config(&mut self, config: &DeviceConfig) {
+        self.write_u32(config.id);
+        self.write_u32(config.model);
+        self.write_u32(config.interrupt);
+        self.write_u32(config.memmap_base);
+        self.write_u32(config.memmap_size);
+    }
+
+    pub fn write_message(&mut self, message: &HardwareMessage) {
+        use hardware::HardwareMessage::*;
+
+        match *message {
+            InitializeMachine(to, ref configs) => {
+                self.write_u32(0);
+                self.write_u32(to);
+                self.write_u32(configs.len() as u32);
+                for config in configs {
+                    self.write_device_config(config);
+                }
+            },
+            InitializeDevice(route)   => { self.write_u32(1); self.write_route(route); },
+            DeviceReady(route)        => { self.write_u32(2); self.write_route(route); },
+            IntMachineToDevice(route) => { self.write_u32(3); self.write_route(route); },
+            IntDeviceToMachine(route) => { self.write_u32(4); self.write_route(route); },
+            MemGetRequest(route, addr) => {
+                self.write_u32(5);
+                self.write_route(route);
+                self.write_u32(addr);
+            },
+            MemGetResponse(route, addr, val, cacheable) => {
+                self.write_u32(6);
+                self.write_route(route);
+                self.write_u32(addr);
+                self.write_u32(val);
+                self.write_bool(cacheable == Cacheable::Yes);
+            },
+            MemSetRequest(route, addr, val) => {
+                self.write_u32(7);
+                self.write_route(route);
+                self.write_u32(addr);
+                self.write_u32(val);
+            },
+            MemSetResponse(route, addr, val, cacheable) => {
+                self.write_u32(8);
+                self.write_route(route);
+                self.write_u32(addr);
+                self.write_u32(val);
+                self.write_bool(cacheable == Cacheable::Yes);
+            },
+            MemBusError(route, addr) => {
+                self.write_u32(9);
+                self.write_route(route);
+                self.write_u32(addr);
+            },
+        }
+    }
+
+    /// Length-prefixed, so that a reader can check that it consumed exactly the right amount.
+    pub fn write_block(&mut self, block: SnapshotWriter) {
+        self.write_words(&block.words);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SnapshotReader<'a> {
+    words: &'a [u32],
+    pos: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(words: &'a [u32]) -> SnapshotReader<'a> {
+        SnapshotReader { words: words, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.words.len()
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SnapshotError> {
+        let word = *self.words.get(self.pos).ok_or(SnapshotError::Truncated)?;
+        self.pos += 1;
+        Ok(word)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SnapshotError> {
+        let lo = self.read_u32()? as u64;
+        let hi = self.read_u32()? as u64;
+        Ok(lo | (hi << 32))
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.read_u32()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(SnapshotError::Invalid(format!("bad bool {:#x}", other)))
+        }
+    }
+
+    pub fn read_option_u32(&mut self) -> Result<Option<u32>, SnapshotError> {
+        if self.read_bool()? {
+            Ok(Some(self.read_u32()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn read_words(&mut self) -> Result<&'a [u32], SnapshotError> {
+        let len = self.read_u32()? as usize;
+
+        if self.words.len() - self.pos < len {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let words = &self.words[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(words)
+    }
+
+    pub fn read_state(&mut self) -> Result<State, SnapshotError> {
+        Ok(State {
+            ip: self.read_u32()?,
+            sp: self.read_u32()?,
+            a: self.read_u32()?,
+            b: self.read_u32()?,
+            c: self.read_u32()?,
+            d: self.read_u32()?,
+            inth: self.read_u32()?,
+            int_outgoing: self.read_option_u32()?,
+            halt: self.read_bool()?,
+            fault: self.read_exception()?,
+            flags: Flags::from(self.read_u32()?),
+        })
+    }
+
+    pub fn read_exception(&mut self) -> Result<Option<Exception>, SnapshotError> {
+        match (self.read_u32()?, self.read_u32()?) {
+            (0, _) => Ok(None),
+            (code, addr) => Exception::from_code(code, addr).map(Some).ok_or_else(|| {
+                SnapshotError::Invalid(format!("bad exception code {:#x}", code))
+            })
+        }
+    }
+
+    pub fn read_instruction(&mut self) -> Result<Instruction, SnapshotError> {
+        let words = self.read_words()?;
+
+        if words.is_empty() {
+            return Err(SnapshotError::Invalid("empty instruction".into()));
+        }
+
+        bitcode::decode_instruction(words)
+            .map_err(|e| SnapshotError::Invalid(format!("instruction: {:?}", e)))
+    }
+
+    pub fn read_route(&mut self) -> Result<Route, SnapshotError> {
+        Ok(Route {
+            to: self.read_u32()?,
+            from: self.read_u32()?,
+        })
+    }
+
+    pub fn read_device_config(&mut self) -> Result<DeviceConfig, SnapshotError> {
+        Ok(DeviceConfig {
+            id: self.read_u32()?,
+            model: self.read_u32()?,
+            interrupt: self.read_u32()?,
+            memmap_base: self.read_u32()?,
+            memmap_size: self.read_u32()?,
+        })
+    }
+
+    fn read_cacheable(&mut self) -> Result<Cacheable, SnapshotError> {
+        Ok(if self.read_bool()? { Cacheable::Yes } else { Cacheable::No })
+    }
+
+    pub fn read_message(&mut self) -> Result<HardwareMessage, SnapshotError> {
+        use hardware::HardwareMessage::*;
+
+        Ok(match self.read_u32()? {
+            0 => {
+                let to = self.read_u32()?;
+                let len = self.read_u32()?;
+                let mut configs = vec![];
+                for _ in 0..len {
+                    configs.push(self.read_device_config()?);
+                }
+                InitializeMachine(to, configs)
+            },
+            1 => InitializeDevice(self.read_route()?),
+            2 => DeviceReady(self.read_route()?),
+            3 => IntMachineToDevice(self.read_route()?),
+            4 => IntDeviceToMachine(self.read_route()?),
+            5 => MemGetRequest(self.read_route()?, self.read_u32()?),
+            6 => MemGetResponse(self.read_route()?, self.read_u32()?, self.read_u32()?,
+                                self.read_cacheable()?),
+            7 => MemSetRequest(self.read_route()?, self.read_u32()?, self.read_u32()?),
+            8 => MemSetResponse(self.read_route()?, self.read_u32()?, self.read_u32()?,
+                                self.read_cacheable()?),
+            9 => MemBusError(self.read_route()?, self.read_u32()?),
+            other => return Err(SnapshotError::Invalid(format!("bad message tag {}", other)))
+        })
+    }
+
+    /// Reads a block written by `SnapshotWriter::write_block()`.
+    pub fn read_block(&mut self) -> Result<SnapshotReader<'a>, SnapshotError> {
+        self.read_words().map(SnapshotReader::new)
+    }
+
+    /// Fails if anything is left over, which means the reader and writer disagree.
+    pub fn finish(&self) -> Result<(), SnapshotError> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(SnapshotError::Invalid(format!("{} words left over", self.words.len() - self.pos)))
+        }
+    }
+}
+
+/// Saves the entire state of `pool`.
+pub fn save<W: Write>(pool: &EventPool, mut out: W) -> Result<(), SnapshotError> {
+    let mut writer = SnapshotWriter::new();
+
+    writer.write_u32(MAGIC);
+    writer.write_u32(VERSION);
+
+    pool.save_state(&mut writer)?;
+
+    let mut buffer = [0u8; 4];
+
+    for word in writer.into_words() {
+        LittleEndian::write_u32(&mut buffer, word);
+        out.write_all(&buffer)?;
+    }
+
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Restores a snapshot into `pool`, which must contain the same hardware as the pool it was
+/// saved from.
+pub fn restore<R: Read>(pool: &mut EventPool, mut input: R) -> Result<(), SnapshotError> {
+    let mut bytes = vec![];
+
+    input.read_to_end(&mut bytes)?;
+
+    if bytes.len() % 4 != 0 {
+        return Err(SnapshotError::Truncated);
+    }
+
+    let words: Vec<u32> = bytes.chunks(4).map(LittleEndian::read_u32).collect();
+
+    let mut reader = SnapshotReader::new(&words);
+
+    if reader.read_u32()? != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+
+    match reader.read_u32()? {
+        VERSION => (),
+        other => return Err(SnapshotError::UnsupportedVersion(other))
+    }
+
+    pool.load_state(&mut reader)?;
+
+    reader.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use super::*;
+
+    use assemble::assemble;
+    use machine::Machine;
+    use ram::Ram;
+    use device::{DeviceConfig, DeviceModel};
+
+    static PROGRAM: &'static [u8] = b"
+        set a [10]
+        call [factorial]
+        store a [0x10000]
+        halt
+
+    factorial:
+        push c
+        set c [a]
+        set a [1]
+    factorial.loop:
+        cmp c [2]
+        branchl [factorial.ret]
+        mul a [c]
+        sub c [1]
+        branch [factorial.loop]
+    factorial.ret:
+        pop c
+        ret
+    ";
+
+    fn build() -> (EventPool, Rc<RefCell<Machine>>) {
+        let mut program = vec![];
+        assemble(PROGRAM, &mut program).unwrap();
+
+        let mut ram = Ram::new(0x2000);
+        ram.words_mut()[0x1000..0x1000 + program.len()].copy_from_slice(&program);
+
+        let machine = Rc::new(RefCell::new(Machine::new(State {
+            ip: 0x11000,
+            sp: 0x10e00,
+            ..State::default()
+        })));
+
+        let mut pool = EventPool::new();
+
+        let machine_id = pool.add_hardware(machine.clone());
+        let ram_id = pool.add_hardware(ram);
+
+        pool.connect(machine_id, ram_id);
+
+        pool.initialize_machine(machine_id, &[
+            DeviceConfig {
+                id: ram_id,
+                model: DeviceModel::Ram.number(),
+                interrupt: 0xffff_0001,
+                memmap_base: 0x10000,
+                memmap_size: 0x2000,
+            }
+        ]);
+
+        (pool, machine)
+    }
+
+    #[test]
+    fn restore_resumes_identically() {
+        let (mut pool, machine) = build();
+
+        for _ in 0..100 {
+            pool.tick();
+        }
+
+        let mut saved = vec![];
+        save(&pool, &mut saved).unwrap();
+
+        while !machine.borrow().state().halt {
+            pool.tick();
+        }
+
+        let expected = (*machine.borrow().state(), pool.ts());
+
+        let (mut pool2, machine2) = build();
+
+        restore(&mut pool2, &saved[..]).unwrap();
+
+        while !machine2.borrow().state().halt {
+            pool2.tick();
+        }
+
+        assert_eq!((*machine2.borrow().state(), pool2.ts()), expected);
+        assert_eq!(expected.0.a, 3628800);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let (mut pool, _) = build();
+
+        match restore(&mut pool, &[0u8; 8][..]) {
+            Err(SnapshotError::BadMagic) => (),
+            other => panic!("{:?}", other)
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Symbol files
+//!
+//! A symbol file maps labels to word offsets within a program image, one per line. It may also
+//! have a line table, which maps offsets to the source line they were assembled from:
+//!
+//! ```text
+//! 00000000 _start
+//! 0000001c MonitorFound
+//! 0000001c @42
+//! 0000002e @lib/print.fai:7
+//! ```
+//!
+//! Blank lines and lines starting with `;` are ignored.
+
+use std::io;
+use std::io::prelude::*;
+use std::fmt;
+use std::collections::BTreeMap;
+use std::collections::Bound::{Included, Unbounded};
+
+/// A line of source code, and the file it's in if there is one.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SourceLine {
+    pub file: Option<String>,
+    pub line: u32,
+}
+
+impl SourceLine {
+    pub fn new(file: Option<String>, line: u32) -> SourceLine {
+        SourceLine { file: file, line: line }
+    }
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.file {
+            Some(ref file) => write!(f, "{}:{}", file, self.line),
+            None           => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: BTreeMap<String, u32>,
+    lines: BTreeMap<u32, SourceLine>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    pub fn insert(&mut self, label: String, addr: u32) {
+        self.symbols.insert(label, addr);
+    }
+
+    pub fn get(&self, label: &str) -> Option<u32> {
+        self.symbols.get(label).cloned()
+    }
+
+    /// Records that the code at `addr` came from `line`.
+    pub fn insert_line(&mut self, addr: u32, line: SourceLine) {
+        self.lines.insert(addr, line);
+    }
+
+    /// The source line of the closest line table entry at or before `addr`.
+    pub fn line(&self, addr: u32) -> Option<&SourceLine> {
+        self.lines.range((Unbounded, Included(addr))).next_back().map(|(_, line)| line)
+    }
+
+    pub fn lines<'a>(&'a self) -> Box<Iterator<Item=(u32, &'a SourceLine)> + 'a> {
+        Box::new(self.lines.iter().map(|(&addr, line)| (addr, line)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item=(&'a str, u32)> + 'a> {
+        Box::new(self.symbols.iter().map(|(label, &addr)| (&label[..], addr)))
+    }
+
+    /// Returns a copy of the table with `base` added to every address, e.g. to go from offsets
+    /// within an image to addresses in memory once it has been loaded.
+    pub fn relocate(&self, base: u32) -> SymbolTable {
+        SymbolTable {
+            symbols: self.symbols.iter()
+                .map(|(label, &addr)| (label.clone(), addr.wrapping_add(base)))
+                .collect(),
+            lines: self.lines.iter()
+                .map(|(&addr, line)| (addr.wrapping_add(base), line.clone()))
+                .collect(),
+        }
+    }
+
+    /// Finds the closest label at or before `addr`, and the distance from it.
+    ///
+    /// If several labels share the same address, the first in alphabetical order wins.
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let mut best: Option<(&str, u32)> = None;
+
+        for (label, &label_addr) in &self.symbols {
+            if label_addr <= addr {
+                let offset = addr - label_addr;
+
+                if best.map(|(_, best_offset)| offset < best_offset).unwrap_or(true) {
+                    best = Some((label, offset));
+                }
+            }
+        }
+
+        best
+    }
+
+    /// Formats `addr` as `label+0x4`, or just as hex if there's no label before it.
+    pub fn describe(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((label, 0))      => format!("{}", label),
+            Some((label, offset)) => format!("{}+{:#x}", label, offset),
+            None                  => format!("{:#010x}", addr),
+        }
+    }
+
+    pub fn read<R: BufRead>(input: R) -> io::Result<SymbolTable> {
+        let mut table = SymbolTable::new();
+
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ' ');
+
+            let addr = parts.next()
+                .and_then(|s| u32::from_str_radix(s, 16).ok());
+
+            let label = parts.next().map(|s| s.trim());
+
+            match (addr, label) {
+                (Some(addr), Some(label)) if label.starts_with('@') => {
+                    let (file, line_no) = match label.rfind(':') {
+                        Some(pos) => (Some(label[1..pos].to_owned()), &label[pos+1..]),
+                        None      => (None, &label[1..]),
+                    };
+
+                    match line_no.parse() {
+                        Ok(line_no) => table.insert_line(addr, SourceLine::new(file, line_no)),
+                        Err(_) => {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                      format!("bad line number: {:?}", line)));
+                        }
+                    }
+                },
+                (Some(addr), Some(label)) if !label.is_empty() => {
+                    table.insert(label.into(), addr);
+                },
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("bad symbol file line: {:?}", line)));
+                }
+            }
+        }
+
+        Ok(table)
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for (label, &addr) in &self.symbols {
+            writeln!(out, "{:08x} {}", addr, label)?;
+        }
+
+        for (&addr, line) in &self.lines {
+            match line.file {
+                Some(ref file) => writeln!(out, "{:08x} @{}:{}", addr, file, line.line)?,
+                None           => writeln!(out, "{:08x} @{}", addr, line.line)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str;
+
+    fn read(text: &str) -> io::Result<SymbolTable> {
+        SymbolTable::read(text.as_bytes())
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut table = SymbolTable::new();
+
+        table.insert("_start".into(), 0);
+        table.insert("loop".into(), 0x1c);
+        table.insert_line(0x1c, SourceLine::new(None, 42));
+        table.insert_line(0x2e, SourceLine::new(Some("lib/print.fai".into()), 7));
+
+        let mut out = vec![];
+        table.write(&mut out).unwrap();
+
+        assert_eq!(read(str::from_utf8(&out).unwrap()).unwrap(), table);
+    }
+
+    #[test]
+    fn line_entries() {
+        let table = read("; comment\n\
+                          \n\
+                          00000000 @3\n\
+                          00000004 @lib/print.fai:7\n\
+                          00000008 @C:\\src\\main.fai:12\n").unwrap();
+
+        assert!(table.is_empty());
+        assert_eq!(table.line(0), Some(&SourceLine::new(None, 3)));
+        assert_eq!(table.line(4), Some(&SourceLine::new(Some("lib/print.fai".into()), 7)));
+        assert_eq!(table.line(8), Some(&SourceLine::new(Some("C:\\src\\main.fai".into()), 12)));
+    }
+
+    #[test]
+    fn bad_lines() {
+        assert!(read("_start\n").is_err());
+        assert!(read("zzzz _start\n").is_err());
+        assert!(read("00000000\n").is_err());
+        assert!(read("00000000 @\n").is_err());
+        assert!(read("00000000 @main.fai:x\n").is_err());
+    }
+
+    #[test]
+    fn nearest_line() {
+        let table = read("00000010 @5\n00000020 @9\n").unwrap();
+
+        assert_eq!(table.line(0xf), None);
+        assert_eq!(table.line(0x10), Some(&SourceLine::new(None, 5)));
+        assert_eq!(table.line(0x1f), Some(&SourceLine::new(None, 5)));
+        assert_eq!(table.line(0x30), Some(&SourceLine::new(None, 9)));
+    }
+
+    #[test]
+    fn lookup_and_describe() {
+        let table = read("00000010 start\n\
+                          00000010 entry\n\
+                          00000020 loop\n").unwrap();
+
+        assert_eq!(table.lookup(0x8), None);
+        assert_eq!(table.lookup(0x10), Some(("entry", 0)));
+        assert_eq!(table.lookup(0x14), Some(("entry", 4)));
+        assert_eq!(table.lookup(0x21), Some(("loop", 1)));
+
+        assert_eq!(table.describe(0x8), "0x00000008");
+        assert_eq!(table.describe(0x10), "entry");
+        assert_eq!(table.describe(0x14), "entry+0x4");
+    }
+}
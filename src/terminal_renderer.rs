@@ -0,0 +1,155 @@
+//! Shows a `Monitor`'s text mode in the local terminal
+//!
+//! The screen is drawn in a box at the top left of the terminal, which should be in raw mode.
+//! The pixel modes can't be shown, so there's just a note saying which one is active.
+
+use std::io;
+use std::io::prelude::*;
+use std::iter;
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+
+use termion::{clear, cursor};
+
+use monitor::{MonitorUpdate, VideoMode};
+
+pub struct TerminalRenderer<W> {
+    out: W,
+    mode: VideoMode,
+    words: Vec<u32>,
+}
+
+/// How a byte of text mode video RAM looks. Anything that isn't printable is blank.
+pub fn display_char(byte: u8) -> char {
+    if (byte >= 0x20 && byte < 0x7f) || byte >= 0xa0 {
+        byte as char
+    } else {
+        ' '
+    }
+}
+
+impl<W> TerminalRenderer<W> where W: Write {
+    pub fn new(out: W) -> TerminalRenderer<W> {
+        TerminalRenderer {
+            out: out,
+            mode: VideoMode::Text,
+            words: vec![0; VideoMode::Text.buffer_size() as usize],
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    pub fn update(&mut self, update: &MonitorUpdate) -> io::Result<()> {
+        match *update {
+            MonitorUpdate::Resync { mode, ref words, .. } => {
+                self.mode = mode;
+                self.words = words.clone();
+
+                self.draw_all()?;
+            },
+            MonitorUpdate::Frame { ref words, .. } => {
+                for region in words {
+                    for (index, &word) in region.words.iter().enumerate() {
+                        let addr = region.offset as usize + index;
+
+                        if let Some(ptr) = self.words.get_mut(addr) {
+                            *ptr = word;
+                        }
+
+                        self.draw_word(addr)?;
+                    }
+                }
+            },
+        }
+
+        self.out.flush()
+    }
+
+    fn draw_all(&mut self) -> io::Result<()> {
+        write!(self.out, "{}", clear::All)?;
+
+        if !self.mode.is_text() {
+            return write!(self.out, "{}[{}x{} indexed color mode can't be shown in a terminal]",
+                          cursor::Goto(1, 1), self.mode.width(), self.mode.height());
+        }
+
+        let width = self.mode.width() as usize;
+        let border: String = iter::repeat('-').take(width).collect();
+
+        write!(self.out, "{}+{}+", cursor::Goto(1, 1), border)?;
+
+        for row in 0..self.mode.height() as u16 {
+            write!(self.out, "{}|{}|", cursor::Goto(1, row + 2), cursor::Right(width as u16))?;
+        }
+
+        write!(self.out, "{}+{}+", cursor::Goto(1, self.mode.height() as u16 + 2), border)?;
+
+        for addr in 0..self.words.len() {
+            self.draw_word(addr)?;
+        }
+
+        Ok(())
+    }
+
+    fn draw_word(&mut self, addr: usize) -> io::Result<()> {
+        if !self.mode.is_text() || addr >= self.words.len() {
+            return Ok(());
+        }
+
+        let word = self.words[addr];
+        let width = self.mode.width() as usize;
+
+        let column = addr * 4 % width;
+        let row = addr * 4 / width;
+
+        let text: String = (0..4).map(|i| display_char((word >> (i * 8)) as u8)).collect();
+
+        write!(self.out, "{}{}", cursor::Goto(column as u16 + 2, row as u16 + 2), text)
+    }
+}
+
+/// Draws updates on stdout as they come in, until the monitor goes away.
+pub fn spawn(update_rx: Receiver<MonitorUpdate>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut renderer = TerminalRenderer::new(io::stdout());
+
+        for update in update_rx {
+            if let Err(e) = renderer.update(&update) {
+                debug!("Couldn't draw the screen: {}", e);
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use monitor::Region;
+
+    #[test]
+    fn text() {
+        let mut renderer = TerminalRenderer::new(vec![]);
+
+        let mut words = vec![0; 200];
+        words[0] = 0x6c6c6548; // "Hell"
+
+        renderer.update(&MonitorUpdate::Resync {
+            mode: VideoMode::Text,
+            words: words,
+            palette: vec![],
+        }).unwrap();
+
+        renderer.update(&MonitorUpdate::Frame {
+            words: vec![Region { offset: 199, words: vec![0x0021006f] }],
+            palette: vec![],
+        }).unwrap();
+
+        let out = String::from_utf8(renderer.into_inner()).unwrap();
+
+        assert!(out.contains(&format!("{}Hell", cursor::Goto(2, 2))));
+        assert!(out.contains(&format!("{}o ! ", cursor::Goto(38, 21))));
+    }
+}
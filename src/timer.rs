@@ -0,0 +1,283 @@
+//! Programmable interval timer
+//!
+//! Counts `EventPool` ticks. The memory map is:
+//!
+//! ```text
+//! 0: counter, counts down once per tick while enabled, and interrupts when it reaches 0
+//! 1: reload, what the counter starts again from when periodic, or 0 to stop instead
+//! 2: control: bit 0 = enabled, bit 1 = periodic
+//! 3: ticks since the timer was initialized, free-running and read only
+//! ```
+//!
+//! Enabling the timer while the counter is 0 starts it from the reload value. A one-shot timer
+//! disables itself after interrupting, and so does any timer that's left with a counter of 0,
+//! i.e. one enabled with a reload of 0 or a periodic one that runs out with a reload of 0.
+//! Interrupts don't need to be acknowledged, so if the machine is slower than the period, they
+//! queue up in the machine.
+
+use hardware::{Hardware, Id, HardwareMessage, Route};
+use event_pool::Dispatch;
+use integrated_ram::IntegratedRam;
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
+
+pub const COUNTER: usize = 0;
+pub const RELOAD: usize = 1;
+pub const CONTROL: usize = 2;
+pub const TICKS: usize = 3;
+
+pub const ENABLED: u32 = 1 << 0;
+pub const PERIODIC: u32 = 1 << 1;
+
+pub struct Timer {
+    id: Option<Id>,
+    machine: Option<Id>,
+
+    ram: IntegratedRam,
+
+    on: bool,
+    initialize: bool,
+    ticks: u64,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            id: None,
+            machine: None,
+
+            ram: IntegratedRam::new(4),
+
+            on: false,
+            initialize: false,
+            ticks: 0,
+        }
+    }
+
+    fn route(&self) -> Route {
+        Route { from: self.id.unwrap(), to: self.machine.unwrap() }
+    }
+
+    /// Whether the counter ran out on this tick
+    fn count(&mut self) -> bool {
+        self.ticks += 1;
+        self.ram.words[TICKS] = self.ticks as u32;
+
+        let control = self.ram.words[CONTROL];
+
+        if control & ENABLED == 0 {
+            return false;
+        }
+
+        if self.ram.words[COUNTER] == 0 {
+            self.ram.words[CONTROL] &= !ENABLED;
+            return false;
+        }
+
+        if self.ram.words[COUNTER] > 1 {
+            self.ram.words[COUNTER] -= 1;
+            return false;
+        }
+
+        if control & PERIODIC != 0 {
+            self.ram.words[COUNTER] = self.ram.words[RELOAD];
+        } else {
+            self.ram.words[COUNTER] = 0;
+            self.ram.words[CONTROL] &= !ENABLED;
+        }
+
+        true
+    }
+}
+
+impl Hardware for Timer {
+    fn set_id(&mut self, id: Id) {
+        self.id = Some(id);
+    }
+
+    fn receive(&mut self, message: HardwareMessage) {
+        use hardware::HardwareMessage::*;
+
+        self.ram.receive(&message);
+
+        if let InitializeDevice(route) = message {
+            self.initialize = true;
+            self.machine = Some(route.from);
+        }
+    }
+
+    fn save_state(&self, out: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        out.write_option_u32(self.machine);
+        out.write_bool(self.on);
+        out.write_bool(self.initialize);
+        out.write_u64(self.ticks);
+        self.ram.save_state(out);
+        Ok(())
+    }
+
+    fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.machine = input.read_option_u32()?;
+        self.on = input.read_bool()?;
+        self.initialize = input.read_bool()?;
+        self.ticks = input.read_u64()?;
+        self.ram.load_state(input)
+    }
+
+    fn tick(&mut self, _ts: u64, mut dispatch: Dispatch) {
+        use hardware::HardwareMessage::*;
+
+        if self.initialize {
+            self.ram.reinitialize();
+            self.ram.clear();
+
+            self.initialize = false;
+            self.on = true;
+            self.ticks = 0;
+
+            dispatch.send(DeviceReady(self.route()));
+
+            return;
+        }
+
+        if !self.on { return; }
+
+        if self.ram.has_pending_request() {
+            let route = self.route();
+
+            let updated = self.ram.tick(route, &mut dispatch)
+                .map(|updated| updated.unwrap() as usize);
+
+            if updated == Some(CONTROL) &&
+                self.ram.words[CONTROL] & ENABLED != 0 && self.ram.words[COUNTER] == 0 {
+                self.ram.words[COUNTER] = self.ram.words[RELOAD];
+            }
+        }
+
+        if self.count() {
+            dispatch.send(IntDeviceToMachine(self.route()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use super::*;
+    use assemble::assemble;
+    use data::State;
+    use device::{DeviceConfig, DeviceModel};
+    use event_pool::EventPool;
+    use machine::Machine;
+    use ram::Ram;
+
+    fn run(code: &[u8], ticks: u64) -> State {
+        let mut ram = Ram::new(0x1000);
+
+        let mut program = vec![];
+        assemble(code, &mut program).unwrap();
+        ram.words_mut()[..program.len()].copy_from_slice(&program);
+
+        let machine = Rc::new(RefCell::new(Machine::new(State {
+            ip: 0x10000,
+            sp: 0x11000,
+            ..State::default()
+        })));
+
+        let mut pool = EventPool::new();
+
+        let machine_id = pool.add_hardware(machine.clone());
+        let ram_id = pool.add_hardware(ram);
+        let timer_id = pool.add_hardware(Timer::new());
+
+        pool.connect(machine_id, ram_id);
+        pool.connect(machine_id, timer_id);
+
+        pool.initialize_machine(machine_id, &[
+            DeviceConfig {
+                id: ram_id,
+                model: DeviceModel::Ram.number(),
+                interrupt: 0xffff_0000,
+                memmap_base: 0x10000,
+                memmap_size: 0x1000,
+            },
+            DeviceConfig {
+                id: timer_id,
+                model: DeviceModel::Timer.number(),
+                interrupt: 0xffff_0001,
+                memmap_base: 0x8b00,
+                memmap_size: DeviceModel::Timer.memory_size().unwrap(),
+            },
+        ]);
+
+        for _ in 0..ticks {
+            pool.tick();
+        }
+
+        let state = *machine.borrow().state();
+        state
+    }
+
+    #[test]
+    fn one_shot() {
+        let state = run(b"
+                inthset [handler]
+                set a [100]
+                store a [0x8b01]
+                set a [1]
+                store a [0x8b02]
+                halt
+            handler:
+                load b [0x8b03]
+                load c [0x8b02]
+                halt
+        ", 1000);
+
+        assert!(state.halt);
+        assert_eq!(state.a, 0xffff_0001);
+        assert!(state.b > 100 && state.b < 1000);
+        assert_eq!(state.c, 0);
+    }
+
+    #[test]
+    fn periodic() {
+        let state = run(b"
+                inthset [handler]
+                set a [100]
+                store a [0x8b01]
+                set a [3]
+                store a [0x8b02]
+            wait:
+                halt
+                branch [wait]
+            handler:
+                add d [1]
+                intexit
+        ", 1050);
+
+        assert!(state.d >= 9 && state.d <= 10, "{} interrupts", state.d);
+    }
+
+    #[test]
+    fn zero_reload() {
+        let state = run(b"
+                inthset [handler]
+                set a [3]
+                store a [0x8b02]
+                set c [50]
+            wait:
+                sub c [1]
+                cmp c [0]
+                branchg [wait]
+                load b [0x8b02]
+                halt
+            handler:
+                add d [1]
+                intexit
+        ", 2000);
+
+        assert!(state.halt);
+        assert_eq!(state.b, PERIODIC);
+        assert_eq!(state.d, 0);
+    }
+}
@@ -0,0 +1,623 @@
+//! Serial port, which `StdioConsole` is one of
+//!
+//! ```text
+//! 0: what the next interrupt from the machine means: 0 = ACK, 1 = SEND
+//! 1: incoming byte, valid when the UART interrupts
+//! 2: outgoing byte, sent on SEND
+//! ```
+//!
+//! After an ACK, the UART interrupts once it has another byte for the program. When the input
+//! ends, no more bytes arrive, and anything sent after the output has gone away is dropped.
+//!
+//! The other end can be the host's stdin and stdout, a pair of files (e.g. named pipes), a TCP
+//! listener that takes one client at a time, or on Unix, a new pseudo-terminal. A UART never
+//! changes the terminal mode itself. Each one is a separate device, so there can be several, at
+//! different addresses.
+
+use std::thread;
+use std::io;
+use std::io::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, SyncSender, Receiver, channel, sync_channel};
+
+use hardware::{Hardware, Id, HardwareMessage, Route};
+use event_pool::Dispatch;
+use integrated_ram::IntegratedRam;
+use input_log::{InputSource, Poll};
+use snapshot::{SnapshotWriter, SnapshotReader, SnapshotError};
+
+pub const INT_MESSAGE: usize = 0;
+pub const INCOMING: usize = 1;
+pub const OUTGOING: usize = 2;
+
+pub const ACK: u32 = 0;
+pub const SEND: u32 = 1;
+
+pub struct Uart {
+    id: Option<Id>,
+    machine: Option<Id>,
+    input: Box<InputSource>,
+    output: Box<Write>,
+    host_end: String,
+
+    ram: IntegratedRam,
+
+    on: bool,
+    initialize: bool,
+    interrupt: bool,
+    acknowledged: bool,
+    input_closed: bool,
+}
+
+impl Uart {
+    /// Reads bytes from `input` and writes them to `output`
+    pub fn new(input: Box<InputSource>, output: Box<Write>) -> Uart {
+        Uart {
+            id: None,
+            machine: None,
+            input: input,
+            output: output,
+            host_end: String::new(),
+
+            ram: IntegratedRam::new(3),
+
+            on: false,
+            initialize: false,
+            interrupt: false,
+            acknowledged: false,
+            input_closed: false,
+        }
+    }
+
+    pub fn stdio() -> Uart {
+        Uart::new(Box::new(spawn_reader(|| Ok(io::stdin()))), Box::new(io::stdout()))
+            .with_host_end("stdio")
+    }
+
+    /// Reads from one file and writes to another. Both are opened in the background, because
+    /// opening a named pipe waits for the other side.
+    pub fn files<P, Q>(input: P, output: Q) -> Uart
+        where P: Into<PathBuf>, Q: Into<PathBuf> {
+
+        let (input, output) = (input.into(), output.into());
+        let host_end = format!("{} -> {}", output.display(), input.display());
+
+        let output = spawn_writer(move || {
+            OpenOptions::new().write(true).create(true).truncate(true).open(output)
+        });
+
+        Uart::new(Box::new(spawn_reader(move || File::open(input))), Box::new(output))
+            .with_host_end(&host_end)
+    }
+
+    /// Listens on `addr`, e.g. "127.0.0.1:5000". When a client disconnects, the next one to
+    /// connect takes over.
+    pub fn tcp<A: ToSocketAddrs>(addr: A) -> io::Result<Uart> {
+        let listener = TcpListener::bind(addr)?;
+        let host_end = listener.local_addr()?.to_string();
+
+        let client = Arc::new(Mutex::new(None));
+
+        let (in_tx, in_rx) = sync_channel(0);
+        let (out_tx, out_rx) = channel();
+
+        {
+            let client = client.clone();
+            thread::spawn(move || tcp_reader(listener, client, in_tx));
+        }
+
+        thread::spawn(move || tcp_writer(client, out_rx));
+
+        Ok(Uart::new(Box::new(in_rx), Box::new(ThreadedOutput(out_tx))).with_host_end(&host_end))
+    }
+
+    /// Makes a new pseudo-terminal in raw mode, for e.g. `screen` or a serial library to open
+    #[cfg(unix)]
+    pub fn pty() -> io::Result<Uart> {
+        let (reader, path) = pty::open()?;
+        let writer = reader.master.try_clone()?;
+
+        Ok(Uart::new(Box::new(spawn_reader(move || Ok(reader))),
+                     Box::new(spawn_writer(move || Ok(writer))))
+            .with_host_end(&path.to_string_lossy()))
+    }
+
+    /// From a command line description: `stdio`, `file:IN,OUT`, `tcp:PORT` (on localhost), or
+    /// `pty`
+    pub fn open(spec: &str) -> io::Result<Uart> {
+        let (kind, arg) = match spec.find(':') {
+            Some(index) => (&spec[..index], &spec[index + 1..]),
+            None => (spec, "")
+        };
+
+        let invalid = || {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("bad UART: {}", spec))
+        };
+
+        match kind {
+            "stdio" => Ok(Uart::stdio()),
+            "file" => {
+                let mut paths = arg.splitn(2, ',');
+
+                match (paths.next(), paths.next()) {
+                    (Some(input), Some(output)) => Ok(Uart::files(input, output)),
+                    _ => Err(invalid())
+                }
+            },
+            "tcp" => {
+                let port = arg.parse::<u16>().map_err(|_| invalid())?;
+                Uart::tcp(("127.0.0.1", port))
+            },
+            #[cfg(unix)]
+            "pty" => Uart::pty(),
+            _ => Err(invalid())
+        }
+    }
+
+    fn with_host_end(mut self, host_end: &str) -> Uart {
+        self.host_end = host_end.into();
+        self
+    }
+
+    /// What to connect to on the host, e.g. the path of the pseudo-terminal
+    pub fn host_end(&self) -> &str {
+        &self.host_end
+    }
+
+    fn route(&self) -> Route {
+        Route { from: self.id.unwrap(), to: self.machine.unwrap() }
+    }
+}
+
+impl Hardware for Uart {
+    fn set_id(&mut self, id: Id) {
+        self.id = Some(id);
+    }
+
+    fn receive(&mut self, message: HardwareMessage) {
+        use hardware::HardwareMessage::*;
+
+        self.ram.receive(&message);
+
+        match message {
+            InitializeDevice(route) => {
+                self.initialize = true;
+                self.machine = Some(route.from);
+            },
+            IntMachineToDevice(_) => {
+                self.interrupt = true;
+            },
+            _ => ()
+        }
+    }
+
+    fn save_state(&self, out: &mut SnapshotWriter) -> Result<(), SnapshotError> {
+        out.write_option_u32(self.machine);
+        out.write_bool(self.on);
+        out.write_bool(self.initialize);
+        out.write_bool(self.interrupt);
+        out.write_bool(self.acknowledged);
+        out.write_bool(self.input_closed);
+        self.ram.save_state(out);
+        Ok(())
+    }
+
+    fn load_state(&mut self, input: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.machine = input.read_option_u32()?;
+        self.on = input.read_bool()?;
+        self.initialize = input.read_bool()?;
+        self.interrupt = input.read_bool()?;
+        self.acknowledged = input.read_bool()?;
+        self.input_closed = input.read_bool()?;
+        self.ram.load_state(input)
+    }
+
+    fn tick(&mut self, ts: u64, mut dispatch: Dispatch) {
+        use hardware::HardwareMessage::*;
+
+        if self.initialize {
+            self.ram.reinitialize();
+            self.ram.clear();
+
+            self.initialize = false;
+            self.on = true;
+            self.interrupt = false;
+            self.acknowledged = false;
+
+            dispatch.send(DeviceReady(self.route()));
+
+            return;
+        }
+
+        if !self.on { return; }
+
+        if self.interrupt {
+            match self.ram.words[INT_MESSAGE] {
+                ACK => {
+                    self.acknowledged = true;
+                },
+                SEND => {
+                    let byte = self.ram.words[OUTGOING] as u8;
+
+                    let result = self.output.write_all(&[byte]).and_then(|_| self.output.flush());
+
+                    if let Err(e) = result {
+                        debug!("Couldn't send {:#x} to {}: {}", byte, self.host_end, e);
+                    }
+                },
+                other => {
+                    debug!("Bad int_message = {:#010x}", other);
+                }
+            }
+            self.interrupt = false;
+            return;
+        }
+
+        if self.ram.has_pending_request() {
+            let route = self.route();
+            self.ram.tick(route, &mut dispatch);
+            return;
+        }
+
+        if self.acknowledged && !self.input_closed {
+            match self.input.poll(ts) {
+                Poll::Word(byte) => {
+                    self.ram.words[INCOMING] = byte;
+                    self.acknowledged = false;
+
+                    dispatch.send(IntDeviceToMachine(self.route()));
+                },
+                Poll::Disconnected => {
+                    debug!("No more input from {}", self.host_end);
+                    self.input_closed = true;
+                },
+                Poll::Empty => (),
+            }
+        }
+    }
+}
+
+/// Reads whatever `open` returns a byte at a time on another thread, until it ends.
+pub fn spawn_reader<R, F>(open: F) -> Receiver<u8>
+    where R: Read, F: FnOnce() -> io::Result<R> + Send + 'static {
+
+    let (tx, rx) = sync_channel(0);
+
+    thread::spawn(move || {
+        let input = match open() {
+            Ok(input) => input,
+            Err(e) => {
+                warn!("Couldn't open UART input: {}", e);
+                return;
+            }
+        };
+
+        for byte in input.bytes() {
+            match byte {
+                Ok(byte) => if tx.send(byte).is_err() { break; },
+                Err(e) => {
+                    debug!("Read error: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    rx
+}
+
+/// Writes on another thread, so that a slow reader on the other end doesn't hold up the machine
+struct ThreadedOutput(Sender<u8>);
+
+impl Write for ThreadedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.0.send(byte).map_err(|_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "UART output closed")
+            })?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn spawn_writer<W, F>(open: F) -> ThreadedOutput
+    where W: Write, F: FnOnce() -> io::Result<W> + Send + 'static {
+
+    let (tx, rx) = channel::<u8>();
+
+    thread::spawn(move || {
+        let mut output = match open() {
+            Ok(output) => output,
+            Err(e) => {
+                warn!("Couldn't open UART output: {}", e);
+                return;
+            }
+        };
+
+        for byte in rx {
+            if let Err(e) = output.write_all(&[byte]).and_then(|_| output.flush()) {
+                debug!("Write error: {}", e);
+                break;
+            }
+        }
+    });
+
+    ThreadedOutput(tx)
+}
+
+/// Sends to the connected client, if there is one
+fn tcp_writer(client: Arc<Mutex<Option<TcpStream>>>, rx: Receiver<u8>) {
+    for byte in rx {
+        let mut client = client.lock().unwrap();
+
+        let failed = match *client {
+            Some(ref mut stream) => stream.write_all(&[byte]).is_err(),
+            None => false
+        };
+
+        if failed {
+            *client = None;
+        }
+    }
+}
+
+fn tcp_reader(listener: TcpListener, client: Arc<Mutex<Option<TcpStream>>>, tx: SyncSender<u8>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("Accept error: {}", e);
+                continue;
+            }
+        };
+
+        debug!("UART client connected from {:?}", stream.peer_addr());
+
+        *client.lock().unwrap() = stream.try_clone().ok();
+
+        for byte in stream.bytes() {
+            match byte {
+                Ok(byte) => if tx.send(byte).is_err() { return; },
+                Err(_) => break
+            }
+        }
+
+        *client.lock().unwrap() = None;
+    }
+}
+
+#[cfg(unix)]
+mod pty {
+    use std::ffi::CStr;
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::io::prelude::*;
+    use std::mem;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::PathBuf;
+
+    use libc;
+
+    /// Reads from the master end. Holding on to the slave end too means that reads wait for
+    /// the next user instead of failing when nobody has it open.
+    pub struct Reader {
+        pub master: File,
+        _slave: File,
+    }
+
+    impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.master.read(buf)
+        }
+    }
+
+    /// A new pseudo-terminal, and the path of its slave end
+    pub fn open() -> io::Result<(Reader, PathBuf)> {
+        let (master, path) = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let master = File::from_raw_fd(fd);
+
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let name = libc::ptsname(fd);
+
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+
+            (master, PathBuf::from(CStr::from_ptr(name).to_string_lossy().into_owned()))
+        };
+
+        let slave = OpenOptions::new().read(true).write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+
+        unsafe {
+            let mut termios: libc::termios = mem::zeroed();
+
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            libc::cfmakeraw(&mut termios);
+
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok((Reader { master: master, _slave: slave }, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    use super::*;
+    use assemble::assemble;
+    use data::State;
+    use device::{DeviceConfig, DeviceModel};
+    use event_pool::EventPool;
+    use machine::Machine;
+    use ram::Ram;
+
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Mounts the UARTs at 0x8e00, 0x8e10, ... with interrupts 0xffff0001, 0xffff0002, ... and
+    /// runs until `done`, or for too long
+    fn run<F>(code: &[u8], uarts: Vec<Uart>, done: F) -> State where F: Fn(&State) -> bool {
+        let mut ram = Ram::new(0x1000);
+
+        let mut program = vec![];
+        assemble(code, &mut program).unwrap();
+        ram.words_mut()[..program.len()].copy_from_slice(&program);
+
+        let machine = Rc::new(RefCell::new(Machine::new(State {
+            ip: 0x10000,
+            sp: 0x11000,
+            ..State::default()
+        })));
+
+        let mut pool = EventPool::new();
+
+        let machine_id = pool.add_hardware(machine.clone());
+        let ram_id = pool.add_hardware(ram);
+
+        pool.connect(machine_id, ram_id);
+
+        let mut configs = vec![DeviceConfig {
+            id: ram_id,
+            model: DeviceModel::Ram.number(),
+            interrupt: 0xffff_0000,
+            memmap_base: 0x10000,
+            memmap_size: 0x1000,
+        }];
+
+        for (index, uart) in uarts.into_iter().enumerate() {
+            let uart_id = pool.add_hardware(uart);
+
+            pool.connect(machine_id, uart_id);
+
+            configs.push(DeviceConfig {
+                id: uart_id,
+                model: DeviceModel::Uart.number(),
+                interrupt: 0xffff_0001 + index as u32,
+                memmap_base: 0x8e00 + 0x10 * index as u32,
+                memmap_size: DeviceModel::Uart.memory_size().unwrap(),
+            });
+        }
+
+        pool.initialize_machine(machine_id, &configs);
+
+        for _ in 0..1000 {
+            for _ in 0..100 {
+                pool.tick();
+            }
+
+            if done(machine.borrow().state()) {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let state = *machine.borrow().state();
+        state
+    }
+
+    /// Forwards everything from the first UART to the second, counting bytes in d
+    static FORWARD: &'static [u8] = b"
+            inthset [handler]
+            set a [0]
+            store a [0x8e00]
+            inthw [0xffff0001]
+        wait:
+            halt
+            branch [wait]
+        handler:
+            load b [0x8e01]
+            store b [0x8e12]
+            set b [1]
+            store b [0x8e10]
+            inthw [0xffff0002]
+            set b [0]
+            store b [0x8e00]
+            inthw [0xffff0001]
+            add d [1]
+            intexit
+    ";
+
+    #[test]
+    fn forward_until_eof() {
+        let (tx, rx) = sync_channel(3);
+
+        for &byte in b"hi\n" {
+            tx.send(byte).unwrap();
+        }
+
+        drop(tx);
+
+        let output = Rc::new(RefCell::new(vec![]));
+
+        let first = Uart::new(Box::new(rx), Box::new(io::sink()));
+        let second = Uart::new(Box::new(channel::<u8>().1),
+                               Box::new(SharedOutput(output.clone())));
+
+        let state = run(FORWARD, vec![first, second], |_| false);
+
+        assert_eq!(state.d, 3);
+        assert_eq!(&output.borrow()[..], b"hi\n");
+    }
+
+    #[test]
+    fn tcp() {
+        let first = Uart::tcp("127.0.0.1:0").unwrap();
+        let second = Uart::tcp("127.0.0.1:0").unwrap();
+
+        let mut sender = TcpStream::connect(first.host_end()).unwrap();
+        let mut receiver = TcpStream::connect(second.host_end()).unwrap();
+
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        sender.write_all(b"ok").unwrap();
+
+        let state = run(FORWARD, vec![first, second], |state| state.d == 2);
+
+        assert_eq!(state.d, 2);
+
+        let mut buffer = [0; 2];
+        receiver.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ok");
+    }
+}
@@ -11,14 +11,19 @@ syn iskeyword .,_,A-Z,a-z
 
 " Keywords
 syn keyword faiFunction
-      \ bad nop set load store cmp branch branchl branchg
-      \ branche branchne getsp setsp push pop call ret add
-      \ sub mul div divmod not and or xor lsh rsh halt
+      \ bad nop set load store cmp cmps branch branchl branchg
+      \ branche branchne branchc brancho getsp setsp push pop call ret add
+      \ sub mul div divmod divs divmods not and or xor lsh rsh ash halt
       \ intsw inthw intpause intcont inthget inthset intexit
       \ trace
 
+syn keyword faiMacro jmpz jmpnz pushall popall
+
 syn keyword faiDirective
-      \ .words .len_words .bytes .len_bytes
+      \ .words .len_words .bytes .len_bytes .include .section
+      \ .export .import .macro .endm .equ .define .org
+
+syn match faiMacroParam '\\\(@\|[_A-Za-z][_.A-Za-z0-9]*\)'
 
 syn keyword faiRegister a b c d
 
@@ -50,12 +55,14 @@ syn match faiOperator '>>'
 syn match faiOperator '<<'
 
 syn region faiOperand start="\[" end="\]" transparent
-      \ contains=faiRegister,faiNumber,faiOperator,faiCharacter,faiRelative
+      \ contains=faiRegister,faiNumber,faiOperator,faiCharacter,faiRelative,faiMacroParam
 
 let b:current_syntax = "fai"
 
 hi def link faiFunction   Statement
+hi def link faiMacro      Macro
 hi def link faiDirective  PreProc
+hi def link faiMacroParam Special
 hi def link faiRegister   Identifier
 hi def link faiNumber     Number
 hi def link faiTodo       Todo